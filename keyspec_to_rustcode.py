@@ -45,7 +45,7 @@ for sidei in ['L', 'R']:
             k2 = '<FIX>'
 
         if s == sidei and k != '':
-            print(f'{indent}m.insert(({keyname}, Layer::Default), KeyboardUsage::Keyboard{k1}{k2}).expect("no space for key!");')
+            print(f'{indent}m.insert(({keyname}, Layer::Default), Action::Key(KeyboardUsage::Keyboard{k1}{k2})).expect("no space for key!");')
 
             if i2 >= 3:
                 i1 += 1
@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* for the UF2 bootloader (the `uf2` feature): after the S140 7.3.0 SoftDevice the XIAO ships
+     with, and up to the saved settings at 0xEC000 (see storage.rs) */
+  FLASH : ORIGIN = 0x00027000, LENGTH = 788K
+  RAM : ORIGIN = 0x20020000, LENGTH = 128K
+}
@@ -1,7 +1,8 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 1024K
+  /* the top 80K is left for saved settings (32K at 0xEC000, see storage.rs) and a bootloader */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 944K
   RAM : ORIGIN = 0x20000000, LENGTH = 256K
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
@@ -0,0 +1,204 @@
+//! Battery voltage, charge status, and what to do as the battery runs down.
+//!
+//! The XIAO measures its LiPo through a 1M/510k divider onto P0.31 (AIN7), with the low side of the
+//! divider switched by P0.14, and its charger pulls P0.17 low while charging.  The battery can't be
+//! sampled alongside the keys, since another channel in the key ADC's scan would slow every key scan
+//! down, so every `BATTERY_PERIOD` the ADC sampler puts the SAADC aside between two key scans and
+//! `BatteryMonitor::measure` takes a single reading with it.  The voltage is turned into a state of
+//! charge with a typical LiPo discharge curve, which is only a rough guide - and no guide at all
+//! while charging, when the charger holds the voltage up.
+//!
+//! On battery, below `LOW_PERCENT` the key LEDs are dimmed and the board LED flashes a warning after
+//! every reading, and below `CRITICAL_MILLIVOLTS` everything is switched off and the chip goes into
+//! System OFF before the battery is run down far enough to damage it.  Plugging USB back in wakes it
+//! up again (from a reset).  While on USB the battery is charging, so it is never low.
+//!
+//! The state is published on `BATTERY_CHANGED` after every reading and whenever charging starts or
+//! stops.
+
+use core::cell::Cell;
+
+use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::saadc::Input as _;
+use embassy_nrf::{Peri, peripherals, saadc};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+
+use crate::power;
+
+/// how often the battery voltage is read
+pub const BATTERY_PERIOD: Duration = Duration::from_secs(30);
+/// the state of charge below which the battery counts as low
+pub const LOW_PERCENT: u8 = 15;
+/// a low battery stops being low once it is back above this, so the warning doesn't come and go
+/// with every reading near `LOW_PERCENT`
+const LOW_CLEAR_PERCENT: u8 = 20;
+/// the voltage below which the keyboard shuts down to save the battery
+pub const CRITICAL_MILLIVOLTS: u16 = 3300;
+/// how many readings in a row have to be critical before shutting down, in case one caught a dip
+/// from the LEDs
+const CRITICAL_READINGS: u8 = 2;
+
+/// the internal 0.6 V reference through a 1/3 gain is 1.8 V full scale (12 bits), and the divider
+/// puts 510k/1510k of the battery voltage on the pin
+const MILLIVOLTS_PER_COUNT: f32 = 1800. / 4096. * 1510. / 510.;
+
+/// (millivolts, percent) points on a typical LiPo discharge curve at light load, highest first
+const DISCHARGE_CURVE: [(u16, u8); 11] = [(4200, 100), (4100, 90), (4000, 79), (3900, 62), (3800, 42),
+                                          (3750, 30), (3700, 18), (3650, 10), (3600, 6), (3500, 3),
+                                          (3300, 0)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ChargeState {
+    /// running on the battery
+    Discharging,
+    Charging,
+    /// on USB with the charger finished (or no battery connected)
+    Charged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BatteryState {
+    /// at the last reading, or None before the first one
+    pub millivolts: Option<u16>,
+    pub percent: Option<u8>,
+    pub charge: ChargeState,
+    pub low: bool,
+    pub critical: bool,
+}
+
+impl BatteryState {
+    const fn new() -> Self {
+        BatteryState { millivolts: None, percent: None, charge: ChargeState::Discharging, low: false, critical: false }
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<BatteryState>> = Mutex::new(Cell::new(BatteryState::new()));
+/// carries the new state after every reading, and whenever the charge state changes
+pub static BATTERY_CHANGED: Signal<CriticalSectionRawMutex, BatteryState> = Signal::new();
+
+pub fn state() -> BatteryState {
+    STATE.lock(|state| state.get())
+}
+
+/// whether the battery is low enough to dim the LEDs for
+pub fn is_low() -> bool {
+    state().low
+}
+
+fn publish(state: BatteryState) {
+    STATE.lock(|s| s.set(state));
+    BATTERY_CHANGED.signal(state);
+}
+
+/// Estimates the state of charge from the voltage, interpolating along `DISCHARGE_CURVE`.
+pub fn percent_from_millivolts(millivolts: u16) -> u8 {
+    let (top_mv, top_percent) = DISCHARGE_CURVE[0];
+    if millivolts >= top_mv {
+        return top_percent;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let [(high_mv, high_percent), (low_mv, low_percent)] = [pair[0], pair[1]];
+        if millivolts >= low_mv {
+            let fraction = (millivolts - low_mv) as f32 / (high_mv - low_mv) as f32;
+            return low_percent + (fraction * (high_percent - low_percent) as f32) as u8;
+        }
+    }
+    0
+}
+
+/// The battery pins, owned by the ADC sampler, which hands over the SAADC for each reading.
+pub struct BatteryMonitor {
+    /// pulls the low side of the divider down; kept low all the time, since with it floating the
+    /// battery voltage reaches P0.31 through the 1M resistor, above what the pin can take
+    _vbat_enable: Output<'static>,
+    /// low while charging
+    charging: Input<'static>,
+    /// the divider output, P0.31
+    vbat: saadc::AnyInput<'static>,
+    next_reading: Instant,
+    critical_readings: u8,
+}
+
+impl BatteryMonitor {
+    pub fn new(vbat_enable: Peri<'static, peripherals::P0_14>, charging: Peri<'static, peripherals::P0_17>,
+               vbat: Peri<'static, peripherals::P0_31>) -> Self {
+        BatteryMonitor {
+            _vbat_enable: Output::new(vbat_enable, Level::Low, OutputDrive::Standard),
+            charging: Input::new(charging, Pull::Up),
+            vbat: vbat.degrade_saadc(),
+            next_reading: Instant::now(),
+            critical_readings: 0,
+        }
+    }
+
+    /// whether it is time for `measure`
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_reading
+    }
+
+    fn charge_state(&self) -> ChargeState {
+        match (power::vbus_present(), self.charging.is_low()) {
+            (false, _) => ChargeState::Discharging,
+            (true, true) => ChargeState::Charging,
+            (true, false) => ChargeState::Charged,
+        }
+    }
+
+    /// Publishes the charge state if it has changed.  Cheap enough to call on every key scan.
+    pub fn check_charging(&self) {
+        let charge = self.charge_state();
+        let mut state = state();
+        if state.charge != charge {
+            defmt::info!("battery now {}", charge);
+            state.charge = charge;
+            if charge != ChargeState::Discharging {
+                state.low = false;
+                state.critical = false;
+            }
+            publish(state);
+        }
+    }
+
+    /// Reads the battery voltage with the SAADC, which must not be in use for anything else, and
+    /// publishes the new state.  Returns whether the battery is critical.
+    pub async fn measure(&mut self, adc: Peri<'_, peripherals::SAADC>) -> bool {
+        let mut channel_config = saadc::ChannelConfig::single_ended(self.vbat.reborrow());
+        channel_config.reference = saadc::Reference::INTERNAL;
+        channel_config.gain = saadc::Gain::GAIN1_3;
+        // the divider is a high impedance source, so the sampling capacitor needs longer to charge
+        channel_config.time = saadc::Time::_40US;
+        let mut adc_config = saadc::Config::default();
+        adc_config.resolution = saadc::Resolution::_12BIT;
+        adc_config.oversample = saadc::Oversample::OVER8X;
+        let mut adc = saadc::Saadc::new(adc, crate::Irqs, adc_config, [channel_config]);
+        adc.calibrate().await;
+        let mut buf = [0; 1];
+        adc.sample(&mut buf).await;
+        drop(adc);
+        self.next_reading = Instant::now() + BATTERY_PERIOD;
+
+        let millivolts = (buf[0].max(0) as f32 * MILLIVOLTS_PER_COUNT) as u16;
+        let percent = percent_from_millivolts(millivolts);
+        let mut state = state();
+        state.charge = self.charge_state();
+        state.millivolts = Some(millivolts);
+        state.percent = Some(percent);
+        if state.charge == ChargeState::Discharging {
+            state.low = if state.low { percent < LOW_CLEAR_PERCENT } else { percent < LOW_PERCENT };
+            self.critical_readings = if millivolts < CRITICAL_MILLIVOLTS { self.critical_readings + 1 } else { 0 };
+        } else {
+            state.low = false;
+            self.critical_readings = 0;
+        }
+        state.critical = self.critical_readings >= CRITICAL_READINGS;
+        defmt::debug!("battery at {} mV, {}%, {}", millivolts, percent, state.charge);
+        if state.critical {
+            defmt::warn!("battery critical at {} mV", millivolts);
+        }
+        publish(state);
+        state.critical
+    }
+}
@@ -0,0 +1,45 @@
+//! Rebooting into the XIAO nRF52840's UF2 bootloader, so the firmware can be updated by copying a
+//! `.uf2` file onto the drive that shows up - no debug probe needed.
+//!
+//! The Adafruit nRF52 bootloader the XIAO ships with stays in UF2 mode after a reset if GPREGRET holds
+//! `DFU_MAGIC_UF2_RESET`.  It can be asked for by holding down every key of `BOOTLOADER_COMBO` (which
+//! doesn't depend on the keymap, so it works even if that is broken), from the raw HID protocol, the
+//! serial shell, VIA, or a USB DFU detach with the `dfu_runtime` feature.
+//!
+//! Firmware for the bootloader has to be built with the `uf2` feature, which links it after the
+//! SoftDevice where the bootloader expects it (see `memory-uf2.x`), then converted with e.g.
+//! `uf2conv.py -c -f 0xADA52840 -b 0x27000` from a `cargo objcopy --features uf2 -- -O binary` image.
+
+use embassy_nrf::pac;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+/// the GPREGRET value the Adafruit bootloader takes to mean "stay in UF2 mode"
+pub const DFU_MAGIC_UF2_RESET: u8 = 0x57;
+/// the top corners and the bottom left key
+#[allow(clippy::zero_prefixed_literal)]
+pub const BOOTLOADER_COMBO: [u8; 3] = [00, 03, 50];
+/// long enough for the reply to whoever asked to reach the host
+const RESET_DELAY: Duration = Duration::from_millis(100);
+
+static REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Reboots into the bootloader shortly, once the current request has been answered.
+pub fn request() {
+    REQUESTED.signal(());
+}
+
+/// Reboots into the bootloader right away.
+pub fn enter() -> ! {
+    pac::POWER.gpregret().write(|w| w.set_gpregret(DFU_MAGIC_UF2_RESET));
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[embassy_executor::task]
+pub async fn bootloader_task() {
+    REQUESTED.wait().await;
+    defmt::info!("rebooting into the UF2 bootloader");
+    Timer::after(RESET_DELAY).await;
+    enter()
+}
@@ -0,0 +1,77 @@
+//! Caps Word: shift letters until a key that isn't part of a word is typed, as in QMK.
+//!
+//! Digits, backspace, delete and `-` (shifted to `_`) keep the word going without ending it.  Any
+//! other key, or a modifier other than shift, ends it - as does going idle for a while.
+
+use embassy_time::{Duration, Instant};
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::report::modifier_bit;
+
+pub const CAPS_WORD_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const LEFT_SHIFT_BIT: u8 = 1 << (KeyboardUsage::KeyboardLeftShift as u8 - KeyboardUsage::KeyboardLeftControl as u8);
+const RIGHT_SHIFT_BIT: u8 = 1 << (KeyboardUsage::KeyboardRightShift as u8 - KeyboardUsage::KeyboardLeftControl as u8);
+const SHIFT_BITS: u8 = LEFT_SHIFT_BIT | RIGHT_SHIFT_BIT;
+
+pub struct CapsWord {
+    active: bool,
+    deadline: Instant,
+}
+
+impl CapsWord {
+    pub const fn new() -> Self {
+        CapsWord { active: false, deadline: Instant::MIN }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.active { Some(self.deadline) } else { None }
+    }
+
+    pub fn set(&mut self, on: bool, now: Instant) {
+        defmt::debug!("caps word {}", if on { "on" } else { "off" });
+        self.active = on;
+        self.deadline = now + CAPS_WORD_IDLE_TIMEOUT;
+    }
+
+    pub fn timeout(&mut self, now: Instant) {
+        if self.active && now >= self.deadline {
+            self.set(false, now);
+        }
+    }
+
+    /// Called for each usage pressed.  Returns the modifiers to add to its report, and ends caps word
+    /// if the usage isn't part of a word.  Letters aren't shifted if the host already has caps lock
+    /// on, since most hosts would lower-case them again.
+    pub fn key(&mut self, usage: u8, host_caps_lock: bool, now: Instant) -> u8 {
+        if !self.active {
+            return 0;
+        }
+        self.deadline = now + CAPS_WORD_IDLE_TIMEOUT;
+
+        const A: u8 = KeyboardUsage::KeyboardAa as u8;
+        const Z: u8 = KeyboardUsage::KeyboardZz as u8;
+        const ONE: u8 = KeyboardUsage::Keyboard1Exclamation as u8;
+        const ZERO: u8 = KeyboardUsage::Keyboard0CloseParens as u8;
+        const MINUS: u8 = KeyboardUsage::KeyboardDashUnderscore as u8;
+        const BACKSPACE: u8 = KeyboardUsage::KeyboardBackspace as u8;
+        const DELETE: u8 = KeyboardUsage::KeyboardDelete as u8;
+        match usage {
+            A..=Z if host_caps_lock => 0,
+            A..=Z | MINUS => LEFT_SHIFT_BIT,
+            ONE..=ZERO | BACKSPACE | DELETE => 0,
+            _ => {
+                match modifier_bit(usage) {
+                    Some(bit) if bit & SHIFT_BITS != 0 => {}
+                    _ => self.set(false, now),
+                }
+                0
+            }
+        }
+    }
+}
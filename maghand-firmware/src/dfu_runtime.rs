@@ -0,0 +1,87 @@
+//! A USB DFU 1.1 runtime interface, so standard tools (`dfu-util -e`) can send the keyboard into its
+//! bootloader.  The bootloader itself is UF2 rather than DFU, so this only does the detach - see
+//! `bootloader`.
+
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::Driver;
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+
+use crate::bootloader;
+
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+const DFU_DETACH: u8 = 0x00;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_GETSTATE: u8 = 0x05;
+
+/// bitWillDetach: the device resets itself on a detach, rather than waiting for a bus reset
+const ATTRIBUTES: u8 = 0x08;
+const DETACH_TIMEOUT_MS: u16 = 1000;
+const TRANSFER_SIZE: u16 = 64;
+const DFU_VERSION: u16 = 0x0110;
+const STATE_APP_IDLE: u8 = 0;
+const STATUS_OK: u8 = 0;
+
+pub struct DfuRuntime {
+    interface: InterfaceNumber,
+}
+
+impl DfuRuntime {
+    /// Adds the interface to the builder.  The returned handler must be registered with
+    /// `Builder::handler` for it to answer requests.
+    pub fn new<'d, D: Driver<'d>>(builder: &mut Builder<'d, D>) -> Self {
+        let mut function = builder.function(USB_CLASS_APPLICATION_SPECIFIC, DFU_SUBCLASS, DFU_PROTOCOL_RUNTIME);
+        let mut interface = function.interface();
+        let number = interface.interface_number();
+        let mut alt = interface.alt_setting(USB_CLASS_APPLICATION_SPECIFIC, DFU_SUBCLASS, DFU_PROTOCOL_RUNTIME, None);
+        let [timeout_lo, timeout_hi] = DETACH_TIMEOUT_MS.to_le_bytes();
+        let [size_lo, size_hi] = TRANSFER_SIZE.to_le_bytes();
+        let [version_lo, version_hi] = DFU_VERSION.to_le_bytes();
+        alt.descriptor(DFU_FUNCTIONAL_DESCRIPTOR, &[ATTRIBUTES, timeout_lo, timeout_hi, size_lo, size_hi, version_lo, version_hi]);
+        DfuRuntime { interface: number }
+    }
+
+    fn is_ours(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == self.interface.0 as u16
+    }
+}
+
+impl Handler for DfuRuntime {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+        match req.request {
+            DFU_DETACH => {
+                defmt::info!("DFU detach requested");
+                bootloader::request();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+        match req.request {
+            DFU_GETSTATUS => {
+                // status, a 3-byte poll timeout, state, and no status string
+                buf[..6].copy_from_slice(&[STATUS_OK, 0, 0, 0, STATE_APP_IDLE, 0]);
+                Some(InResponse::Accepted(&buf[..6]))
+            }
+            DFU_GETSTATE => {
+                buf[0] = STATE_APP_IDLE;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
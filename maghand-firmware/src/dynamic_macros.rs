@@ -0,0 +1,133 @@
+//! Macros recorded from the keyboard at runtime, QMK "dynamic macro" style.
+//!
+//! While a slot is recording, every key usage pressed or released is appended to it in the same
+//! encoding as the stored macro buffer (see `macros`), so the `MacroPlayer` can replay it unchanged.
+//! A release is only recorded if its press was, so keys held when recording starts (such as the
+//! record key itself) don't replay as stray releases.  Recordings live in RAM only and are lost on
+//! power-off.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use heapless::Vec;
+
+use crate::macros::{MAX_MACRO_LEN, SS_DOWN_CODE, SS_QMK_PREFIX, SS_UP_CODE};
+
+pub const N_DYNAMIC_MACRO_SLOTS: usize = 2;
+/// bytes per recorded press or release
+const STEP_SIZE: usize = 3;
+/// bytes per slot
+pub const DYNAMIC_MACRO_SIZE: usize = 40 * STEP_SIZE;
+const _: () = assert!(DYNAMIC_MACRO_SIZE <= MAX_MACRO_LEN, "dynamic macros must fit in the macro player");
+
+pub static DYNAMIC_MACROS: Mutex<CriticalSectionRawMutex, RefCell<DynamicMacros>> = Mutex::new(RefCell::new(DynamicMacros::new()));
+
+pub struct DynamicMacros {
+    slots: [Vec<u8, DYNAMIC_MACRO_SIZE>; N_DYNAMIC_MACRO_SLOTS],
+    recording: Option<u8>,
+}
+
+impl DynamicMacros {
+    const fn new() -> Self {
+        DynamicMacros {
+            slots: [const { Vec::new() }; N_DYNAMIC_MACRO_SLOTS],
+            recording: None,
+        }
+    }
+
+    /// Clears `slot` and starts recording into it, ending any recording already in progress.
+    pub fn start_recording(&mut self, slot: u8) {
+        if slot as usize >= N_DYNAMIC_MACRO_SLOTS {
+            defmt::warn!("no dynamic macro slot {}", slot);
+            return;
+        }
+        self.stop_recording();
+        self.slots[slot as usize].clear();
+        self.recording = Some(slot);
+        defmt::info!("recording dynamic macro {}", slot);
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(slot) = self.recording.take() {
+            defmt::info!("recorded {} bytes into dynamic macro {}", self.slots[slot as usize].len(), slot);
+        }
+    }
+
+    pub fn recording(&self) -> Option<u8> {
+        self.recording
+    }
+
+    /// Appends a press or release to the slot being recorded, if any.  Recording stops when the slot fills.
+    pub fn record(&mut self, usage: u8, pressed: bool) {
+        let Some(slot) = self.recording else { return };
+        let steps = &mut self.slots[slot as usize];
+        if !pressed && !is_down(steps, usage) {
+            return;
+        }
+        let code = if pressed { SS_DOWN_CODE } else { SS_UP_CODE };
+        if steps.extend_from_slice(&[SS_QMK_PREFIX, code, usage]).is_err() {
+            defmt::warn!("dynamic macro {} is full", slot);
+            self.stop_recording();
+        }
+    }
+
+    pub fn steps(&self, slot: u8) -> Option<&[u8]> {
+        self.slots.get(slot as usize).map(|s| s.as_slice())
+    }
+}
+
+/// Whether the last step of `steps` that presses or releases `usage` is a press.
+fn is_down(steps: &[u8], usage: u8) -> bool {
+    steps.chunks_exact(STEP_SIZE).rev().find(|step| step[2] == usage).is_some_and(|step| step[1] == SS_DOWN_CODE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u8 = 4;
+    const B: u8 = 5;
+
+    #[test]
+    fn records_presses_and_releases() {
+        let mut dm = DynamicMacros::new();
+        dm.start_recording(0);
+        dm.record(A, true);
+        dm.record(B, true);
+        dm.record(A, false);
+        dm.record(B, false);
+        assert_eq!(dm.steps(0), Some([
+            SS_QMK_PREFIX, SS_DOWN_CODE, A,
+            SS_QMK_PREFIX, SS_DOWN_CODE, B,
+            SS_QMK_PREFIX, SS_UP_CODE, A,
+            SS_QMK_PREFIX, SS_UP_CODE, B,
+        ].as_slice()));
+    }
+
+    #[test]
+    fn release_without_recorded_press_is_dropped() {
+        let mut dm = DynamicMacros::new();
+        // held from before the recording started
+        dm.record(A, true);
+        dm.start_recording(0);
+        dm.record(A, false);
+        assert_eq!(dm.steps(0), Some([].as_slice()));
+        // pressed again once recording, so this time the release goes in too
+        dm.record(A, true);
+        dm.record(A, false);
+        dm.record(A, false);
+        assert_eq!(dm.steps(0), Some([SS_QMK_PREFIX, SS_DOWN_CODE, A, SS_QMK_PREFIX, SS_UP_CODE, A].as_slice()));
+    }
+
+    #[test]
+    fn presses_from_an_earlier_recording_dont_count() {
+        let mut dm = DynamicMacros::new();
+        dm.start_recording(0);
+        dm.record(A, true);
+        dm.start_recording(0);
+        dm.record(A, false);
+        assert_eq!(dm.steps(0), Some([].as_slice()));
+    }
+}
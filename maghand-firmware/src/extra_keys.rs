@@ -0,0 +1,179 @@
+//! Consumer control (media, volume, brightness) and system control (power, sleep) keys.
+//!
+//! These go out in their own reports, one report ID per usage page, since a keyboard report can only
+//! carry keyboard page usages.  They share the n-key rollover keyboard's interface (see `report`).
+//! Each report holds a single usage: if more than one key of a page is held, the most recently
+//! pressed one is reported until it is released.  The mouse keys report (see `mouse_keys`) shares
+//! the interface.
+
+use heapless::Vec;
+
+use crate::mouse_keys::{MOUSE_BUTTONS, MOUSE_REPORT_ID, MOUSE_REPORT_SIZE};
+use crate::report::UsageEvent;
+
+pub const CONSUMER_REPORT_ID: u8 = 1;
+pub const SYSTEM_REPORT_ID: u8 = 2;
+/// the report ID followed by a 16-bit usage
+const USAGE_REPORT_SIZE: usize = 3;
+/// the largest of these reports
+pub const EXTRA_REPORT_SIZE: usize = MOUSE_REPORT_SIZE;
+const MAX_HELD: usize = 4;
+
+pub const EXTRA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,        // Usage Page (Consumer)
+    0x09, 0x01,        // Usage (Consumer Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, CONSUMER_REPORT_ID, // Report ID
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x03,  //   Logical Maximum (0x3FF)
+    0x19, 0x00,        //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03,  //   Usage Maximum (0x3FF)
+    0x75, 0x10,        //   Report Size (16)
+    0x95, 0x01,        //   Report Count (1)
+    0x81, 0x00,        //   Input (Data, Array, Absolute)
+    0xC0,              // End Collection
+    0x05, 0x01,        // Usage Page (Generic Desktop)
+    0x09, 0x80,        // Usage (System Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, SYSTEM_REPORT_ID, // Report ID
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xB7, 0x00,  //   Logical Maximum (0xB7)
+    0x19, 0x00,        //   Usage Minimum (0)
+    0x29, 0xB7,        //   Usage Maximum (0xB7)
+    0x75, 0x10,        //   Report Size (16)
+    0x95, 0x01,        //   Report Count (1)
+    0x81, 0x00,        //   Input (Data, Array, Absolute)
+    0xC0,              // End Collection
+    0x05, 0x01,        // Usage Page (Generic Desktop)
+    0x09, 0x02,        // Usage (Mouse)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, MOUSE_REPORT_ID, // Report ID
+    0x09, 0x01,        //   Usage (Pointer)
+    0xA1, 0x00,        //   Collection (Physical)
+    0x05, 0x09,        //     Usage Page (Button)
+    0x19, 0x01,        //     Usage Minimum (1)
+    0x29, MOUSE_BUTTONS, //   Usage Maximum
+    0x15, 0x00,        //     Logical Minimum (0)
+    0x25, 0x01,        //     Logical Maximum (1)
+    0x75, 0x01,        //     Report Size (1)
+    0x95, MOUSE_BUTTONS, //   Report Count
+    0x81, 0x02,        //     Input (Data, Variable, Absolute)
+    0x75, 8 - MOUSE_BUTTONS, // Report Size, padding the buttons out to a byte
+    0x95, 0x01,        //     Report Count (1)
+    0x81, 0x01,        //     Input (Constant)
+    0x05, 0x01,        //     Usage Page (Generic Desktop)
+    0x09, 0x30,        //     Usage (X)
+    0x09, 0x31,        //     Usage (Y)
+    0x09, 0x38,        //     Usage (Wheel)
+    0x15, 0x81,        //     Logical Minimum (-127)
+    0x25, 0x7F,        //     Logical Maximum (127)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x03,        //     Report Count (3)
+    0x81, 0x06,        //     Input (Data, Variable, Relative)
+    0x05, 0x0C,        //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02,  //     Usage (AC Pan)
+    0x95, 0x01,        //     Report Count (1)
+    0x81, 0x06,        //     Input (Data, Variable, Relative)
+    0xC0,              //   End Collection
+    0xC0,              // End Collection
+];
+
+/// Consumer page usages for the keymap.  usbd_hid's `MediaKey` is missing brightness and the
+/// application launch keys, so these are listed here.
+#[repr(u16)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+#[allow(dead_code)] // not every usage is in the default keymap
+pub enum ConsumerUsage {
+    BrightnessIncrement = 0x6F,
+    BrightnessDecrement = 0x70,
+    NextTrack = 0xB5,
+    PrevTrack = 0xB6,
+    Stop = 0xB7,
+    Eject = 0xB8,
+    PlayPause = 0xCD,
+    Mute = 0xE2,
+    VolumeIncrement = 0xE9,
+    VolumeDecrement = 0xEA,
+    LaunchMail = 0x18A,
+    LaunchCalculator = 0x192,
+    LaunchBrowser = 0x196,
+    Search = 0x221,
+    BrowserHome = 0x223,
+    BrowserBack = 0x224,
+    BrowserForward = 0x225,
+    BrowserRefresh = 0x227,
+}
+
+impl ConsumerUsage {
+    const ALL: [ConsumerUsage; 18] = [
+        ConsumerUsage::BrightnessIncrement, ConsumerUsage::BrightnessDecrement, ConsumerUsage::NextTrack,
+        ConsumerUsage::PrevTrack, ConsumerUsage::Stop, ConsumerUsage::Eject, ConsumerUsage::PlayPause,
+        ConsumerUsage::Mute, ConsumerUsage::VolumeIncrement, ConsumerUsage::VolumeDecrement,
+        ConsumerUsage::LaunchMail, ConsumerUsage::LaunchCalculator, ConsumerUsage::LaunchBrowser,
+        ConsumerUsage::Search, ConsumerUsage::BrowserHome, ConsumerUsage::BrowserBack,
+        ConsumerUsage::BrowserForward, ConsumerUsage::BrowserRefresh,
+    ];
+
+    pub fn from_u16(usage: u16) -> Option<ConsumerUsage> {
+        Self::ALL.into_iter().find(|u| *u as u16 == usage)
+    }
+}
+
+/// Generic Desktop page system control usages for the keymap
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+#[allow(dead_code)] // not every usage is in the default keymap
+pub enum SystemUsage {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+impl SystemUsage {
+    pub fn from_u8(usage: u8) -> Option<SystemUsage> {
+        [SystemUsage::PowerDown, SystemUsage::Sleep, SystemUsage::WakeUp].into_iter().find(|u| *u as u8 == usage)
+    }
+}
+
+/// The consumer and system usages currently held, most recent last
+#[derive(Debug)]
+pub struct ExtraKeysState {
+    consumer: Vec<u16, MAX_HELD>,
+    system: Vec<u16, MAX_HELD>,
+}
+
+impl ExtraKeysState {
+    pub const fn new() -> Self {
+        ExtraKeysState { consumer: Vec::new(), system: Vec::new() }
+    }
+
+    /// Applies a consumer, system or mouse event, and returns the report that now needs sending.
+    /// Keyboard events are ignored.
+    pub fn apply(&mut self, event: UsageEvent) -> Option<Vec<u8, EXTRA_REPORT_SIZE>> {
+        let (held, report_id, usage, pressed) = match event {
+            UsageEvent::Mouse(report) => return Vec::from_slice(&report.to_bytes()).ok(),
+            UsageEvent::ConsumerPress(usage) => (&mut self.consumer, CONSUMER_REPORT_ID, usage, true),
+            UsageEvent::ConsumerRelease(usage) => (&mut self.consumer, CONSUMER_REPORT_ID, usage, false),
+            UsageEvent::SystemPress(usage) => (&mut self.system, SYSTEM_REPORT_ID, usage as u16, true),
+            UsageEvent::SystemRelease(usage) => (&mut self.system, SYSTEM_REPORT_ID, usage as u16, false),
+            _ => return None,
+        };
+
+        if pressed {
+            if held.is_full() {
+                held.remove(0);
+            }
+            held.push(usage).ok();
+        } else {
+            match held.iter().rposition(|u| *u == usage) {
+                Some(i) => { held.remove(i); }
+                None => defmt::warn!("On keyup, {} wasnt down", usage),
+            }
+        }
+
+        let current = held.last().copied().unwrap_or(0);
+        let [lo, hi] = current.to_le_bytes();
+        let report: [u8; USAGE_REPORT_SIZE] = [report_id, lo, hi];
+        Vec::from_slice(&report).ok()
+    }
+}
@@ -1,6 +1,7 @@
 use embassy_time::Duration;
 
 // note the order here should match the order of the leds
+#[allow(clippy::zero_prefixed_literal)]
 pub const KEY_NAMES: [u8; 23] = [00, 01, 02, 03, 
                              10, 11, 12, 13,
                              20, 21, 22, 23,
@@ -9,6 +10,57 @@ pub const KEY_NAMES: [u8; 23] = [00, 01, 02, 03,
                              50, 51, 52];//, 53];
 pub const N_KEYS: usize = KEY_NAMES.len();
 
+/// Key centres in mm, x to the right and y up, in `KEY_NAMES` order.  These are each hand's first
+/// `N_KEYS` keys in `key_spec`, which is the order `arrange_keyboard.py` places them on the PCB.
+#[cfg(not(feature = "right_hand"))]
+pub const KEY_POSITIONS: [(f32, f32); N_KEYS] = [(8.0, 8.0), (27.0, 8.0), (46.0, 8.0), (65.0, 8.0),
+                                                (84.0, 8.0), (-6.25, -11.0), (12.75, -11.0), (31.75, -11.0),
+                                                (50.75, -11.0), (69.75, -11.0), (88.75, -11.0), (3.25, -30.0),
+                                                (22.25, -30.0), (41.25, -30.0), (60.25, -30.0), (79.25, -30.0),
+                                                (98.25, -30.0), (-1.5, -49.0), (17.5, -49.0), (36.5, -49.0),
+                                                (55.5, -49.0), (74.5, -49.0), (93.5, -49.0)];
+#[cfg(feature = "right_hand")]
+pub const KEY_POSITIONS: [(f32, f32); N_KEYS] = [(103.0, 8.0), (122.0, 8.0), (141.0, 8.0), (160.0, 8.0),
+                                                (179.0, 8.0), (198.0, 8.0), (107.75, -11.0), (126.75, -11.0),
+                                                (145.75, -11.0), (164.75, -11.0), (183.75, -11.0), (202.75, -11.0),
+                                                (117.25, -30.0), (136.25, -30.0), (155.25, -30.0), (174.25, -30.0),
+                                                (193.25, -30.0), (212.25, -30.0), (112.5, -49.0), (131.5, -49.0),
+                                                (150.5, -49.0), (169.5, -49.0), (188.5, -49.0)];
+/// the distance between neighbouring key centres, in mm
+pub const KEY_PITCH: f32 = 19.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[allow(dead_code)] // only one hand is built at a time
+pub enum Hand {
+    Left,
+    Right,
+}
+
+/// which half of the keyboard this board is - build with the `right_hand` feature for the right one
+#[cfg(not(feature = "right_hand"))]
+pub const HAND: Hand = Hand::Left;
+#[cfg(feature = "right_hand")]
+pub const HAND: Hand = Hand::Right;
+/// whether this is the half of a split keyboard that the other half's keys come to - see `split`
+pub const SPLIT_CENTRAL: bool = cfg!(all(feature = "split", not(feature = "right_hand")));
+/// added to the other half's key numbers on the central half, putting its keys in rows 6 to 11
+pub const REMOTE_KEY_OFFSET: u8 = 60;
+pub const N_REMOTE_KEYS: usize = if SPLIT_CENTRAL { N_KEYS } else { 0 };
+/// every key the keymap covers: this half's, then the other half's if this is the central half
+pub const N_ALL_KEYS: usize = N_KEYS + N_REMOTE_KEYS;
+pub const ALL_KEY_NAMES: [u8; N_ALL_KEYS] = {
+    let mut names = [0; N_ALL_KEYS];
+    let mut i = 0;
+    while i < N_ALL_KEYS {
+        names[i] = if i < N_KEYS { KEY_NAMES[i] } else { KEY_NAMES[i - N_KEYS] + REMOTE_KEY_OFFSET };
+        i += 1;
+    }
+    names
+};
+
+/// the `rev` in the title block of the schematic
+pub const HARDWARE_REVISION: u8 = 0;
+
 pub const LED_POWERUP_TIME: Duration = Duration::from_millis(1); // this is just a guess - implicitly it's everything connected to vhi
 pub const IMU_POWERUP_TIME: Duration = Duration::from_millis(35); // lsm6ds3tr datasheet
 pub const MUX_SETTLE_TIME: Option<Duration> = Some(Duration::from_millis(1)); // lsm6ds3tr datasheet
\ No newline at end of file
@@ -0,0 +1,240 @@
+//! A USB HID interface that can declare itself a boot device, modelled on `embassy_usb::class::hid`.
+//!
+//! The embassy HID class always uses subclass 0 and rejects SET_PROTOCOL(boot), which leaves
+//! BIOSes and boot loaders without a keyboard.  This one advertises the boot subclass/protocol when
+//! asked to, keeps track of the protocol the host selected, and lets the writer ask for it so the
+//! right report format can be sent.  Output reports only arrive over the control pipe (SET_REPORT),
+//! so no OUT endpoint is allocated.
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_usb::Builder;
+use embassy_usb::Handler;
+use embassy_usb::class::hid::{ReportId, RequestHandler};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Driver, Endpoint, EndpointError, EndpointIn};
+use embassy_usb::types::InterfaceNumber;
+
+const USB_CLASS_HID: u8 = 0x03;
+const USB_SUBCLASS_NONE: u8 = 0x00;
+const USB_SUBCLASS_BOOT: u8 = 0x01;
+
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+const HID_DESC_SPEC_1_11: [u8; 2] = [0x11, 0x01];
+const HID_DESC_COUNTRY_UNSPEC: u8 = 0x00;
+
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0a;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+
+/// The boot device an interface presents itself as, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum BootDevice {
+    None = 0,
+    Keyboard = 1,
+    Mouse = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+pub struct Config<'d> {
+    pub report_descriptor: &'d [u8],
+    pub request_handler: Option<&'d mut dyn RequestHandler>,
+    pub boot_device: BootDevice,
+    pub poll_ms: u8,
+    pub max_packet_size: u16,
+}
+
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    protocol: AtomicU8,
+}
+
+impl<'d> State<'d> {
+    pub const fn new() -> Self {
+        State {
+            control: MaybeUninit::uninit(),
+            protocol: AtomicU8::new(Protocol::Report as u8),
+        }
+    }
+}
+
+/// Sends input reports on the interface's interrupt IN endpoint
+pub struct HidWriter<'d, D: Driver<'d>, const N: usize> {
+    ep_in: D::EndpointIn,
+    protocol: &'d AtomicU8,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        let len = config.report_descriptor.len();
+        let subclass = if config.boot_device == BootDevice::None { USB_SUBCLASS_NONE } else { USB_SUBCLASS_BOOT };
+        let hid_descriptor = [
+            HID_DESC_SPEC_1_11[0],
+            HID_DESC_SPEC_1_11[1],
+            HID_DESC_COUNTRY_UNSPEC,
+            1, // number of following descriptors
+            HID_DESC_DESCTYPE_HID_REPORT,
+            (len & 0xFF) as u8,
+            (len >> 8 & 0xFF) as u8,
+        ];
+
+        let mut func = builder.function(USB_CLASS_HID, subclass, config.boot_device as u8);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_HID, subclass, config.boot_device as u8, None);
+        alt.descriptor(HID_DESC_DESCTYPE_HID, &hid_descriptor);
+        let ep_in = alt.endpoint_interrupt_in(None, config.max_packet_size, config.poll_ms);
+        drop(func);
+
+        let control = state.control.write(Control {
+            if_num,
+            report_descriptor: config.report_descriptor,
+            request_handler: config.request_handler,
+            protocol: &state.protocol,
+            hid_descriptor: [
+                9, // length including this byte
+                HID_DESC_DESCTYPE_HID,
+                hid_descriptor[0],
+                hid_descriptor[1],
+                hid_descriptor[2],
+                hid_descriptor[3],
+                hid_descriptor[4],
+                hid_descriptor[5],
+                hid_descriptor[6],
+            ],
+        });
+        builder.handler(control);
+
+        HidWriter { ep_in, protocol: &state.protocol }
+    }
+
+    /// the protocol most recently selected by the host - report protocol unless it asked for boot
+    pub fn protocol(&self) -> Protocol {
+        if self.protocol.load(Ordering::Acquire) == Protocol::Boot as u8 { Protocol::Boot } else { Protocol::Report }
+    }
+
+    pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
+        assert!(report.len() <= N);
+
+        let max_packet_size = usize::from(self.ep_in.info().max_packet_size);
+        let zlp_needed = report.len() < N && report.len().is_multiple_of(max_packet_size);
+        for chunk in report.chunks(max_packet_size) {
+            self.ep_in.write(chunk).await?;
+        }
+        if zlp_needed {
+            self.ep_in.write(&[]).await?;
+        }
+        Ok(())
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    report_descriptor: &'d [u8],
+    request_handler: Option<&'d mut dyn RequestHandler>,
+    protocol: &'d AtomicU8,
+    hid_descriptor: [u8; 9],
+}
+
+fn report_id(value: u16) -> Option<ReportId> {
+    match value >> 8 {
+        1 => Some(ReportId::In(value as u8)),
+        2 => Some(ReportId::Out(value as u8)),
+        3 => Some(ReportId::Feature(value as u8)),
+        _ => None,
+    }
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        // the HID spec has devices come out of reset in report protocol
+        self.protocol.store(Protocol::Report as u8, Ordering::Release);
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            HID_REQ_SET_IDLE => {
+                if let Some(handler) = self.request_handler.as_mut() {
+                    let id = req.value as u8;
+                    let id = (id != 0).then_some(ReportId::In(id));
+                    let dur = u32::from(req.value >> 8);
+                    let dur = if dur == 0 { u32::MAX } else { 4 * dur };
+                    handler.set_idle_ms(id, dur);
+                }
+                Some(OutResponse::Accepted)
+            }
+            HID_REQ_SET_REPORT => match (report_id(req.value), self.request_handler.as_mut()) {
+                (Some(id), Some(handler)) => Some(handler.set_report(id, data)),
+                _ => Some(OutResponse::Rejected),
+            },
+            HID_REQ_SET_PROTOCOL => {
+                let protocol = if req.value == 0 { Protocol::Boot } else { Protocol::Report };
+                defmt::info!("HID interface {} set to {} protocol", self.if_num.0, protocol);
+                self.protocol.store(protocol as u8, Ordering::Release);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.index != self.if_num.0 as u16 {
+            return None;
+        }
+
+        match (req.request_type, req.recipient) {
+            (RequestType::Standard, Recipient::Interface) => match req.request {
+                Request::GET_DESCRIPTOR => match (req.value >> 8) as u8 {
+                    HID_DESC_DESCTYPE_HID_REPORT => Some(InResponse::Accepted(self.report_descriptor)),
+                    HID_DESC_DESCTYPE_HID => Some(InResponse::Accepted(&self.hid_descriptor)),
+                    _ => Some(InResponse::Rejected),
+                },
+                _ => Some(InResponse::Rejected),
+            },
+            (RequestType::Class, Recipient::Interface) => match req.request {
+                HID_REQ_GET_REPORT => {
+                    let size = report_id(req.value)
+                        .and_then(|id| self.request_handler.as_mut().and_then(|h| h.get_report(id, buf)));
+                    match size {
+                        Some(size) => Some(InResponse::Accepted(&buf[0..size])),
+                        None => Some(InResponse::Rejected),
+                    }
+                }
+                HID_REQ_GET_IDLE => {
+                    let id = req.value as u8;
+                    let id = (id != 0).then_some(ReportId::In(id));
+                    match self.request_handler.as_mut().and_then(|h| h.get_idle_ms(id)) {
+                        Some(dur) => {
+                            buf[0] = u8::try_from(dur / 4).unwrap_or(0);
+                            Some(InResponse::Accepted(&buf[0..1]))
+                        }
+                        None => Some(InResponse::Rejected),
+                    }
+                }
+                HID_REQ_GET_PROTOCOL => {
+                    buf[0] = self.protocol.load(Ordering::Acquire);
+                    Some(InResponse::Accepted(&buf[0..1]))
+                }
+                _ => Some(InResponse::Rejected),
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! The lock LEDs (Num/Caps/Scroll Lock etc.) as last set by the host in a keyboard output report.
+//!
+//! The USB request handler stores them here; anything else can read them with `host_leds`, or wait
+//! on `HOST_LEDS_CHANGED` to hear about changes.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
+pub static HOST_LEDS_CHANGED: Signal<CriticalSectionRawMutex, HostLeds> = Signal::new();
+
+/// The LED output report byte, in the bit order of the HID LED usage page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub struct HostLeds(pub u8);
+
+#[allow(dead_code)] // not every LED is shown or used
+impl HostLeds {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+    pub const COMPOSE: u8 = 1 << 3;
+    pub const KANA: u8 = 1 << 4;
+
+    pub fn num_lock(self) -> bool {
+        self.0 & Self::NUM_LOCK != 0
+    }
+
+    pub fn caps_lock(self) -> bool {
+        self.0 & Self::CAPS_LOCK != 0
+    }
+
+    pub fn scroll_lock(self) -> bool {
+        self.0 & Self::SCROLL_LOCK != 0
+    }
+
+    pub fn compose(self) -> bool {
+        self.0 & Self::COMPOSE != 0
+    }
+
+    pub fn kana(self) -> bool {
+        self.0 & Self::KANA != 0
+    }
+
+    /// board RGB LED duties showing the lock state: red for caps lock, green for num lock and blue
+    /// for scroll lock, kept dim since they stay on
+    pub fn board_led_duties(self) -> [u16; 3] {
+        const BRIGHTNESS: u16 = 20;
+        [
+            if self.caps_lock() { BRIGHTNESS } else { 0 },
+            if self.num_lock() { BRIGHTNESS } else { 0 },
+            if self.scroll_lock() { BRIGHTNESS } else { 0 },
+        ]
+    }
+}
+
+pub fn host_leds() -> HostLeds {
+    HostLeds(HOST_LEDS.load(Ordering::Acquire))
+}
+
+pub fn set_host_leds(leds: HostLeds) {
+    let previous = HOST_LEDS.swap(leds.0, Ordering::AcqRel);
+    if previous != leds.0 {
+        defmt::debug!("host leds now {}", leds);
+        HOST_LEDS_CHANGED.signal(leds);
+    }
+}
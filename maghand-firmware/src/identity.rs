@@ -0,0 +1,34 @@
+//! Which board this is, for the USB descriptors and the configuration interfaces.
+//!
+//! The serial number is the nRF52840's factory-programmed 64-bit device ID (FICR DEVICEID), so every
+//! board gets its own and hosts can tell two of them apart.  The product string carries the hand and
+//! hardware revision, which are set at build time.
+
+use core::fmt::Write;
+
+use embassy_nrf::pac;
+use embassy_sync::lazy_lock::LazyLock;
+
+use heapless::String;
+
+use crate::hardware_consts::{Hand, HAND, HARDWARE_REVISION};
+
+pub struct Identity {
+    pub device_id: u64,
+    /// the device ID as 16 hex digits
+    pub serial_number: String<16>,
+    pub product: String<32>,
+}
+
+pub static IDENTITY: LazyLock<Identity> = LazyLock::new(|| {
+    let device_id = pac::FICR.deviceid(0).read() as u64 | (pac::FICR.deviceid(1).read() as u64) << 32;
+    let mut serial_number = String::new();
+    write!(serial_number, "{:016X}", device_id).ok();
+    let hand = match HAND {
+        Hand::Left => "left",
+        Hand::Right => "right",
+    };
+    let mut product = String::new();
+    write!(product, "maghand-keyboard {} rev{}", hand, HARDWARE_REVISION).ok();
+    Identity { device_id, serial_number, product }
+});
@@ -0,0 +1,634 @@
+//! Turns key changes into keyboard usage presses and releases, by way of the keymap actions.
+//!
+//! Nothing in here touches USB: the transport feeds in `KeySignal`s, calls `poll` when `deadline`
+//! comes due, and reports the `UsageEvent`s it takes out with `next_event`.
+//!
+//! Key changes can get lost on the way here (the key change bus drops them when it is full), so the
+//! transport also calls `resync` with the current state of every key, and any key whose state was
+//! missed is pressed or released to match.  A key that reads as down for longer than the stuck key
+//! timeout (e.g. from a drifting sensor) is released and ignored until it comes back up.  The timeout
+//! is a runtime setting, saved with the others, and off by default, since some keys are held down for
+//! a long time on purpose.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant};
+
+use heapless::Deque;
+use heapless::index_map::FnvIndexMap;
+
+use crate::caps_word::CapsWord;
+use crate::dynamic_macros::DYNAMIC_MACROS;
+use crate::hardware_consts::{ALL_KEY_NAMES, N_ALL_KEYS};
+use crate::host_leds::host_leds;
+use crate::keys::{keymap_get, Action, KeySignal, Layer, LAYERS};
+use crate::leader::{Leader, LeaderOutcome, LEADER_PASS_THROUGH_UNMATCHED, LEADER_SEQUENCES};
+use crate::macros::{MacroPlayer, MacroSource};
+use crate::midi::NoteChange;
+use crate::mouse_keys::{MouseDirection, MouseKeys, NO_KEY};
+use crate::oneshot::OneShotMods;
+use crate::report::{modifier_bit, UsageEvent};
+use crate::tap_dance::{Resolution, TapDance};
+
+/// anything shorter would release keys that are only being held, e.g. for key repeat
+pub const MIN_STUCK_KEY_TIMEOUT_MS: u32 = 10_000;
+const EVENT_QUEUE_LEN: usize = 16;
+const N_KEYS_POWEROF2: usize = N_ALL_KEYS.next_power_of_two();
+// the highest key number is the last one
+const _: () = assert!(ALL_KEY_NAMES[N_ALL_KEYS - 1] < 128, "key numbers have to fit the stuck key bits");
+
+/// how long a key can be held before it is taken to be stuck, in ms, or 0 to never time out
+static STUCK_KEY_TIMEOUT_MS: AtomicU32 = AtomicU32::new(0);
+
+/// The stuck key timeout in ms, 0 if keys never time out.
+pub fn stuck_key_timeout_ms() -> u32 {
+    STUCK_KEY_TIMEOUT_MS.load(Ordering::Relaxed)
+}
+
+/// Sets the stuck key timeout, in ms or 0 to never time out.  Returns false, leaving it as it was, if
+/// it is shorter than `MIN_STUCK_KEY_TIMEOUT_MS`.  A held key goes by the new timeout from the next key
+/// change.
+pub fn set_stuck_key_timeout_ms(ms: u32) -> bool {
+    if ms != 0 && ms < MIN_STUCK_KEY_TIMEOUT_MS {
+        return false;
+    }
+    STUCK_KEY_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+    true
+}
+
+fn stuck_key_timeout() -> Option<Duration> {
+    match stuck_key_timeout_ms() {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
+
+pub struct KeyProcessor {
+    macro_player: MacroPlayer,
+    tap_dance: TapDance,
+    leader: Leader,
+    caps_word: CapsWord,
+    oneshot: OneShotMods,
+    mouse_keys: MouseKeys,
+    /// bit n is set if `LAYERS[n]` is active
+    active_layers: u8,
+    /// the action each held key was pressed as (None if it was swallowed), so that it is released as
+    /// the same thing even if the layers change while it's down
+    pressed: FnvIndexMap<u8, Option<Action>, N_KEYS_POWEROF2>,
+    /// when each held key went down
+    pressed_at: FnvIndexMap<u8, Instant, N_KEYS_POWEROF2>,
+    /// bit n is set if key number n timed out as stuck and hasn't come back up yet
+    stuck: u128,
+    events: Deque<UsageEvent, EVENT_QUEUE_LEN>,
+}
+
+impl KeyProcessor {
+    pub fn new() -> Self {
+        KeyProcessor {
+            macro_player: MacroPlayer::new(),
+            tap_dance: TapDance::new(),
+            leader: Leader::new(&LEADER_SEQUENCES, LEADER_PASS_THROUGH_UNMATCHED),
+            caps_word: CapsWord::new(),
+            oneshot: OneShotMods::new(),
+            mouse_keys: MouseKeys::new(),
+            active_layers: 1 << Layer::Default as u8,
+            pressed: FnvIndexMap::new(),
+            pressed_at: FnvIndexMap::new(),
+            stuck: 0,
+            events: Deque::new(),
+        }
+    }
+
+    /// the action for a key on the highest active layer that maps it
+    fn lookup(&self, keynumber: u8) -> Option<Action> {
+        LAYERS.iter().rev()
+            .filter(|layer| self.active_layers & (1 << **layer as u8) != 0)
+            .find_map(|layer| keymap_get(keynumber, *layer))
+    }
+
+    fn set_layer(&mut self, layer: Layer, on: bool) {
+        if layer == Layer::Default {
+            return; // always active
+        }
+        if on {
+            self.active_layers |= 1 << layer as u8;
+        } else {
+            self.active_layers &= !(1 << layer as u8);
+        }
+        defmt::debug!("layer {} {}", layer, if on { "on" } else { "off" });
+    }
+
+    /// when `poll` should next be called, or None if nothing is waiting on time
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.events.is_empty() {
+            return Some(Instant::MIN);
+        }
+        [
+            self.macro_player.deadline(),
+            self.tap_dance.deadline(),
+            self.leader.deadline(),
+            self.caps_word.deadline(),
+            self.oneshot.deadline(),
+            self.mouse_keys.deadline(),
+            self.stuck_deadline(),
+        ].into_iter().flatten().min()
+    }
+
+    /// Handles anything whose time has come.  `key_depth` gives how far a key is pressed, for mouse
+    /// keys that scale with depth.
+    pub fn poll(&mut self, now: Instant, key_depth: impl Fn(u8) -> Option<f32>) {
+        if let Some(resolution) = self.tap_dance.timeout(now) {
+            self.resolve_tap_dance(resolution, now);
+        }
+        if let Some(outcome) = self.leader.timeout(now) {
+            self.resolve_leader(outcome, now);
+        }
+        self.caps_word.timeout(now);
+        self.oneshot.timeout(now);
+        if let Some(deadline) = self.stuck_deadline() && now >= deadline {
+            self.release_stuck(now);
+        }
+        if let Some(report) = self.mouse_keys.tick(now, key_depth) {
+            self.push_event(UsageEvent::Mouse(report));
+        }
+    }
+
+    /// The next usage change to report, if any.  Macro steps come out one at a time, after any
+    /// events from key changes.
+    pub fn next_event(&mut self, now: Instant) -> Option<UsageEvent> {
+        self.events.pop_front().or_else(|| self.macro_player.poll(now))
+    }
+
+    /// whether a key is physically down, whatever it was pressed as
+    pub fn is_held(&self, keynumber: u8) -> bool {
+        self.pressed.contains_key(&keynumber)
+    }
+
+    /// when the longest-held key will have been down for the stuck key timeout
+    fn stuck_deadline(&self) -> Option<Instant> {
+        Some(*self.pressed_at.values().min()? + stuck_key_timeout()?)
+    }
+
+    fn release_stuck(&mut self, now: Instant) {
+        let Some(timeout) = stuck_key_timeout() else { return };
+        let stuck: heapless::Vec<u8, N_ALL_KEYS> = self.pressed_at.iter()
+            .filter(|(_, at)| now - **at >= timeout)
+            .map(|(keynumber, _)| *keynumber)
+            .collect();
+        for keynumber in stuck {
+            defmt::warn!("key {} held for over {} ms, releasing it as stuck", keynumber, timeout.as_millis());
+            self.key_changed(KeySignal { toggle_on: false, keynumber }, now);
+            self.stuck |= 1 << keynumber;
+        }
+    }
+
+    /// Presses or releases keys to match `is_down`, the current state of each key, in case any key
+    /// changes went missing.  Stuck keys stay released until they read as up again.
+    pub fn resync(&mut self, is_down: impl Fn(u8) -> bool, now: Instant) {
+        for keynumber in ALL_KEY_NAMES {
+            let down = is_down(keynumber);
+            if self.stuck & (1 << keynumber) != 0 {
+                if !down {
+                    defmt::info!("stuck key {} came back up", keynumber);
+                    self.stuck &= !(1 << keynumber);
+                }
+                continue;
+            }
+            if down != self.is_held(keynumber) {
+                defmt::warn!("missed a change of key {}, resyncing it to {}", keynumber, if down { "down" } else { "up" });
+                self.key_changed(KeySignal { toggle_on: down, keynumber }, now);
+            }
+        }
+    }
+
+    pub fn key_changed(&mut self, signal: KeySignal, now: Instant) {
+        if self.stuck & (1 << signal.keynumber) != 0 {
+            // released after timing out, so its next change is coming back up
+            self.stuck &= !(1 << signal.keynumber);
+            if !signal.toggle_on {
+                return;
+            }
+        }
+        if signal.toggle_on {
+            self.pressed_at.insert(signal.keynumber, now).ok();
+        } else {
+            self.pressed_at.remove(&signal.keynumber);
+        }
+
+        if !signal.toggle_on {
+            match self.pressed.remove(&signal.keynumber) {
+                Some(Some(action)) => self.key_action(signal.keynumber, action, false, now),
+                Some(None) => {} // swallowed on the way down
+                None => defmt::warn!("keynumber {} released without being pressed", signal.keynumber),
+            }
+            return;
+        }
+
+        let Some(action) = self.lookup(signal.keynumber) else {
+            defmt::warn!("No keycode mapped for keynumber {}, skipping", signal.keynumber);
+            // still held, so it isn't taken for a missed press
+            self.pressed.insert(signal.keynumber, None).ok();
+            return;
+        };
+
+        if !matches!(action, Action::TapDance(_)) && let Some(resolution) = self.tap_dance.interrupt() {
+            self.resolve_tap_dance(resolution, now);
+        }
+
+        if self.leader.is_active() {
+            // keys typed after the leader go into the sequence instead of to the host
+            if let Action::Key(kbusage) = action {
+                if self.pressed.insert(signal.keynumber, None).is_err() {
+                    defmt::warn!("too many keys held");
+                }
+                if let Some(outcome) = self.leader.key(kbusage, now) {
+                    self.resolve_leader(outcome, now);
+                }
+                return;
+            }
+        }
+
+        if self.pressed.insert(signal.keynumber, Some(action)).is_err() {
+            defmt::warn!("too many keys held, {} will not be released", signal.keynumber);
+        }
+        self.key_action(signal.keynumber, action, true, now);
+    }
+
+    /// handles the press or release of an action that came straight from a key
+    fn key_action(&mut self, keynumber: u8, action: Action, pressed: bool, now: Instant) {
+        match action {
+            Action::TapDance(id) => {
+                let resolution = if pressed {
+                    self.tap_dance.press(id, keynumber, now)
+                } else {
+                    self.tap_dance.release(keynumber, now)
+                };
+                if let Some(resolution) = resolution {
+                    self.resolve_tap_dance(resolution, now);
+                }
+            }
+            Action::Leader => {
+                if pressed {
+                    self.leader.start(now);
+                }
+            }
+            Action::MouseMove(direction) | Action::MouseWheel(direction) => {
+                self.mouse_motion(keynumber, matches!(action, Action::MouseWheel(_)), direction, pressed, now);
+            }
+            // from a key, so it can have velocity and aftertouch
+            Action::MidiNote(note) => self.push_event(UsageEvent::Note(NoteChange { keynumber, note, on: pressed })),
+            _ => self.action(action, pressed, now),
+        }
+    }
+
+    fn mouse_motion(&mut self, keynumber: u8, wheel: bool, direction: MouseDirection, pressed: bool, now: Instant) {
+        if pressed {
+            self.mouse_keys.press_motion(keynumber, wheel, direction, now);
+        } else {
+            self.mouse_keys.release_motion(keynumber, wheel, direction);
+        }
+    }
+
+    fn resolve_leader(&mut self, outcome: LeaderOutcome, now: Instant) {
+        match outcome {
+            LeaderOutcome::Matched(action) => {
+                self.action(action, true, now);
+                self.action(action, false, now);
+            }
+            LeaderOutcome::Unmatched(sequence) => {
+                for kbusage in sequence {
+                    self.action(Action::Key(kbusage), true, now);
+                    self.action(Action::Key(kbusage), false, now);
+                }
+            }
+        }
+    }
+
+    fn resolve_tap_dance(&mut self, resolution: Resolution, now: Instant) {
+        match resolution {
+            Resolution::Tap(action) => {
+                self.action(action, true, now);
+                self.action(action, false, now);
+            }
+            Resolution::Press(action) => self.action(action, true, now),
+            Resolution::Release(action) => self.action(action, false, now),
+        }
+    }
+
+    fn push_event(&mut self, event: UsageEvent) {
+        if self.events.push_back(event).is_err() {
+            defmt::warn!("key event queue full, dropping {}", event);
+        }
+    }
+
+    fn action(&mut self, action: Action, pressed: bool, now: Instant) {
+        match action {
+            Action::Key(kbusage) => {
+                let keycode = kbusage as u8;
+                let event = if !pressed {
+                    UsageEvent::Release(keycode)
+                } else {
+                    let mut weak_modifier = self.caps_word.key(keycode, host_leds().caps_lock(), now);
+                    if modifier_bit(keycode).is_none() {
+                        weak_modifier |= self.oneshot.other_key_pressed();
+                    }
+                    if weak_modifier != 0 {
+                        UsageEvent::PressWeak { usage: keycode, modifier: weak_modifier }
+                    } else {
+                        UsageEvent::Press(keycode)
+                    }
+                };
+                self.push_event(event);
+                DYNAMIC_MACROS.lock(|dm| dm.borrow_mut().record(keycode, pressed));
+            }
+            Action::Consumer(usage) => {
+                let usage = usage as u16;
+                self.push_event(if pressed { UsageEvent::ConsumerPress(usage) } else { UsageEvent::ConsumerRelease(usage) });
+            }
+            Action::System(usage) => {
+                let usage = usage as u8;
+                self.push_event(if pressed { UsageEvent::SystemPress(usage) } else { UsageEvent::SystemRelease(usage) });
+            }
+            Action::MouseMove(direction) => self.mouse_motion(NO_KEY, false, direction, pressed, now),
+            Action::MouseWheel(direction) => self.mouse_motion(NO_KEY, true, direction, pressed, now),
+            Action::MouseButton(n) => {
+                let report = self.mouse_keys.button(n, pressed);
+                self.push_event(UsageEvent::Mouse(report));
+            }
+            Action::OneShot(kbusage) => {
+                let keycode = kbusage as u8;
+                let Some(bit) = modifier_bit(keycode) else {
+                    defmt::warn!("one-shot key {} is not a modifier", keycode);
+                    return;
+                };
+                if pressed {
+                    self.push_event(UsageEvent::Press(keycode));
+                    self.oneshot.press(bit);
+                } else {
+                    self.push_event(UsageEvent::Release(keycode));
+                    self.oneshot.release(bit, now);
+                }
+            }
+            Action::CapsWord => {
+                if pressed {
+                    let on = !self.caps_word.is_active();
+                    self.caps_word.set(on, now);
+                }
+            }
+            Action::Macro(id) => {
+                if pressed {
+                    self.macro_player.trigger(MacroSource::Stored(id));
+                }
+            }
+            Action::DynamicMacroRecord(slot) => {
+                if pressed {
+                    DYNAMIC_MACROS.lock(|dm| {
+                        let mut dm = dm.borrow_mut();
+                        match dm.recording() {
+                            Some(_) => dm.stop_recording(),
+                            None => dm.start_recording(slot),
+                        }
+                    });
+                }
+            }
+            Action::DynamicMacroStop => {
+                if pressed {
+                    DYNAMIC_MACROS.lock(|dm| dm.borrow_mut().stop_recording());
+                }
+            }
+            Action::DynamicMacroPlay(slot) => {
+                if pressed {
+                    if DYNAMIC_MACROS.lock(|dm| dm.borrow().recording()) == Some(slot) {
+                        defmt::warn!("not playing dynamic macro {} while recording it", slot);
+                    } else {
+                        self.macro_player.trigger(MacroSource::Dynamic(slot));
+                    }
+                }
+            }
+            Action::MidiNote(note) => self.push_event(UsageEvent::Note(NoteChange { keynumber: NO_KEY, note, on: pressed })),
+            Action::LayerMomentary(layer) => self.set_layer(layer, pressed),
+            Action::LayerToggle(layer) => {
+                if pressed {
+                    let on = self.active_layers & (1 << layer as u8) == 0;
+                    self.set_layer(layer, on);
+                }
+            }
+            Action::TapDance(_) | Action::Leader => {
+                defmt::warn!("{} can't be the result of another action", defmt::Debug2Format(&action));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use usbd_hid::descriptor::KeyboardUsage;
+    use usbd_hid::descriptor::KeyboardUsage::{Keyboard1Exclamation as K1, KeyboardAa as KA, KeyboardBb as KB,
+                                              KeyboardDashUnderscore as KMINUS, KeyboardSpacebar as KSPACE};
+
+    use crate::caps_word::CAPS_WORD_IDLE_TIMEOUT;
+    use crate::keys::keymap_set;
+    use crate::oneshot::ONESHOT_TIMEOUT;
+    use crate::report::{KeyboardState, BOOT_REPORT_SIZE};
+
+    const CAPS_WORD: u8 = 120;
+    const A: u8 = 121;
+    const B: u8 = 122;
+    const MINUS: u8 = 123;
+    const SPACE: u8 = 124;
+    const ONE: u8 = 125;
+    const ONESHOT_SHIFT: u8 = 126;
+    const CONTROL: u8 = 127;
+    /// the default layer for these tests, which all set the same keys to the same actions so they
+    /// can share the keymap
+    const TEST_KEYS: [(u8, Action); 8] = [
+        (CAPS_WORD, Action::CapsWord),
+        (A, Action::Key(KeyboardUsage::KeyboardAa)),
+        (B, Action::Key(KeyboardUsage::KeyboardBb)),
+        (MINUS, Action::Key(KeyboardUsage::KeyboardDashUnderscore)),
+        (SPACE, Action::Key(KeyboardUsage::KeyboardSpacebar)),
+        (ONE, Action::Key(KeyboardUsage::Keyboard1Exclamation)),
+        (ONESHOT_SHIFT, Action::OneShot(KeyboardUsage::KeyboardLeftShift)),
+        (CONTROL, Action::Key(KeyboardUsage::KeyboardLeftControl)),
+    ];
+    const SHIFT_BIT: u8 = 0x02;
+    const CONTROL_BIT: u8 = 0x01;
+
+    /// A `KeyProcessor` with its events batched into reports the way `usb_kb` does it.
+    struct Keyboard {
+        processor: KeyProcessor,
+        state: KeyboardState,
+        now: Instant,
+        /// the boot reports sent so far, not yet taken by `reports`
+        sent: std::vec::Vec<[u8; BOOT_REPORT_SIZE]>,
+    }
+
+    impl Keyboard {
+        fn new() -> Self {
+            for (keynumber, action) in TEST_KEYS {
+                keymap_set(keynumber, Layer::Default, Some(action));
+            }
+            Keyboard { processor: KeyProcessor::new(), state: KeyboardState::new(), now: Instant::from_secs(1000),
+                       sent: std::vec::Vec::new() }
+        }
+
+        /// Changes keys all at once, as if they came in together, and sends the resulting reports.
+        fn change(&mut self, changes: &[(u8, bool)]) {
+            self.now += Duration::from_millis(10);
+            for (keynumber, down) in changes {
+                self.processor.key_changed(KeySignal { toggle_on: *down, keynumber: *keynumber }, self.now);
+            }
+            self.send();
+        }
+
+        fn tap(&mut self, keynumber: u8) {
+            self.change(&[(keynumber, true)]);
+            self.change(&[(keynumber, false)]);
+        }
+
+        fn wait(&mut self, duration: Duration) {
+            self.now += duration;
+            self.send();
+        }
+
+        fn send(&mut self) {
+            self.processor.poll(self.now, |_| None);
+            while let Some(event) = self.processor.next_event(self.now) {
+                assert!(event.is_keyboard(), "{event:?}");
+                if self.state.must_send_before(event) {
+                    self.send_report();
+                }
+                self.state.apply(event);
+            }
+            if self.state.is_dirty() {
+                self.send_report();
+            }
+        }
+
+        fn send_report(&mut self) {
+            self.sent.push(self.state.boot_report());
+            self.state.mark_sent();
+        }
+
+        fn reports(&mut self) -> std::vec::Vec<[u8; BOOT_REPORT_SIZE]> {
+            core::mem::take(&mut self.sent)
+        }
+    }
+
+    fn report(modifier: u8, keys: &[KeyboardUsage]) -> [u8; BOOT_REPORT_SIZE] {
+        let mut report = [0; BOOT_REPORT_SIZE];
+        report[0] = modifier;
+        for (slot, key) in report[2..].iter_mut().zip(keys) {
+            *slot = *key as u8;
+        }
+        report
+    }
+
+    #[test]
+    fn caps_word_shifts_until_a_non_word_key() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(CAPS_WORD);
+        assert!(keyboard.reports().is_empty());
+        keyboard.tap(A);
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[KA]), report(0, &[])]);
+        // digits carry on the word without being shifted, and minus is shifted to underscore
+        keyboard.tap(ONE);
+        keyboard.tap(MINUS);
+        assert_eq!(keyboard.reports(), [report(0, &[K1]), report(0, &[]), report(SHIFT_BIT, &[KMINUS]), report(0, &[])]);
+        // space isn't part of a word, so it goes out as it is and ends caps word
+        keyboard.tap(SPACE);
+        keyboard.tap(B);
+        assert_eq!(keyboard.reports(), [report(0, &[KSPACE]), report(0, &[]), report(0, &[KB]), report(0, &[])]);
+    }
+
+    #[test]
+    fn caps_word_ends_on_other_modifiers_but_not_shift() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(CAPS_WORD);
+        // the one-shot key held down is an ordinary shift
+        keyboard.change(&[(ONESHOT_SHIFT, true)]);
+        keyboard.tap(A);
+        keyboard.change(&[(ONESHOT_SHIFT, false)]);
+        keyboard.reports();
+        keyboard.tap(B);
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[KB]), report(0, &[])]);
+        keyboard.tap(CONTROL);
+        keyboard.tap(A);
+        assert_eq!(keyboard.reports(), [report(CONTROL_BIT, &[]), report(0, &[]), report(0, &[KA]), report(0, &[])]);
+    }
+
+    #[test]
+    fn caps_word_times_out() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(CAPS_WORD);
+        keyboard.wait(CAPS_WORD_IDLE_TIMEOUT);
+        keyboard.tap(A);
+        assert_eq!(keyboard.reports(), [report(0, &[KA]), report(0, &[])]);
+    }
+
+    #[test]
+    fn oneshot_applies_to_exactly_one_key() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(ONESHOT_SHIFT);
+        // the modifier goes out as itself while it is down, like any other
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[]), report(0, &[])]);
+        keyboard.tap(A);
+        keyboard.tap(B);
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[KA]), report(0, &[]), report(0, &[KB]), report(0, &[])]);
+    }
+
+    #[test]
+    fn oneshot_held_is_an_ordinary_modifier() {
+        let mut keyboard = Keyboard::new();
+        keyboard.change(&[(ONESHOT_SHIFT, true)]);
+        keyboard.tap(A);
+        keyboard.change(&[(ONESHOT_SHIFT, false)]);
+        // used while held, so it isn't armed for the next key
+        keyboard.tap(B);
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[]), report(SHIFT_BIT, &[KA]), report(SHIFT_BIT, &[]),
+                                        report(0, &[]), report(0, &[KB]), report(0, &[])]);
+    }
+
+    #[test]
+    fn oneshot_times_out() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(ONESHOT_SHIFT);
+        keyboard.wait(ONESHOT_TIMEOUT);
+        keyboard.reports();
+        keyboard.tap(A);
+        assert_eq!(keyboard.reports(), [report(0, &[KA]), report(0, &[])]);
+    }
+
+    #[test]
+    fn weak_modifier_stays_in_its_own_report() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(ONESHOT_SHIFT);
+        keyboard.reports();
+        // two keys coming in together would normally share a report, but the one-shot shift only
+        // belongs to the first
+        keyboard.change(&[(A, true), (B, true)]);
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[KA]), report(0, &[KA, KB])]);
+        keyboard.change(&[(A, false), (B, false)]);
+        assert_eq!(keyboard.reports(), [report(0, &[])]);
+    }
+
+    #[test]
+    fn weak_modifier_is_dropped_while_its_key_is_held() {
+        let mut keyboard = Keyboard::new();
+        keyboard.tap(CAPS_WORD);
+        keyboard.change(&[(A, true)]);
+        // a digit pressed while the shifted letter is still down isn't shifted along with it
+        keyboard.change(&[(ONE, true)]);
+        keyboard.change(&[(A, false), (ONE, false)]);
+        assert_eq!(keyboard.reports(), [report(SHIFT_BIT, &[KA]), report(0, &[KA, K1]), report(0, &[])]);
+    }
+
+    #[test]
+    fn stuck_key_timeout_has_a_minimum() {
+        // only ever refused here, since every test's keyboard shares the timeout, which is off
+        assert!(!set_stuck_key_timeout_ms(MIN_STUCK_KEY_TIMEOUT_MS - 1));
+        assert_eq!(stuck_key_timeout_ms(), 0);
+    }
+}
@@ -1,23 +1,187 @@
-use crate::{KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS};
-use crate::hardware_consts::N_KEYS;
+use crate::hardware_consts::{KEY_NAMES, N_ALL_KEYS, N_KEYS, SPLIT_CENTRAL};
+
+use core::cell::RefCell;
 
-use embassy_nrf::gpio::Level;
 use embassy_sync::pubsub::Publisher;
-use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
 use embassy_sync::lazy_lock::LazyLock;
+use embassy_time::{Duration, Instant};
 
 use usbd_hid::descriptor::KeyboardUsage;
 
+use crate::extra_keys::{ConsumerUsage, SystemUsage};
+use crate::midi::MIDI_FIRST_NOTE;
+use crate::mouse_keys::{MouseDirection, MOUSE_BUTTONS};
+
 use heapless::index_map::FnvIndexMap;
 
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+// layers higher in this list take priority over lower ones when active
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, defmt::Format)]
 pub enum Layer {
-    Default
+    Default,
+    Fn,
+    Mouse,
+    /// the keys play notes - see `midi`
+    Midi,
 }
 //const N_LAYERS: usize = mem::variant_count::<Layers>(); // not stabilized - https://github.com/rust-lang/rust/issues/73662
-const N_LAYERS: usize = 1;
+pub const N_LAYERS: usize = 4;
+pub const LAYERS: [Layer; N_LAYERS] = [Layer::Default, Layer::Fn, Layer::Mouse, Layer::Midi];
+
+impl Layer {
+    /// the layer at position `index` in `LAYERS`
+    pub fn from_index(index: u8) -> Option<Layer> {
+        LAYERS.get(index as usize).copied()
+    }
+}
+
+/// What a key does when pressed, as looked up in the `KEYMAP`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[allow(dead_code)] // not every action is used in the default keymap
+pub enum Action {
+    Key(KeyboardUsage),
+    /// media, volume, brightness etc. - see `extra_keys`
+    Consumer(ConsumerUsage),
+    /// power, sleep and wake
+    System(SystemUsage),
+    /// moves the cursor while held - see `mouse_keys`
+    MouseMove(MouseDirection),
+    /// scrolls while held, with left and right for horizontal scrolling
+    MouseWheel(MouseDirection),
+    /// mouse button n, 0 being the left button
+    MouseButton(u8),
+    /// plays macro number n from the macro buffer - see `macros`
+    Macro(u8),
+    /// starts recording into dynamic macro slot n, or stops if already recording
+    DynamicMacroRecord(u8),
+    DynamicMacroStop,
+    DynamicMacroPlay(u8),
+    /// tap dance n - see `tap_dance`
+    TapDance(u8),
+    /// starts a leader sequence - see `leader`
+    Leader,
+    /// the layer is active while the key is held
+    LayerMomentary(Layer),
+    LayerToggle(Layer),
+    /// toggles caps word - see `caps_word`
+    CapsWord,
+    /// a one-shot modifier, which must be one of the modifier usages - see `oneshot`
+    OneShot(KeyboardUsage),
+    /// plays MIDI note number n (0-127) while held - see `midi`
+    MidiNote(u8),
+}
+
+/// the size of an encoded action: a kind byte and a 16-bit little-endian parameter
+pub const ACTION_ENCODED_SIZE: usize = 3;
+/// the encoding of "no action", for keys that aren't mapped on a layer
+pub const NO_ACTION_ENCODED: [u8; ACTION_ENCODED_SIZE] = [0; ACTION_ENCODED_SIZE];
+
+impl Action {
+    /// The byte encoding used in flash and in the raw HID protocol.  Kind numbers must never be
+    /// reused, since they end up in saved keymaps.
+    pub fn encode(&self) -> [u8; ACTION_ENCODED_SIZE] {
+        let (kind, param): (u8, u16) = match *self {
+            Action::Key(usage) => (1, usage as u16),
+            Action::Consumer(usage) => (2, usage as u16),
+            Action::System(usage) => (3, usage as u16),
+            Action::MouseMove(direction) => (4, direction as u16),
+            Action::MouseWheel(direction) => (5, direction as u16),
+            Action::MouseButton(n) => (6, n as u16),
+            Action::Macro(n) => (7, n as u16),
+            Action::DynamicMacroRecord(n) => (8, n as u16),
+            Action::DynamicMacroStop => (9, 0),
+            Action::DynamicMacroPlay(n) => (10, n as u16),
+            Action::TapDance(n) => (11, n as u16),
+            Action::Leader => (12, 0),
+            Action::LayerMomentary(layer) => (13, layer as u16),
+            Action::LayerToggle(layer) => (14, layer as u16),
+            Action::CapsWord => (15, 0),
+            Action::OneShot(usage) => (16, usage as u16),
+            Action::MidiNote(note) => (17, note as u16),
+        };
+        let [lo, hi] = param.to_le_bytes();
+        [kind, lo, hi]
+    }
+
+    /// The inverse of `encode`.  None for "no action" or anything that isn't a valid action.
+    pub fn decode(bytes: [u8; ACTION_ENCODED_SIZE]) -> Option<Action> {
+        let [kind, lo, hi] = bytes;
+        let param = u16::from_le_bytes([lo, hi]);
+        let byte = u8::try_from(param).ok();
+        let action = match kind {
+            1 => Action::Key(KeyboardUsage::from(byte?)),
+            2 => Action::Consumer(ConsumerUsage::from_u16(param)?),
+            3 => Action::System(SystemUsage::from_u8(byte?)?),
+            4 => Action::MouseMove(MouseDirection::from_u8(byte?)?),
+            5 => Action::MouseWheel(MouseDirection::from_u8(byte?)?),
+            6 => Action::MouseButton(byte.filter(|n| *n < MOUSE_BUTTONS)?),
+            7 => Action::Macro(byte?),
+            8 => Action::DynamicMacroRecord(byte?),
+            9 => Action::DynamicMacroStop,
+            10 => Action::DynamicMacroPlay(byte?),
+            11 => Action::TapDance(byte?),
+            12 => Action::Leader,
+            13 => Action::LayerMomentary(Layer::from_index(byte?)?),
+            14 => Action::LayerToggle(Layer::from_index(byte?)?),
+            15 => Action::CapsWord,
+            16 => Action::OneShot(KeyboardUsage::from(byte?)),
+            17 => Action::MidiNote(byte.filter(|note| *note < 0x80)?),
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// The per-key settings that can be changed at runtime and saved to flash
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct KeySettings {
+    pub switch_point: f32,
+    pub hysteresis_fraction: f32,
+    pub filter_alpha: f32,
+    pub norm_valid_range: f32,
+}
+
+impl KeySettings {
+    pub const ENCODED_SIZE: usize = 16;
+
+    /// four little-endian f32s, in field order
+    pub fn encode(&self) -> [u8; Self::ENCODED_SIZE] {
+        let mut bytes = [0u8; Self::ENCODED_SIZE];
+        let fields = [self.switch_point, self.hysteresis_fraction, self.filter_alpha, self.norm_valid_range];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// None if any of the values is out of range, which is also how blank flash (all NaN) comes out
+    pub fn decode(bytes: &[u8; Self::ENCODED_SIZE]) -> Option<KeySettings> {
+        let mut fields = [0f32; 4];
+        for (field, chunk) in fields.iter_mut().zip(bytes.chunks_exact(4)) {
+            *field = f32::from_le_bytes(chunk.try_into().ok()?);
+        }
+        let [switch_point, hysteresis_fraction, filter_alpha, norm_valid_range] = fields;
+        let settings = KeySettings { switch_point, hysteresis_fraction, filter_alpha, norm_valid_range };
+        settings.is_valid().then_some(settings)
+    }
 
+    pub fn is_valid(&self) -> bool {
+        (0. ..=1.).contains(&self.switch_point)
+            && (0. ..=0.5).contains(&self.hysteresis_fraction)
+            && (0. ..=1.).contains(&self.filter_alpha) && self.filter_alpha > 0.
+            && (0. ..=4096.).contains(&self.norm_valid_range)
+    }
+}
+
+
+/// how far down a key has to be before its press starts being timed, for MIDI velocity
+const TRAVEL_START_DEPTH: f32 = 0.1;
+
+/// the size of the key change bus, which the `AnalogKey`s publish their `KeySignal`s on
+pub const KEYCHANGE_BUS_CAP: usize = 32;
+pub const KEYCHANGE_BUS_SUBS: usize = 5;
 
 #[derive(Debug, Clone, Copy)]
 pub struct KeySignal {
@@ -34,25 +198,34 @@ pub struct AnalogKey<M:RawMutex + 'static>
     pub max_value: Option<f32>,
     pub min_value: Option<f32>,
     switch_threshold: f32,
+    /// the normalized value at which the key switches, before hysteresis
+    switch_point: f32,
     pub switch_hysteresis_fraction: f32,
     pub high_is_on: bool,
     pub norm_valid_range: f32,
+    /// when the key went down past `TRAVEL_START_DEPTH`, if it is on its way to switching on
+    travel_started: Option<Instant>,
+    /// how long the last press took from `TRAVEL_START_DEPTH` to switching on
+    travel_time: Option<Duration>,
     pub toggle_publisher: Option<Publisher<'static, M, KeySignal, KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, N_KEYS>>,
 } 
 
 impl<M: RawMutex> AnalogKey<M> {
     pub fn new(keynumber: u8, toggle_publisher: Option<Publisher<'static, M, KeySignal, KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, N_KEYS>>) -> Self {
         AnalogKey {
-            keynumber: keynumber,
+            keynumber,
             value: None,
             filter_alpha: 0.05,
             max_value: None,
             min_value: None,
             switch_threshold: 0.5,
+            switch_point: 0.5,
             switch_hysteresis_fraction: 0.1,
             high_is_on: false,
             norm_valid_range: 100.,
-            toggle_publisher: toggle_publisher,
+            travel_started: None,
+            travel_time: None,
+            toggle_publisher,
         }
     }
     pub fn update_value_adc(&mut self, new_adc_value: i16) {
@@ -80,58 +253,114 @@ impl<M: RawMutex> AnalogKey<M> {
 
             if oldval < self.switch_threshold && newval >= self.switch_threshold {
                 // crossed threshold upwards
-                self.switch_threshold = 0.5 - self.switch_hysteresis_fraction;
+                self.switch_threshold = self.switch_point - self.switch_hysteresis_fraction;
             } else if oldval >= self.switch_threshold && newval < self.switch_threshold {
                 // crossed threshold downwards
-                self.switch_threshold = 0.5 + self.switch_hysteresis_fraction;
+                self.switch_threshold = self.switch_point + self.switch_hysteresis_fraction;
             }
         } else {
             self.value = Some(new_adc_value as f32);
         }
 
         let newon = self.is_on();
+        self.time_travel(newon == Some(true));
         match (oldon, newon) {
             (Some(old), Some(new)) if old != new => {
+                if new {
+                    // None if it started out past the start depth, so there's no telling how fast
+                    self.travel_time = self.travel_started.take().map(|started| Instant::now() - started);
+                }
                 self.toggled(new);
             }
             _ => {}
         }
     }
 
+    /// Starts timing a press once the key goes down past `TRAVEL_START_DEPTH`, and stops if it comes
+    /// back up without switching on.  Samples come in a scan at a time, so the timing is only as fine
+    /// as the scan period.
+    fn time_travel(&mut self, on: bool) {
+        let Some(depth) = self.depth() else { return };
+        if depth < TRAVEL_START_DEPTH {
+            self.travel_started = None;
+        } else if !on && self.travel_started.is_none() {
+            self.travel_started = Some(Instant::now());
+        }
+    }
+
+    /// how long the last press took to go down, which is how hard the key was struck
+    pub fn press_travel_time(&self) -> Option<Duration> {
+        self.travel_time
+    }
+
+    pub fn switch_point(&self) -> f32 {
+        self.switch_point
+    }
+
+    /// moves the switch point, keeping the hysteresis the key currently has
+    pub fn set_switch_point(&mut self, switch_point: f32) {
+        self.switch_threshold += switch_point - self.switch_point;
+        self.switch_point = switch_point;
+    }
+
+    pub fn settings(&self) -> KeySettings {
+        KeySettings {
+            switch_point: self.switch_point,
+            hysteresis_fraction: self.switch_hysteresis_fraction,
+            filter_alpha: self.filter_alpha,
+            norm_valid_range: self.norm_valid_range,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: KeySettings) {
+        self.set_switch_point(settings.switch_point);
+        self.switch_hysteresis_fraction = settings.hysteresis_fraction;
+        self.filter_alpha = settings.filter_alpha;
+        self.norm_valid_range = settings.norm_valid_range;
+    }
+
+    /// forgets the min and max seen so far, so the key recalibrates from the next readings
+    pub fn reset_calibration(&mut self) {
+        self.min_value = None;
+        self.max_value = None;
+    }
+
     fn toggled(&self, to_on: bool) {
-        match &self.toggle_publisher {
-            Some(publisher) => {
-                let signal = KeySignal {
-                    toggle_on: to_on,
-                    keynumber: self.keynumber,
-                };
-                // we use try_publish to avoid blocking here, since that could cause missed ADC readings
-                match publisher.try_publish(signal) {
-                    Ok(()) => {}
-                    Err(_sig) => {
-                        // right now this probably means the USB isn't on.  Should check for that and not worry if it's disconnected
-                        defmt::warn!("Failed to publish key toggle signal for key {}", self.keynumber);
-                    }
+        if let Some(publisher) = &self.toggle_publisher {
+            let signal = KeySignal {
+                toggle_on: to_on,
+                keynumber: self.keynumber,
+            };
+            // we use try_publish to avoid blocking here, since that could cause missed ADC readings
+            match publisher.try_publish(signal) {
+                Ok(()) => {}
+                Err(_sig) => {
+                    // right now this probably means the USB isn't on.  Should check for that and not worry if it's disconnected.
+                    // The key processor resyncs from `is_on`, so a dropped change doesn't leave the key stuck.
+                    defmt::warn!("Failed to publish key toggle signal for key {}", self.keynumber);
                 }
             }
-            None => {}
         }
     }
 
     pub fn normalized_value(&self) -> Option<f32> {
-        if self.value.is_none() {
-            return None;
-        }
+        let value = self.value?;
 
         match (self.min_value, self.max_value) {
             (Some(minval), Some(maxval)) if maxval > minval => {
                 if (maxval - minval) < self.norm_valid_range { return None; }
-                return Some((self.value.unwrap() - minval) / (maxval - minval));
+                Some((value - minval) / (maxval - minval))
             }
-            _ => { return None; }
+            _ => None,
         }
     }
 
+    /// how far the key is pressed, from 0 (up) to 1 (all the way down)
+    pub fn depth(&self) -> Option<f32> {
+        let normval = self.normalized_value()?;
+        Some(if self.high_is_on { normval } else { 1. - normval })
+    }
+
     pub fn is_on(&self) -> Option<bool> {
         let normval = self.normalized_value()?;
         let high = normval >= self.switch_threshold;
@@ -148,63 +377,216 @@ impl<M: RawMutex> Default for AnalogKey<M> {
     fn default() -> Self { AnalogKey::<M>::new(0, None) }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct MuxSpec {
-    // the Mux configuration for a specific key
-    pub a: Level,
-    pub b: Level,
+const N_KEYMAP: usize = N_ALL_KEYS * N_LAYERS;
+const N_KEYMAP_POWEROF2: usize = N_KEYMAP.next_power_of_two();
+pub type Keymap = FnvIndexMap<(u8, Layer), Action, N_KEYMAP_POWEROF2>;
+
+/// The live keymap, which starts out as `default_keymap` and can be changed at runtime (and saved to
+/// flash - see `storage`).
+pub static KEYMAP: LazyLock<Mutex<CriticalSectionRawMutex, RefCell<Keymap>>> = LazyLock::new(|| {
+    Mutex::new(RefCell::new(default_keymap()))
+});
+
+pub fn keymap_get(keynumber: u8, layer: Layer) -> Option<Action> {
+    KEYMAP.get().lock(|keymap| keymap.borrow().get(&(keynumber, layer)).copied())
 }
 
-impl MuxSpec {
-    pub fn iterator() -> core::slice::Iter<'static, MuxSpec> {
-        static ALLSPECS: [MuxSpec; 4] = [MuxSpec { a: Level::Low, b: Level::Low }, 
-                                         MuxSpec { a: Level::High, b: Level::Low }, 
-                                         MuxSpec { a: Level::Low, b: Level::High }, 
-                                         MuxSpec { a: Level::High, b: Level::High }];
-        ALLSPECS.iter()
-    }
-    pub fn index(&self) -> u8 {
-        match (self.a, self.b) {
-            (Level::Low, Level::Low) => 0,
-            (Level::High, Level::Low) => 1,
-            (Level::Low, Level::High) => 2,
-            (Level::High, Level::High) => 3,
+/// Maps the key on `layer` to `action`, or unmaps it if None.
+pub fn keymap_set(keynumber: u8, layer: Layer, action: Option<Action>) {
+    KEYMAP.get().lock(|keymap| {
+        let mut keymap = keymap.borrow_mut();
+        match action {
+            Some(action) => {
+                // there is room for every key on every layer
+                keymap.insert((keynumber, layer), action).ok();
+            }
+            None => {
+                keymap.remove(&(keynumber, layer));
+            }
         }
-    }
+    });
 }
-
-impl Default for MuxSpec {
-    fn default() -> Self { MuxSpec { a: Level::Low, b: Level::Low } }
+/// Puts every key on every layer back to `default_keymap`.
+pub fn keymap_reset() {
+    KEYMAP.get().lock(|keymap| *keymap.borrow_mut() = default_keymap());
 }
 
-
-const N_KEYMAP: usize = N_KEYS * N_LAYERS;
-const N_KEYMAP_POWEROF2: usize = N_KEYMAP.next_power_of_two();
-pub static KEYMAP: LazyLock<FnvIndexMap<(u8, Layer), KeyboardUsage, N_KEYMAP_POWEROF2>> = LazyLock::new(|| {
+// key names are written row-then-column, so the leading zeros are intentional
+#[allow(clippy::zero_prefixed_literal)]
+pub fn default_keymap() -> Keymap {
     let mut m = FnvIndexMap::new();
-    m.insert((00, Layer::Default), KeyboardUsage::KeyboardQq).expect("no space for key!");
-    m.insert((01, Layer::Default), KeyboardUsage::KeyboardWw).expect("no space for key!");
-    m.insert((02, Layer::Default), KeyboardUsage::KeyboardEe).expect("no space for key!");
-    m.insert((03, Layer::Default), KeyboardUsage::KeyboardRr).expect("no space for key!");
-    m.insert((10, Layer::Default), KeyboardUsage::KeyboardTt).expect("no space for key!");
-    m.insert((11, Layer::Default), KeyboardUsage::KeypadTab).expect("no space for key!");
-    m.insert((12, Layer::Default), KeyboardUsage::KeyboardAa).expect("no space for key!");
-    m.insert((13, Layer::Default), KeyboardUsage::KeyboardSs).expect("no space for key!");
-    m.insert((20, Layer::Default), KeyboardUsage::KeyboardDd).expect("no space for key!");
-    m.insert((21, Layer::Default), KeyboardUsage::KeyboardFf).expect("no space for key!");
-    m.insert((22, Layer::Default), KeyboardUsage::KeyboardGg).expect("no space for key!");
-    m.insert((23, Layer::Default), KeyboardUsage::KeypadLeftShift).expect("no space for key!");
-    m.insert((30, Layer::Default), KeyboardUsage::KeyboardZz).expect("no space for key!");
-    m.insert((31, Layer::Default), KeyboardUsage::KeyboardXx).expect("no space for key!");
-    m.insert((32, Layer::Default), KeyboardUsage::KeyboardCc).expect("no space for key!");
-    m.insert((33, Layer::Default), KeyboardUsage::KeyboardVv).expect("no space for key!");
-    m.insert((40, Layer::Default), KeyboardUsage::KeyboardBb).expect("no space for key!");
-    m.insert((41, Layer::Default), KeyboardUsage::KeyboardLeftControl).expect("no space for key!");
-    m.insert((42, Layer::Default), KeyboardUsage::KeyboardLeftAlt).expect("no space for key!");
+    m.insert((00, Layer::Default), Action::Key(KeyboardUsage::KeyboardQq)).expect("no space for key!");
+    m.insert((01, Layer::Default), Action::Key(KeyboardUsage::KeyboardWw)).expect("no space for key!");
+    m.insert((02, Layer::Default), Action::Key(KeyboardUsage::KeyboardEe)).expect("no space for key!");
+    m.insert((03, Layer::Default), Action::Key(KeyboardUsage::KeyboardRr)).expect("no space for key!");
+    m.insert((10, Layer::Default), Action::Key(KeyboardUsage::KeyboardTt)).expect("no space for key!");
+    m.insert((11, Layer::Default), Action::Key(KeyboardUsage::KeypadTab)).expect("no space for key!");
+    m.insert((12, Layer::Default), Action::Key(KeyboardUsage::KeyboardAa)).expect("no space for key!");
+    m.insert((13, Layer::Default), Action::Key(KeyboardUsage::KeyboardSs)).expect("no space for key!");
+    m.insert((20, Layer::Default), Action::Key(KeyboardUsage::KeyboardDd)).expect("no space for key!");
+    m.insert((21, Layer::Default), Action::Key(KeyboardUsage::KeyboardFf)).expect("no space for key!");
+    m.insert((22, Layer::Default), Action::Key(KeyboardUsage::KeyboardGg)).expect("no space for key!");
+    m.insert((23, Layer::Default), Action::Key(KeyboardUsage::KeypadLeftShift)).expect("no space for key!");
+    m.insert((30, Layer::Default), Action::Key(KeyboardUsage::KeyboardZz)).expect("no space for key!");
+    m.insert((31, Layer::Default), Action::Key(KeyboardUsage::KeyboardXx)).expect("no space for key!");
+    m.insert((32, Layer::Default), Action::Key(KeyboardUsage::KeyboardCc)).expect("no space for key!");
+    m.insert((33, Layer::Default), Action::Key(KeyboardUsage::KeyboardVv)).expect("no space for key!");
+    m.insert((40, Layer::Default), Action::Key(KeyboardUsage::KeyboardBb)).expect("no space for key!");
+    m.insert((41, Layer::Default), Action::Key(KeyboardUsage::KeyboardLeftControl)).expect("no space for key!");
+    m.insert((42, Layer::Default), Action::Key(KeyboardUsage::KeyboardLeftAlt)).expect("no space for key!");
     //m.insert((43, Layer::Default), KeyboardUsage::Keyboard1<FIX>).expect("no space for key!");
+    m.insert((43, Layer::Default), Action::DynamicMacroRecord(0)).expect("no space for key!");
     //m.insert((50, Layer::Default), KeyboardUsage::Keyboard2<FIX>).expect("no space for key!");
-    m.insert((51, Layer::Default), KeyboardUsage::KeyboardSpacebar).expect("no space for key!");
+    m.insert((50, Layer::Default), Action::DynamicMacroPlay(0)).expect("no space for key!");
+    m.insert((51, Layer::Default), Action::Key(KeyboardUsage::KeyboardSpacebar)).expect("no space for key!");
     //m.insert((52, Layer::Default), KeyboardUsage::Keyboard3<FIX>).expect("no space for key!");
+    m.insert((52, Layer::Default), Action::LayerMomentary(Layer::Fn)).expect("no space for key!");
+
+    // keys not in the Fn layer fall through to the default layer
+    m.insert((00, Layer::Fn), Action::Key(KeyboardUsage::Keyboard1Exclamation)).expect("no space for key!");
+    m.insert((01, Layer::Fn), Action::Key(KeyboardUsage::Keyboard2At)).expect("no space for key!");
+    m.insert((02, Layer::Fn), Action::Key(KeyboardUsage::Keyboard3Hash)).expect("no space for key!");
+    m.insert((03, Layer::Fn), Action::Key(KeyboardUsage::Keyboard4Dollar)).expect("no space for key!");
+    m.insert((10, Layer::Fn), Action::Key(KeyboardUsage::Keyboard5Percent)).expect("no space for key!");
+    m.insert((12, Layer::Fn), Action::Consumer(ConsumerUsage::VolumeDecrement)).expect("no space for key!");
+    m.insert((13, Layer::Fn), Action::Consumer(ConsumerUsage::VolumeIncrement)).expect("no space for key!");
+    m.insert((20, Layer::Fn), Action::Consumer(ConsumerUsage::Mute)).expect("no space for key!");
+    m.insert((21, Layer::Fn), Action::Consumer(ConsumerUsage::PlayPause)).expect("no space for key!");
+    m.insert((30, Layer::Fn), Action::Consumer(ConsumerUsage::BrightnessDecrement)).expect("no space for key!");
+    m.insert((31, Layer::Fn), Action::Consumer(ConsumerUsage::BrightnessIncrement)).expect("no space for key!");
+    m.insert((40, Layer::Fn), Action::System(SystemUsage::Sleep)).expect("no space for key!");
+    m.insert((11, Layer::Fn), Action::LayerToggle(Layer::Mouse)).expect("no space for key!");
+    m.insert((43, Layer::Fn), Action::Macro(0)).expect("no space for key!");
+
+    // mouse keys on the home row, ESDF for movement
+    m.insert((02, Layer::Mouse), Action::MouseMove(MouseDirection::Up)).expect("no space for key!");
+    m.insert((13, Layer::Mouse), Action::MouseMove(MouseDirection::Left)).expect("no space for key!");
+    m.insert((20, Layer::Mouse), Action::MouseMove(MouseDirection::Down)).expect("no space for key!");
+    m.insert((21, Layer::Mouse), Action::MouseMove(MouseDirection::Right)).expect("no space for key!");
+    m.insert((12, Layer::Mouse), Action::MouseButton(0)).expect("no space for key!");
+    m.insert((22, Layer::Mouse), Action::MouseButton(1)).expect("no space for key!");
+    m.insert((03, Layer::Mouse), Action::MouseWheel(MouseDirection::Up)).expect("no space for key!");
+    m.insert((10, Layer::Mouse), Action::MouseWheel(MouseDirection::Down)).expect("no space for key!");
+    m.insert((11, Layer::Mouse), Action::LayerToggle(Layer::Mouse)).expect("no space for key!");
+
+    // the other half of a split keyboard, numbered from `REMOTE_KEY_OFFSET` - see `split`
+    if SPLIT_CENTRAL {
+        m.insert((60, Layer::Default), Action::Key(KeyboardUsage::KeyboardYy)).expect("no space for key!");
+        m.insert((61, Layer::Default), Action::Key(KeyboardUsage::KeyboardUu)).expect("no space for key!");
+        m.insert((62, Layer::Default), Action::Key(KeyboardUsage::KeyboardIi)).expect("no space for key!");
+        m.insert((63, Layer::Default), Action::Key(KeyboardUsage::KeyboardOo)).expect("no space for key!");
+        m.insert((70, Layer::Default), Action::Key(KeyboardUsage::KeyboardPp)).expect("no space for key!");
+        m.insert((71, Layer::Default), Action::Key(KeyboardUsage::KeyboardBackspace)).expect("no space for key!");
+        m.insert((72, Layer::Default), Action::Key(KeyboardUsage::KeyboardHh)).expect("no space for key!");
+        m.insert((73, Layer::Default), Action::Key(KeyboardUsage::KeyboardJj)).expect("no space for key!");
+        m.insert((80, Layer::Default), Action::Key(KeyboardUsage::KeyboardKk)).expect("no space for key!");
+        m.insert((81, Layer::Default), Action::Key(KeyboardUsage::KeyboardLl)).expect("no space for key!");
+        m.insert((82, Layer::Default), Action::Key(KeyboardUsage::KeyboardSemiColon)).expect("no space for key!");
+        m.insert((83, Layer::Default), Action::Key(KeyboardUsage::KeyboardSingleDoubleQuote)).expect("no space for key!");
+        m.insert((90, Layer::Default), Action::Key(KeyboardUsage::KeyboardNn)).expect("no space for key!");
+        m.insert((91, Layer::Default), Action::Key(KeyboardUsage::KeyboardMm)).expect("no space for key!");
+        m.insert((92, Layer::Default), Action::Key(KeyboardUsage::KeyboardCommaLess)).expect("no space for key!");
+        m.insert((93, Layer::Default), Action::Key(KeyboardUsage::KeyboardPeriodGreater)).expect("no space for key!");
+        m.insert((100, Layer::Default), Action::Key(KeyboardUsage::KeyboardSlashQuestion)).expect("no space for key!");
+        m.insert((101, Layer::Default), Action::Key(KeyboardUsage::KeyboardRightShift)).expect("no space for key!");
+        m.insert((102, Layer::Default), Action::Key(KeyboardUsage::KeyboardEnter)).expect("no space for key!");
+        m.insert((103, Layer::Default), Action::LayerMomentary(Layer::Fn)).expect("no space for key!");
+        m.insert((111, Layer::Default), Action::Key(KeyboardUsage::KeyboardRightAlt)).expect("no space for key!");
+        m.insert((112, Layer::Default), Action::Key(KeyboardUsage::KeyboardRightControl)).expect("no space for key!");
+
+        m.insert((60, Layer::Fn), Action::Key(KeyboardUsage::Keyboard6Caret)).expect("no space for key!");
+        m.insert((61, Layer::Fn), Action::Key(KeyboardUsage::Keyboard7Ampersand)).expect("no space for key!");
+        m.insert((62, Layer::Fn), Action::Key(KeyboardUsage::Keyboard8Asterisk)).expect("no space for key!");
+        m.insert((63, Layer::Fn), Action::Key(KeyboardUsage::Keyboard9OpenParens)).expect("no space for key!");
+        m.insert((70, Layer::Fn), Action::Key(KeyboardUsage::Keyboard0CloseParens)).expect("no space for key!");
+        m.insert((72, Layer::Fn), Action::Key(KeyboardUsage::KeyboardLeftArrow)).expect("no space for key!");
+        m.insert((73, Layer::Fn), Action::Key(KeyboardUsage::KeyboardDownArrow)).expect("no space for key!");
+        m.insert((80, Layer::Fn), Action::Key(KeyboardUsage::KeyboardUpArrow)).expect("no space for key!");
+        m.insert((81, Layer::Fn), Action::Key(KeyboardUsage::KeyboardRightArrow)).expect("no space for key!");
+    }
+
+    // without the USB MIDI interface the notes would go nowhere, so only map them if it's there
+    if cfg!(feature = "midi") {
+        m.insert((41, Layer::Fn), Action::LayerToggle(Layer::Midi)).expect("no space for key!");
+        // every other key plays a note, chromatically up from middle C in key number order
+        let note_keys = KEY_NAMES.iter().filter(|keynumber| **keynumber != 41);
+        for (note, keynumber) in (MIDI_FIRST_NOTE..).zip(note_keys) {
+            m.insert((*keynumber, Layer::Midi), Action::MidiNote(note)).expect("no space for key!");
+        }
+        m.insert((41, Layer::Midi), Action::LayerToggle(Layer::Midi)).expect("no space for key!");
+    }
 
     m
-});
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// one of every kind of action, at the ends of their parameter ranges where they have one
+    const EVERY_ACTION: [Action; 21] = [
+        Action::Key(KeyboardUsage::KeyboardAa),
+        Action::Key(KeyboardUsage::KeyboardRightGUI),
+        Action::Consumer(ConsumerUsage::BrightnessIncrement),
+        Action::Consumer(ConsumerUsage::BrowserRefresh),
+        Action::System(SystemUsage::Sleep),
+        Action::MouseMove(MouseDirection::Up),
+        Action::MouseWheel(MouseDirection::Right),
+        Action::MouseButton(0),
+        Action::MouseButton(MOUSE_BUTTONS - 1),
+        Action::Macro(7),
+        Action::DynamicMacroRecord(1),
+        Action::DynamicMacroStop,
+        Action::DynamicMacroPlay(0),
+        Action::TapDance(3),
+        Action::Leader,
+        Action::LayerMomentary(Layer::Fn),
+        Action::LayerToggle(Layer::Midi),
+        Action::CapsWord,
+        Action::OneShot(KeyboardUsage::KeyboardLeftShift),
+        Action::MidiNote(0),
+        Action::MidiNote(0x7F),
+    ];
+
+    #[test]
+    fn actions_round_trip() {
+        for action in EVERY_ACTION {
+            assert_eq!(Action::decode(action.encode()), Some(action), "{:?}", action.encode());
+        }
+    }
+
+    #[test]
+    fn every_kind_is_covered() {
+        for kind in 1..=17 {
+            assert!(EVERY_ACTION.iter().any(|action| action.encode()[0] == kind), "no action of kind {kind}");
+        }
+    }
+
+    #[test]
+    fn invalid_actions_decode_as_none() {
+        let invalid = [
+            NO_ACTION_ENCODED,
+            [18, 0, 0],
+            [0xFF, 0, 0],
+            // a byte parameter with a high byte
+            [1, 4, 1],
+            [2, 0x71, 0],
+            [3, 0x84, 0],
+            [4, 4, 0],
+            [6, MOUSE_BUTTONS, 0],
+            [6, 8, 0],
+            [13, N_LAYERS as u8, 0],
+            [17, 0x80, 0],
+        ];
+        for bytes in invalid {
+            assert_eq!(Action::decode(bytes), None, "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn key_settings_round_trip() {
+        let settings = KeySettings { switch_point: 0.4, hysteresis_fraction: 0.1, filter_alpha: 0.05, norm_valid_range: 800. };
+        assert_eq!(KeySettings::decode(&settings.encode()), Some(settings));
+        assert_eq!(KeySettings::decode(&[0xFF; KeySettings::ENCODED_SIZE]), None);
+    }
+}
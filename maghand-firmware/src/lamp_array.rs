@@ -0,0 +1,505 @@
+//! The key LEDs as a HID LampArray (the Lighting and Illumination usage page), which is what Windows
+//! Dynamic Lighting and other host lighting software drive.
+//!
+//! The LampArray is a set of feature reports on the keyboard interface, so it needs no endpoint of
+//! its own.  There is one lamp per key, with lamp n being `KEY_NAMES[n]`, placed from
+//! `KEY_POSITIONS`.  The host reads the array and lamp attributes, then either leaves the LEDs to
+//! the firmware (autonomous mode, which is how it starts) or takes over and sets their colors.
+//! Color updates only show once one arrives with the update complete flag, so a host can change
+//! many lamps at once.  Intensity isn't supported, so hosts fold it into the colors.
+//!
+//! Nothing here touches hardware: the USB request handler passes reports to `LAMP_ARRAY`, and the
+//! main loop shows `colors` when `KEY_COLORS_CHANGED` is signalled.
+//!
+//! ```text
+//! feature reports (report ID, then little-endian fields):
+//!   5 array attributes  get  lamp count u16, bounding box width, height, depth (µm), kind, min update interval (µs) u32
+//!   6 attributes request set  lamp id u16 - the lamp the next attributes response is for
+//!   7 attributes response get  lamp id u16, x, y, z (µm), update latency (µs), purposes u32,
+//!                             red, green, blue, intensity level counts, programmable, input binding u8
+//!                             - then moves on to the next lamp
+//!   8 multi update       set  count u8, flags u8, 8 lamp ids u16, 8 × red, green, blue, intensity u8
+//!   9 range update       set  flags u8, first lamp id u16, last lamp id u16, red, green, blue, intensity u8
+//!  10 control            set  autonomous mode u8
+//! ```
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use smart_leds::RGB8;
+
+use crate::hardware_consts::{KEY_PITCH, KEY_POSITIONS, N_KEYS};
+
+/// after the keyboard interface's input report IDs
+pub const ATTRIBUTES_REPORT_ID: u8 = 5;
+pub const ATTRIBUTES_REQUEST_REPORT_ID: u8 = 6;
+pub const ATTRIBUTES_RESPONSE_REPORT_ID: u8 = 7;
+pub const MULTI_UPDATE_REPORT_ID: u8 = 8;
+pub const RANGE_UPDATE_REPORT_ID: u8 = 9;
+pub const CONTROL_REPORT_ID: u8 = 10;
+
+pub const ATTRIBUTES_REPORT_SIZE: usize = 23;
+pub const ATTRIBUTES_RESPONSE_REPORT_SIZE: usize = 29;
+/// how many lamps a multi update can set
+const MULTI_UPDATE_LAMPS: usize = 8;
+
+const LAMP_ARRAY_KIND_KEYBOARD: u32 = 1;
+const LAMP_PURPOSE_CONTROL: u32 = 1;
+const LAMP_UPDATE_COMPLETE: u8 = 1;
+/// the main loop rewrites the LEDs as soon as it hears of a change
+const MIN_UPDATE_INTERVAL_US: u32 = 10_000;
+const UPDATE_LATENCY_US: u32 = 1_000;
+/// how tall the keys stand, for the bounding box
+const KEY_HEIGHT: f32 = 15.0;
+const COLOR_LEVELS: u8 = 255;
+
+pub const LAMP_ARRAY_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x59,        // Usage Page (Lighting and Illumination)
+    0x09, 0x01,        // Usage (LampArray)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, ATTRIBUTES_REPORT_ID, // Report ID
+    0x09, 0x02,        //   Usage (LampArrayAttributesReport)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x03,        //     Usage (LampCount)
+    0x15, 0x00,        //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, // Logical Maximum (65535)
+    0x75, 0x10,        //     Report Size (16)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x03,        //     Feature (Constant, Variable, Absolute)
+    0x09, 0x04,        //     Usage (BoundingBoxWidthInMicrometers)
+    0x09, 0x05,        //     Usage (BoundingBoxHeightInMicrometers)
+    0x09, 0x06,        //     Usage (BoundingBoxDepthInMicrometers)
+    0x09, 0x07,        //     Usage (LampArrayKind)
+    0x09, 0x08,        //     Usage (MinUpdateIntervalInMicroseconds)
+    0x27, 0xFF, 0xFF, 0xFF, 0x7F, // Logical Maximum (2^31 - 1)
+    0x75, 0x20,        //     Report Size (32)
+    0x95, 0x05,        //     Report Count (5)
+    0xB1, 0x03,        //     Feature (Constant, Variable, Absolute)
+    0xC0,              //   End Collection
+    0x85, ATTRIBUTES_REQUEST_REPORT_ID, // Report ID
+    0x09, 0x20,        //   Usage (LampAttributesRequestReport)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x21,        //     Usage (LampId)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, // Logical Maximum (65535)
+    0x75, 0x10,        //     Report Size (16)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0xC0,              //   End Collection
+    0x85, ATTRIBUTES_RESPONSE_REPORT_ID, // Report ID
+    0x09, 0x22,        //   Usage (LampAttributesResponseReport)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x21,        //     Usage (LampId)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, // Logical Maximum (65535)
+    0x75, 0x10,        //     Report Size (16)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0x09, 0x23,        //     Usage (PositionXInMicrometers)
+    0x09, 0x24,        //     Usage (PositionYInMicrometers)
+    0x09, 0x25,        //     Usage (PositionZInMicrometers)
+    0x09, 0x27,        //     Usage (UpdateLatencyInMicroseconds)
+    0x09, 0x26,        //     Usage (LampPurposes)
+    0x27, 0xFF, 0xFF, 0xFF, 0x7F, // Logical Maximum (2^31 - 1)
+    0x75, 0x20,        //     Report Size (32)
+    0x95, 0x05,        //     Report Count (5)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0x09, 0x28,        //     Usage (RedLevelCount)
+    0x09, 0x29,        //     Usage (GreenLevelCount)
+    0x09, 0x2A,        //     Usage (BlueLevelCount)
+    0x09, 0x2B,        //     Usage (IntensityLevelCount)
+    0x09, 0x2C,        //     Usage (IsProgrammable)
+    0x09, 0x2D,        //     Usage (InputBinding)
+    0x26, 0xFF, 0x00,  //     Logical Maximum (255)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x06,        //     Report Count (6)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0xC0,              //   End Collection
+    0x85, MULTI_UPDATE_REPORT_ID, // Report ID
+    0x09, 0x50,        //   Usage (LampMultiUpdateReport)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x03,        //     Usage (LampCount)
+    0x09, 0x55,        //     Usage (LampUpdateFlags)
+    0x25, MULTI_UPDATE_LAMPS as u8, // Logical Maximum
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x02,        //     Report Count (2)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0x09, 0x21, 0x09, 0x21, 0x09, 0x21, 0x09, 0x21, // Usage (LampId) × 8
+    0x09, 0x21, 0x09, 0x21, 0x09, 0x21, 0x09, 0x21,
+    0x27, 0xFF, 0xFF, 0x00, 0x00, // Logical Maximum (65535)
+    0x75, 0x10,        //     Report Size (16)
+    0x95, MULTI_UPDATE_LAMPS as u8, // Report Count
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54, // Usage (Red/Green/Blue/IntensityUpdateChannel) × 8
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54,
+    0x26, 0xFF, 0x00,  //     Logical Maximum (255)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 4 * MULTI_UPDATE_LAMPS as u8, // Report Count
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0xC0,              //   End Collection
+    0x85, RANGE_UPDATE_REPORT_ID, // Report ID
+    0x09, 0x60,        //   Usage (LampRangeUpdateReport)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x55,        //     Usage (LampUpdateFlags)
+    0x25, 0x08,        //     Logical Maximum (8)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0x09, 0x61,        //     Usage (LampIdStart)
+    0x09, 0x62,        //     Usage (LampIdEnd)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, // Logical Maximum (65535)
+    0x75, 0x10,        //     Report Size (16)
+    0x95, 0x02,        //     Report Count (2)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0x09, 0x51, 0x09, 0x52, 0x09, 0x53, 0x09, 0x54, // Usage (Red/Green/Blue/IntensityUpdateChannel)
+    0x26, 0xFF, 0x00,  //     Logical Maximum (255)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x04,        //     Report Count (4)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0xC0,              //   End Collection
+    0x85, CONTROL_REPORT_ID, // Report ID
+    0x09, 0x70,        //   Usage (LampArrayControlReport)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x71,        //     Usage (AutonomousMode)
+    0x25, 0x01,        //     Logical Maximum (1)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x02,        //     Feature (Data, Variable, Absolute)
+    0xC0,              //   End Collection
+    0xC0,              // End Collection
+];
+
+pub static LAMP_ARRAY: Mutex<CriticalSectionRawMutex, RefCell<LampArray>> = Mutex::new(RefCell::new(LampArray::new()));
+/// signalled when the LEDs should be redrawn - `colors` says with what
+pub static KEY_COLORS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LampArrayError {
+    UnknownReport,
+    /// too short, or a lamp id out of range
+    BadReport,
+}
+
+/// The LampArray state the host sees and sets.
+#[derive(Debug)]
+pub struct LampArray {
+    /// the lamp the next attributes response is for
+    attributes_lamp: u16,
+    autonomous: bool,
+    /// colors the host has set but not finished updating yet
+    pending: [RGB8; N_KEYS],
+    colors: [RGB8; N_KEYS],
+}
+
+/// the bounding box of the lamp positions, (min x, max y, width, height) in mm - y goes up in
+/// `KEY_POSITIONS` but down in a LampArray, so the top edge is the origin
+fn bounds() -> (f32, f32, f32, f32) {
+    let (mut min_x, mut max_x, mut min_y, mut max_y) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+    for (x, y) in KEY_POSITIONS {
+        min_x = min_x.min(x);
+        max_x = max_x.max(x);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+    }
+    (min_x - KEY_PITCH / 2., max_y + KEY_PITCH / 2., max_x - min_x + KEY_PITCH, max_y - min_y + KEY_PITCH)
+}
+
+fn micrometers(mm: f32) -> u32 {
+    (mm * 1000.) as u32
+}
+
+fn lamp_index(bytes: &[u8]) -> Result<usize, LampArrayError> {
+    let id = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    if id < N_KEYS { Ok(id) } else { Err(LampArrayError::BadReport) }
+}
+
+impl LampArray {
+    pub const fn new() -> Self {
+        LampArray {
+            attributes_lamp: 0,
+            autonomous: true,
+            pending: [RGB8 { r: 0, g: 0, b: 0 }; N_KEYS],
+            colors: [RGB8 { r: 0, g: 0, b: 0 }; N_KEYS],
+        }
+    }
+
+    /// the colors the host has set, or None if the firmware is in charge of the LEDs
+    pub fn colors(&self) -> Option<[RGB8; N_KEYS]> {
+        (!self.autonomous).then_some(self.colors)
+    }
+
+    pub fn attributes_report(&self) -> [u8; ATTRIBUTES_REPORT_SIZE] {
+        let (_, _, width, height) = bounds();
+        let mut report = [0u8; ATTRIBUTES_REPORT_SIZE];
+        report[0] = ATTRIBUTES_REPORT_ID;
+        report[1..3].copy_from_slice(&(N_KEYS as u16).to_le_bytes());
+        let fields = [micrometers(width), micrometers(height), micrometers(KEY_HEIGHT),
+                      LAMP_ARRAY_KIND_KEYBOARD, MIN_UPDATE_INTERVAL_US];
+        for (chunk, field) in report[3..].chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        report
+    }
+
+    /// The attributes of the requested lamp, after which the next lamp's come up.  `input_binding`
+    /// gives the keyboard usage of a key, if it types one.
+    pub fn attributes_response_report(&mut self, input_binding: impl Fn(usize) -> Option<u8>) -> [u8; ATTRIBUTES_RESPONSE_REPORT_SIZE] {
+        let lamp = self.attributes_lamp as usize;
+        self.attributes_lamp = ((lamp + 1) % N_KEYS) as u16;
+
+        let (left, top, _, _) = bounds();
+        let (x, y) = KEY_POSITIONS[lamp];
+        let mut report = [0u8; ATTRIBUTES_RESPONSE_REPORT_SIZE];
+        report[0] = ATTRIBUTES_RESPONSE_REPORT_ID;
+        report[1..3].copy_from_slice(&(lamp as u16).to_le_bytes());
+        let fields = [micrometers(x - left), micrometers(top - y), micrometers(KEY_HEIGHT), UPDATE_LATENCY_US, LAMP_PURPOSE_CONTROL];
+        for (chunk, field) in report[3..23].chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        // one intensity level, since intensity isn't supported
+        report[23..].copy_from_slice(&[COLOR_LEVELS, COLOR_LEVELS, COLOR_LEVELS, 1, 1, input_binding(lamp).unwrap_or(0)]);
+        report
+    }
+
+    /// Answers a GET_REPORT for a feature report into `buf`, returning its length.
+    pub fn get_report(&mut self, id: u8, buf: &mut [u8], input_binding: impl Fn(usize) -> Option<u8>) -> Option<usize> {
+        let report = match id {
+            ATTRIBUTES_REPORT_ID => &self.attributes_report()[..],
+            ATTRIBUTES_RESPONSE_REPORT_ID => &self.attributes_response_report(input_binding)[..],
+            _ => return None,
+        };
+        let buf = buf.get_mut(..report.len())?;
+        buf.copy_from_slice(report);
+        Some(report.len())
+    }
+
+    /// Takes a SET_REPORT for a feature report, report ID first.  Returns whether the LEDs need
+    /// redrawing.
+    pub fn set_report(&mut self, id: u8, data: &[u8]) -> Result<bool, LampArrayError> {
+        let data = match data.split_first() {
+            Some((&report_id, data)) if report_id == id => data,
+            _ => return Err(LampArrayError::BadReport),
+        };
+        let flags = match id {
+            ATTRIBUTES_REQUEST_REPORT_ID => {
+                let data = data.get(..2).ok_or(LampArrayError::BadReport)?;
+                self.attributes_lamp = lamp_index(data)? as u16;
+                return Ok(false);
+            }
+            MULTI_UPDATE_REPORT_ID => {
+                let data = data.get(..2 + 6 * MULTI_UPDATE_LAMPS).ok_or(LampArrayError::BadReport)?;
+                let (count, flags) = (data[0] as usize, data[1]);
+                if count > MULTI_UPDATE_LAMPS {
+                    return Err(LampArrayError::BadReport);
+                }
+                let (ids, channels) = data[2..].split_at(2 * MULTI_UPDATE_LAMPS);
+                for (id, rgbi) in ids.chunks_exact(2).zip(channels.chunks_exact(4)).take(count) {
+                    self.pending[lamp_index(id)?] = RGB8::new(rgbi[0], rgbi[1], rgbi[2]);
+                }
+                flags
+            }
+            RANGE_UPDATE_REPORT_ID => {
+                let data = data.get(..9).ok_or(LampArrayError::BadReport)?;
+                let (first, last) = (lamp_index(&data[1..3])?, lamp_index(&data[3..5])?);
+                if first > last {
+                    return Err(LampArrayError::BadReport);
+                }
+                self.pending[first..=last].fill(RGB8::new(data[5], data[6], data[7]));
+                data[0]
+            }
+            CONTROL_REPORT_ID => {
+                let autonomous = *data.first().ok_or(LampArrayError::BadReport)? != 0;
+                let changed = autonomous != self.autonomous;
+                self.autonomous = autonomous;
+                defmt::info!("key LEDs now under {} control", if autonomous { "firmware" } else { "host" });
+                return Ok(changed);
+            }
+            _ => return Err(LampArrayError::UnknownReport),
+        };
+        if flags & LAMP_UPDATE_COMPLETE == 0 {
+            return Ok(false);
+        }
+        self.colors = self.pending;
+        Ok(!self.autonomous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+    const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+    fn range_update(flags: u8, first: u16, last: u16, color: RGB8) -> std::vec::Vec<u8> {
+        let mut report = std::vec![RANGE_UPDATE_REPORT_ID, flags];
+        report.extend(first.to_le_bytes());
+        report.extend(last.to_le_bytes());
+        report.extend([color.r, color.g, color.b, 1]);
+        report
+    }
+
+    fn multi_update(count: u8, flags: u8, lamps: &[(u16, RGB8)]) -> std::vec::Vec<u8> {
+        let mut ids = [0u8; 2 * MULTI_UPDATE_LAMPS];
+        let mut channels = [0u8; 4 * MULTI_UPDATE_LAMPS];
+        for (i, (id, color)) in lamps.iter().enumerate() {
+            ids[2 * i..2 * i + 2].copy_from_slice(&id.to_le_bytes());
+            channels[4 * i..4 * i + 4].copy_from_slice(&[color.r, color.g, color.b, 1]);
+        }
+        let mut report = std::vec![MULTI_UPDATE_REPORT_ID, count, flags];
+        report.extend(ids);
+        report.extend(channels);
+        report
+    }
+
+    /// a LampArray the host has taken control of
+    fn host_controlled() -> LampArray {
+        let mut lamps = LampArray::new();
+        assert_eq!(lamps.set_report(CONTROL_REPORT_ID, &[CONTROL_REPORT_ID, 0]), Ok(true));
+        lamps
+    }
+
+    fn u32_at(report: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(report[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn array_attributes_layout() {
+        let report = LampArray::new().attributes_report();
+        assert_eq!(report.len(), 23);
+        assert_eq!(report[0], ATTRIBUTES_REPORT_ID);
+        assert_eq!(u16::from_le_bytes([report[1], report[2]]), N_KEYS as u16);
+        let (_, _, width, height) = bounds();
+        assert_eq!(u32_at(&report, 3), micrometers(width));
+        assert_eq!(u32_at(&report, 7), micrometers(height));
+        assert_eq!(u32_at(&report, 11), micrometers(KEY_HEIGHT));
+        assert_eq!(u32_at(&report, 15), LAMP_ARRAY_KIND_KEYBOARD);
+        assert_eq!(u32_at(&report, 19), MIN_UPDATE_INTERVAL_US);
+    }
+
+    #[test]
+    fn lamp_attributes_layout() {
+        let mut lamps = LampArray::new();
+        let last = (N_KEYS - 1) as u16;
+        assert_eq!(lamps.set_report(ATTRIBUTES_REQUEST_REPORT_ID, &[ATTRIBUTES_REQUEST_REPORT_ID, last as u8, (last >> 8) as u8]), Ok(false));
+        let report = lamps.attributes_response_report(|lamp| (lamp == N_KEYS - 1).then_some(0x04));
+        assert_eq!(report.len(), 29);
+        assert_eq!(report[0], ATTRIBUTES_RESPONSE_REPORT_ID);
+        assert_eq!(u16::from_le_bytes([report[1], report[2]]), last);
+        let (left, top, _, _) = bounds();
+        let (x, y) = KEY_POSITIONS[N_KEYS - 1];
+        assert_eq!(u32_at(&report, 3), micrometers(x - left));
+        assert_eq!(u32_at(&report, 7), micrometers(top - y));
+        assert_eq!(u32_at(&report, 11), micrometers(KEY_HEIGHT));
+        assert_eq!(u32_at(&report, 15), UPDATE_LATENCY_US);
+        assert_eq!(u32_at(&report, 19), LAMP_PURPOSE_CONTROL);
+        assert_eq!(report[23..], [COLOR_LEVELS, COLOR_LEVELS, COLOR_LEVELS, 1, 1, 0x04]);
+        // then it wraps round to the first lamp, which has no input binding here
+        let report = lamps.attributes_response_report(|lamp| (lamp == N_KEYS - 1).then_some(0x04));
+        assert_eq!(u16::from_le_bytes([report[1], report[2]]), 0);
+        assert_eq!(report[28], 0);
+    }
+
+    #[test]
+    fn get_report_needs_room() {
+        let mut lamps = LampArray::new();
+        let mut buf = [0u8; 64];
+        assert_eq!(lamps.get_report(ATTRIBUTES_REPORT_ID, &mut buf, |_| None), Some(ATTRIBUTES_REPORT_SIZE));
+        assert_eq!(lamps.get_report(ATTRIBUTES_RESPONSE_REPORT_ID, &mut buf, |_| None), Some(ATTRIBUTES_RESPONSE_REPORT_SIZE));
+        assert_eq!(lamps.get_report(ATTRIBUTES_RESPONSE_REPORT_ID, &mut buf[..28], |_| None), None);
+        assert_eq!(lamps.get_report(MULTI_UPDATE_REPORT_ID, &mut buf, |_| None), None);
+    }
+
+    #[test]
+    fn short_reports_are_rejected() {
+        let mut lamps = host_controlled();
+        let full_reports = [
+            std::vec![ATTRIBUTES_REQUEST_REPORT_ID, 0, 0],
+            multi_update(1, LAMP_UPDATE_COMPLETE, &[(0, RED)]),
+            range_update(LAMP_UPDATE_COMPLETE, 0, 1, RED),
+            std::vec![CONTROL_REPORT_ID, 0],
+        ];
+        for report in full_reports {
+            for len in 0..report.len() {
+                assert_eq!(lamps.set_report(report[0], &report[..len]), Err(LampArrayError::BadReport), "{report:?} cut to {len}");
+            }
+        }
+        assert_eq!(lamps.colors(), Some([BLACK; N_KEYS]));
+    }
+
+    #[test]
+    fn report_ids_must_match() {
+        let mut lamps = LampArray::new();
+        assert_eq!(lamps.set_report(CONTROL_REPORT_ID, &[RANGE_UPDATE_REPORT_ID, 0]), Err(LampArrayError::BadReport));
+        assert_eq!(lamps.set_report(ATTRIBUTES_REPORT_ID, &[ATTRIBUTES_REPORT_ID; 23]), Err(LampArrayError::UnknownReport));
+        assert_eq!(lamps.set_report(0x42, &[0x42, 0]), Err(LampArrayError::UnknownReport));
+    }
+
+    #[test]
+    fn lamp_ids_past_the_end_are_rejected() {
+        let mut lamps = host_controlled();
+        let past_end = N_KEYS as u16;
+        assert_eq!(lamps.set_report(ATTRIBUTES_REQUEST_REPORT_ID, &[ATTRIBUTES_REQUEST_REPORT_ID, past_end as u8, (past_end >> 8) as u8]),
+                   Err(LampArrayError::BadReport));
+        assert_eq!(lamps.set_report(MULTI_UPDATE_REPORT_ID, &multi_update(2, LAMP_UPDATE_COMPLETE, &[(0, RED), (past_end, RED)])),
+                   Err(LampArrayError::BadReport));
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(LAMP_UPDATE_COMPLETE, 0, past_end, RED)),
+                   Err(LampArrayError::BadReport));
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(LAMP_UPDATE_COMPLETE, u16::MAX, u16::MAX, RED)),
+                   Err(LampArrayError::BadReport));
+        assert_eq!(lamps.colors(), Some([BLACK; N_KEYS]));
+    }
+
+    #[test]
+    fn multi_update_count_is_limited() {
+        let mut lamps = host_controlled();
+        let report = multi_update(MULTI_UPDATE_LAMPS as u8 + 1, LAMP_UPDATE_COMPLETE, &[(0, RED)]);
+        assert_eq!(lamps.set_report(MULTI_UPDATE_REPORT_ID, &report), Err(LampArrayError::BadReport));
+        // only the first `count` lamps are set, whatever the rest of the report says
+        let report = multi_update(1, LAMP_UPDATE_COMPLETE, &[(0, RED), (1, BLUE)]);
+        assert_eq!(lamps.set_report(MULTI_UPDATE_REPORT_ID, &report), Ok(true));
+        let colors = lamps.colors().unwrap();
+        assert_eq!(colors[..2], [RED, BLACK]);
+    }
+
+    #[test]
+    fn range_must_be_in_order() {
+        let mut lamps = host_controlled();
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(LAMP_UPDATE_COMPLETE, 3, 2, RED)),
+                   Err(LampArrayError::BadReport));
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(LAMP_UPDATE_COMPLETE, 2, 2, RED)), Ok(true));
+        assert_eq!(lamps.colors().unwrap()[1..4], [BLACK, RED, BLACK]);
+    }
+
+    #[test]
+    fn updates_show_once_complete() {
+        let mut lamps = host_controlled();
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(0, 0, 1, RED)), Ok(false));
+        assert_eq!(lamps.set_report(MULTI_UPDATE_REPORT_ID, &multi_update(1, 0, &[(4, BLUE)])), Ok(false));
+        assert_eq!(lamps.colors(), Some([BLACK; N_KEYS]));
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(LAMP_UPDATE_COMPLETE, 2, 2, BLUE)), Ok(true));
+        let colors = lamps.colors().unwrap();
+        assert_eq!(colors[..5], [RED, RED, BLUE, BLACK, BLUE]);
+    }
+
+    #[test]
+    fn autonomous_mode_keeps_the_firmware_colors() {
+        let mut lamps = LampArray::new();
+        // updates are taken, but don't need a redraw while the firmware is in charge
+        assert_eq!(lamps.set_report(RANGE_UPDATE_REPORT_ID, &range_update(LAMP_UPDATE_COMPLETE, 0, 0, RED)), Ok(false));
+        assert_eq!(lamps.colors(), None);
+        assert_eq!(lamps.set_report(CONTROL_REPORT_ID, &[CONTROL_REPORT_ID, 0]), Ok(true));
+        assert_eq!(lamps.colors().unwrap()[0], RED);
+        assert_eq!(lamps.set_report(CONTROL_REPORT_ID, &[CONTROL_REPORT_ID, 0]), Ok(false));
+        assert_eq!(lamps.set_report(CONTROL_REPORT_ID, &[CONTROL_REPORT_ID, 1]), Ok(true));
+        assert_eq!(lamps.colors(), None);
+    }
+}
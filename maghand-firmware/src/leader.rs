@@ -0,0 +1,230 @@
+//! Leader key: after the leader is pressed, a short sequence of keys typed within a timeout is looked
+//! up in `LEADER_SEQUENCES` and replaced by the matching action.
+//!
+//! Like `tap_dance`, this is a pure state machine driven by the key processor.
+
+use embassy_time::{Duration, Instant};
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+use heapless::Vec;
+
+use crate::keys::{Action, Layer};
+
+/// how long to wait for each key of a sequence - the timer restarts on every key
+pub const LEADER_TIMEOUT: Duration = Duration::from_millis(500);
+pub const MAX_LEADER_SEQUENCE: usize = 4;
+/// whether a sequence that doesn't match is typed out as normal keys, or thrown away
+pub const LEADER_PASS_THROUGH_UNMATCHED: bool = true;
+
+pub struct LeaderSequence {
+    pub keys: &'static [KeyboardUsage],
+    pub action: Action,
+}
+
+pub static LEADER_SEQUENCES: [LeaderSequence; 4] = [
+    LeaderSequence { keys: &[KeyboardUsage::KeyboardFf], action: Action::LayerToggle(Layer::Fn) },
+    LeaderSequence { keys: &[KeyboardUsage::KeyboardCc], action: Action::Macro(0) },
+    LeaderSequence { keys: &[KeyboardUsage::KeyboardSs, KeyboardUsage::KeyboardGg], action: Action::Macro(1) },
+    LeaderSequence { keys: &[KeyboardUsage::KeyboardDd, KeyboardUsage::KeyboardRr], action: Action::DynamicMacroRecord(0) },
+];
+const _: () = {
+    let mut i = 0;
+    while i < LEADER_SEQUENCES.len() {
+        assert!(LEADER_SEQUENCES[i].keys.len() <= MAX_LEADER_SEQUENCE, "leader sequence too long");
+        i += 1;
+    }
+};
+
+pub type Sequence = Vec<KeyboardUsage, MAX_LEADER_SEQUENCE>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeaderOutcome {
+    Matched(Action),
+    /// the keys typed, to be passed through - only when passing unmatched sequences through
+    Unmatched(Sequence),
+}
+
+pub struct Leader {
+    sequences: &'static [LeaderSequence],
+    /// whether unmatched sequences come back to be typed, or are thrown away - see
+    /// `LEADER_PASS_THROUGH_UNMATCHED`
+    pass_through_unmatched: bool,
+    active: bool,
+    sequence: Sequence,
+    deadline: Instant,
+}
+
+impl Leader {
+    pub const fn new(sequences: &'static [LeaderSequence], pass_through_unmatched: bool) -> Self {
+        Leader { sequences, pass_through_unmatched, active: false, sequence: Vec::new(), deadline: Instant::MIN }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.active { Some(self.deadline) } else { None }
+    }
+
+    pub fn start(&mut self, now: Instant) {
+        defmt::debug!("leader started");
+        self.active = true;
+        self.sequence.clear();
+        self.deadline = now + LEADER_TIMEOUT;
+    }
+
+    /// Adds a key to the sequence.  Resolves as soon as the outcome can't change: on an exact match
+    /// that no longer sequence starts with, or when no sequence starts with the keys so far.
+    pub fn key(&mut self, usage: KeyboardUsage, now: Instant) -> Option<LeaderOutcome> {
+        if !self.active {
+            return None;
+        }
+        if self.sequence.push(usage).is_err() {
+            return self.finish();
+        }
+        self.deadline = now + LEADER_TIMEOUT;
+
+        let mut prefix_of_longer = false;
+        let mut any_prefix = false;
+        for seq in self.sequences.iter() {
+            if seq.keys.starts_with(&self.sequence) {
+                any_prefix = true;
+                prefix_of_longer |= seq.keys.len() > self.sequence.len();
+            }
+        }
+
+        if !any_prefix || !prefix_of_longer || self.sequence.is_full() {
+            self.finish()
+        } else {
+            None
+        }
+    }
+
+    pub fn timeout(&mut self, now: Instant) -> Option<LeaderOutcome> {
+        if self.active && now >= self.deadline { self.finish() } else { None }
+    }
+
+    fn finish(&mut self) -> Option<LeaderOutcome> {
+        self.active = false;
+        let sequence = core::mem::take(&mut self.sequence);
+        match self.sequences.iter().find(|seq| seq.keys == sequence.as_slice()) {
+            Some(seq) => {
+                defmt::debug!("leader sequence matched");
+                Some(LeaderOutcome::Matched(seq.action))
+            }
+            None if sequence.is_empty() => None,
+            None => {
+                defmt::debug!("leader sequence of {} keys unmatched", sequence.len());
+                self.pass_through_unmatched.then_some(LeaderOutcome::Unmatched(sequence))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: KeyboardUsage = KeyboardUsage::KeyboardAa;
+    const B: KeyboardUsage = KeyboardUsage::KeyboardBb;
+    const C: KeyboardUsage = KeyboardUsage::KeyboardCc;
+    const D: KeyboardUsage = KeyboardUsage::KeyboardDd;
+    const E: KeyboardUsage = KeyboardUsage::KeyboardEe;
+    const X: KeyboardUsage = KeyboardUsage::KeyboardXx;
+
+    /// the sequences for these tests, rather than the ones in the keymap
+    static SEQUENCES: [LeaderSequence; 4] = [
+        LeaderSequence { keys: &[A], action: Action::Macro(0) },
+        LeaderSequence { keys: &[B, C], action: Action::Macro(1) },
+        LeaderSequence { keys: &[B, C, D], action: Action::Macro(2) },
+        // longer than a sequence can get, so it can never match
+        LeaderSequence { keys: &[E, E, E, E, E], action: Action::Macro(3) },
+    ];
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(1000 + ms)
+    }
+
+    fn started(pass_through_unmatched: bool) -> Leader {
+        let mut leader = Leader::new(&SEQUENCES, pass_through_unmatched);
+        leader.start(at(0));
+        leader
+    }
+
+    #[test]
+    fn one_key_sequence() {
+        let mut leader = started(true);
+        assert_eq!(leader.key(A, at(100)), Some(LeaderOutcome::Matched(Action::Macro(0))));
+        assert!(!leader.is_active());
+        assert_eq!(leader.deadline(), None);
+    }
+
+    #[test]
+    fn multi_key_sequence() {
+        let mut leader = started(true);
+        assert_eq!(leader.key(B, at(100)), None);
+        assert_eq!(leader.key(C, at(200)), None, "B C could still become B C D");
+        assert_eq!(leader.key(D, at(300)), Some(LeaderOutcome::Matched(Action::Macro(2))));
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn shorter_sequence_matches_on_timeout() {
+        let mut leader = started(true);
+        assert_eq!(leader.key(B, at(100)), None);
+        assert_eq!(leader.key(C, at(200)), None);
+        assert_eq!(leader.timeout(at(200) + LEADER_TIMEOUT), Some(LeaderOutcome::Matched(Action::Macro(1))));
+    }
+
+    #[test]
+    fn unmatched_sequence_passes_through() {
+        let mut leader = started(true);
+        assert_eq!(leader.key(B, at(100)), None);
+        assert_eq!(leader.key(X, at(200)), Some(LeaderOutcome::Unmatched(Vec::from_slice(&[B, X]).unwrap())));
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn unmatched_sequence_is_discarded() {
+        let mut leader = started(false);
+        assert_eq!(leader.key(B, at(100)), None);
+        assert_eq!(leader.key(X, at(200)), None);
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn timeout() {
+        let mut leader = started(true);
+        assert_eq!(leader.deadline(), Some(at(0) + LEADER_TIMEOUT));
+        assert_eq!(leader.key(B, at(100)), None);
+        // every key restarts the timer
+        assert_eq!(leader.deadline(), Some(at(100) + LEADER_TIMEOUT));
+        assert_eq!(leader.timeout(at(100) + LEADER_TIMEOUT - Duration::from_millis(1)), None);
+        assert_eq!(leader.timeout(at(100) + LEADER_TIMEOUT),
+                   Some(LeaderOutcome::Unmatched(Vec::from_slice(&[B]).unwrap())));
+        assert!(!leader.is_active());
+        assert_eq!(leader.timeout(at(5000)), None);
+    }
+
+    #[test]
+    fn timeout_with_no_keys() {
+        let mut leader = started(true);
+        assert_eq!(leader.timeout(at(0) + LEADER_TIMEOUT), None);
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn sequence_ends_at_max_length() {
+        let mut leader = started(true);
+        for i in 1..MAX_LEADER_SEQUENCE {
+            assert_eq!(leader.key(E, at(100 * i as u64)), None);
+        }
+        // full, so it resolves with what there is instead of taking more keys
+        let full = Vec::from_slice(&[E; MAX_LEADER_SEQUENCE]).unwrap();
+        assert_eq!(leader.key(E, at(1000)), Some(LeaderOutcome::Unmatched(full)));
+        assert!(!leader.is_active());
+        assert_eq!(leader.key(E, at(1100)), None);
+    }
+}
@@ -0,0 +1,49 @@
+//! The parts of the firmware that don't touch the hardware: the keymap and what the keys do, the
+//! reports that come out of them, and the protocols spoken over raw HID and the split link.  The
+//! firmware itself is the bin, which wires these up to the peripherals.
+//!
+//! Keeping them apart lets them be tested on the host, with
+//! `cargo test --lib --target x86_64-unknown-linux-gnu` (or whichever triple the host is).
+
+#![cfg_attr(not(test), no_std)]
+// the state machines here are made with `new`, often in a const context for a static, and only ever
+// by the firmware, which has no use for a `Default` as well
+#![allow(clippy::new_without_default)]
+
+pub mod hardware_consts;
+pub mod caps_word;
+pub mod dynamic_macros;
+pub mod extra_keys;
+pub mod host_leds;
+pub mod key_processor;
+pub mod keys;
+pub mod lamp_array;
+pub mod leader;
+pub mod macros;
+pub mod midi;
+pub mod mouse_keys;
+pub mod oneshot;
+pub mod raw_hid_protocol;
+pub mod report;
+pub mod split_protocol;
+pub mod tap_dance;
+
+/// defmt needs a logger and a panic handler to link, which on the target come from defmt-rtt and
+/// panic-probe
+#[cfg(test)]
+mod test_defmt {
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+}
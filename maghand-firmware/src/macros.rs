@@ -0,0 +1,395 @@
+//! Stored keystroke sequences ("macros") and a player that steps through them without blocking.
+//!
+//! All macros live back-to-back in one null-separated byte buffer, using the same encoding as QMK's
+//! dynamic macro buffer: plain ASCII bytes are typed as characters, and `SS_QMK_PREFIX` introduces a
+//! tap/down/up of a raw keyboard usage or a delay in milliseconds (written as ASCII digits ended by
+//! `|`).  The buffer is in RAM so that it can be rewritten at runtime without reflashing.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::lazy_lock::LazyLock;
+use embassy_time::{Duration, Instant};
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+use heapless::{Deque, Vec};
+
+use crate::dynamic_macros::DYNAMIC_MACROS;
+use crate::report::UsageEvent;
+
+pub const MACRO_BUFFER_SIZE: usize = 512;
+pub const MAX_MACRO_LEN: usize = 128;
+const MACRO_QUEUE_LEN: usize = 4;
+const MAX_MACRO_HELD: usize = 8;
+/// the most usage events one step decodes to: a shifted character is shift, press, release, unshift
+const MAX_STEP_EVENTS: usize = 4;
+
+pub const SS_QMK_PREFIX: u8 = 1;
+pub const SS_TAP_CODE: u8 = 1;
+pub const SS_DOWN_CODE: u8 = 2;
+pub const SS_UP_CODE: u8 = 3;
+pub const SS_DELAY_CODE: u8 = 4;
+const SS_DELAY_END: u8 = b'|';
+/// the longest delay step played, which is also the longest `MacroWriter::delay` can write - the
+/// buffer can be written by the host, so longer ones are cut short rather than trusted
+const MAX_DELAY_MS: u64 = u16::MAX as u64;
+
+const SHIFT: u8 = KeyboardUsage::KeyboardLeftShift as u8;
+
+pub static MACRO_BUFFER: LazyLock<Mutex<CriticalSectionRawMutex, RefCell<[u8; MACRO_BUFFER_SIZE]>>> = LazyLock::new(|| {
+    Mutex::new(RefCell::new(default_macros()))
+});
+
+/// the macros the buffer starts out with, before any are loaded from flash or edited
+pub fn default_macros() -> [u8; MACRO_BUFFER_SIZE] {
+    let mut buf = [0u8; MACRO_BUFFER_SIZE];
+    let mut writer = MacroWriter::new(&mut buf);
+
+    // 0: select all and copy
+    writer.wrapped(KeyboardUsage::KeyboardLeftControl, |w| {
+        w.tap(KeyboardUsage::KeyboardAa);
+        w.delay(20);
+        w.tap(KeyboardUsage::KeyboardCc);
+    });
+    writer.end();
+    // 1: sign-off
+    writer.text("maghand\n");
+    writer.end();
+
+    if writer.overflowed() {
+        defmt::error!("default macros do not fit in the macro buffer");
+    }
+    buf
+}
+
+/// Writes macros into a buffer in the format the player expects.
+///
+/// Writes that don't fit are dropped and flagged, rather than leaving a truncated step behind.
+pub struct MacroWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    overflowed: bool,
+}
+
+impl<'a> MacroWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        MacroWriter { buf, pos: 0, overflowed: false }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        // always leave room for a final null terminator
+        if self.overflowed || self.pos + bytes.len() >= self.buf.len() {
+            self.overflowed = true;
+            return;
+        }
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    pub fn tap(&mut self, usage: KeyboardUsage) {
+        self.push(&[SS_QMK_PREFIX, SS_TAP_CODE, usage as u8]);
+    }
+
+    pub fn press(&mut self, usage: KeyboardUsage) {
+        self.push(&[SS_QMK_PREFIX, SS_DOWN_CODE, usage as u8]);
+    }
+
+    pub fn release(&mut self, usage: KeyboardUsage) {
+        self.push(&[SS_QMK_PREFIX, SS_UP_CODE, usage as u8]);
+    }
+
+    pub fn delay(&mut self, ms: u16) {
+        let mut digits = [0u8; 5];
+        let mut start = digits.len();
+        let mut rest = ms;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+            if rest == 0 {
+                break;
+            }
+        }
+        self.push(&[SS_QMK_PREFIX, SS_DELAY_CODE]);
+        self.push(&digits[start..]);
+        self.push(&[SS_DELAY_END]);
+    }
+
+    /// types an ASCII string - characters the player can't type are skipped on playback
+    pub fn text(&mut self, text: &str) {
+        for &b in text.as_bytes() {
+            if b.is_ascii() && b > SS_DELAY_CODE {
+                self.push(&[b]);
+            }
+        }
+    }
+
+    /// holds `modifier` down for the steps written by `steps`
+    pub fn wrapped(&mut self, modifier: KeyboardUsage, steps: impl FnOnce(&mut Self)) {
+        self.press(modifier);
+        steps(self);
+        self.release(modifier);
+    }
+
+    /// terminates the current macro, so the next write starts the next macro
+    pub fn end(&mut self) {
+        if self.overflowed || self.pos >= self.buf.len() {
+            self.overflowed = true;
+            return;
+        }
+        self.buf[self.pos] = 0;
+        self.pos += 1;
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MacroSource {
+    /// a macro in the stored macro buffer
+    Stored(u8),
+    /// a dynamic macro slot - see `dynamic_macros`
+    Dynamic(u8),
+}
+
+/// Copies the steps of a macro out of where it is stored, or None if there is no such macro.
+fn load_macro(source: MacroSource) -> Option<Vec<u8, MAX_MACRO_LEN>> {
+    let truncated = |steps: &[u8]| {
+        if steps.len() > MAX_MACRO_LEN {
+            defmt::warn!("macro {} is longer than {} bytes, truncating", source, MAX_MACRO_LEN);
+        }
+        Vec::from_slice(&steps[..steps.len().min(MAX_MACRO_LEN)]).unwrap()
+    };
+
+    match source {
+        MacroSource::Stored(id) => MACRO_BUFFER.get().lock(|buf| {
+            buf.borrow().split(|b| *b == 0).nth(id as usize).map(truncated)
+        }),
+        MacroSource::Dynamic(slot) => DYNAMIC_MACROS.lock(|dm| {
+            dm.borrow().steps(slot).map(truncated)
+        }),
+    }
+}
+
+/// Maps a printable ASCII character to the (US layout) usage that types it, and whether shift is needed.
+fn ascii_to_usage(c: u8) -> Option<(u8, bool)> {
+    let unshifted = |kbu: KeyboardUsage| Some((kbu as u8, false));
+    let shifted = |kbu: KeyboardUsage| Some((kbu as u8, true));
+    match c {
+        b'a'..=b'z' => Some((KeyboardUsage::KeyboardAa as u8 + (c - b'a'), false)),
+        b'A'..=b'Z' => Some((KeyboardUsage::KeyboardAa as u8 + (c - b'A'), true)),
+        b'1'..=b'9' => Some((KeyboardUsage::Keyboard1Exclamation as u8 + (c - b'1'), false)),
+        b'0' => unshifted(KeyboardUsage::Keyboard0CloseParens),
+        b'\n' => unshifted(KeyboardUsage::KeyboardEnter),
+        b'\t' => unshifted(KeyboardUsage::KeyboardTab),
+        b' ' => unshifted(KeyboardUsage::KeyboardSpacebar),
+        b'-' => unshifted(KeyboardUsage::KeyboardDashUnderscore),
+        b'=' => unshifted(KeyboardUsage::KeyboardEqualPlus),
+        b'[' => unshifted(KeyboardUsage::KeyboardOpenBracketBrace),
+        b']' => unshifted(KeyboardUsage::KeyboardCloseBracketBrace),
+        b'\\' => unshifted(KeyboardUsage::KeyboardBackslashBar),
+        b';' => unshifted(KeyboardUsage::KeyboardSemiColon),
+        b'\'' => unshifted(KeyboardUsage::KeyboardSingleDoubleQuote),
+        b'`' => unshifted(KeyboardUsage::KeyboardBacktickTilde),
+        b',' => unshifted(KeyboardUsage::KeyboardCommaLess),
+        b'.' => unshifted(KeyboardUsage::KeyboardPeriodGreater),
+        b'/' => unshifted(KeyboardUsage::KeyboardSlashQuestion),
+        b'!' => shifted(KeyboardUsage::Keyboard1Exclamation),
+        b'@' => shifted(KeyboardUsage::Keyboard2At),
+        b'#' => shifted(KeyboardUsage::Keyboard3Hash),
+        b'$' => shifted(KeyboardUsage::Keyboard4Dollar),
+        b'%' => shifted(KeyboardUsage::Keyboard5Percent),
+        b'^' => shifted(KeyboardUsage::Keyboard6Caret),
+        b'&' => shifted(KeyboardUsage::Keyboard7Ampersand),
+        b'*' => shifted(KeyboardUsage::Keyboard8Asterisk),
+        b'(' => shifted(KeyboardUsage::Keyboard9OpenParens),
+        b')' => shifted(KeyboardUsage::Keyboard0CloseParens),
+        b'_' => shifted(KeyboardUsage::KeyboardDashUnderscore),
+        b'+' => shifted(KeyboardUsage::KeyboardEqualPlus),
+        b'{' => shifted(KeyboardUsage::KeyboardOpenBracketBrace),
+        b'}' => shifted(KeyboardUsage::KeyboardCloseBracketBrace),
+        b'|' => shifted(KeyboardUsage::KeyboardBackslashBar),
+        b':' => shifted(KeyboardUsage::KeyboardSemiColon),
+        b'"' => shifted(KeyboardUsage::KeyboardSingleDoubleQuote),
+        b'~' => shifted(KeyboardUsage::KeyboardBacktickTilde),
+        b'<' => shifted(KeyboardUsage::KeyboardCommaLess),
+        b'>' => shifted(KeyboardUsage::KeyboardPeriodGreater),
+        b'?' => shifted(KeyboardUsage::KeyboardSlashQuestion),
+        _ => None,
+    }
+}
+
+/// Plays macros one press or release at a time, so the caller can send a report for every event and
+/// go back to handling key changes in between.
+///
+/// Macros triggered while one is playing are queued.  Anything a macro leaves held down is released
+/// when it finishes.
+pub struct MacroPlayer {
+    current: Vec<u8, MAX_MACRO_LEN>,
+    pos: usize,
+    pending: Deque<UsageEvent, MAX_STEP_EVENTS>,
+    resume_at: Instant,
+    held: Vec<u8, MAX_MACRO_HELD>,
+    queue: Deque<MacroSource, MACRO_QUEUE_LEN>,
+}
+
+impl MacroPlayer {
+    pub fn new() -> Self {
+        MacroPlayer {
+            current: Vec::new(),
+            pos: 0,
+            pending: Deque::new(),
+            resume_at: Instant::MIN,
+            held: Vec::new(),
+            queue: Deque::new(),
+        }
+    }
+
+    pub fn trigger(&mut self, source: MacroSource) {
+        if self.queue.push_back(source).is_err() {
+            defmt::warn!("macro queue full, dropping macro {}", source);
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.pending.is_empty() || self.pos < self.current.len() || !self.held.is_empty() || !self.queue.is_empty()
+    }
+
+    /// when `poll` should next be called, or None if there's nothing to play
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.is_playing() { Some(self.resume_at) } else { None }
+    }
+
+    /// Returns the next event to send, or None if there is nothing due at `now`.
+    pub fn poll(&mut self, now: Instant) -> Option<UsageEvent> {
+        loop {
+            if now < self.resume_at {
+                return None;
+            }
+
+            if let Some(event) = self.pending.pop_front() {
+                match event {
+                    UsageEvent::Press(usage) | UsageEvent::PressWeak { usage, .. } => {
+                        if !self.held.contains(&usage) && self.held.push(usage).is_err() {
+                            defmt::warn!("macro holding too many keys, {} will not be auto-released", usage);
+                        }
+                    }
+                    UsageEvent::Release(usage) => self.held.retain(|u| *u != usage),
+                    _ => {} // macros only type keyboard usages
+                }
+                return Some(event);
+            }
+
+            if self.pos < self.current.len() {
+                self.decode_step(now);
+                continue;
+            }
+
+            if let Some(usage) = self.held.pop() {
+                return Some(UsageEvent::Release(usage));
+            }
+
+            let source = self.queue.pop_front()?;
+            match load_macro(source) {
+                Some(steps) => {
+                    defmt::debug!("playing macro {}", source);
+                    self.current = steps;
+                    self.pos = 0;
+                }
+                None => defmt::warn!("no macro {}", source),
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        let b = self.current.get(self.pos).copied();
+        self.pos += 1;
+        b
+    }
+
+    /// decodes one step into `pending`, or into `resume_at` for a delay
+    fn decode_step(&mut self, now: Instant) {
+        let Some(b) = self.next_byte() else { return };
+
+        if b != SS_QMK_PREFIX {
+            match ascii_to_usage(b) {
+                Some((usage, shift)) => {
+                    if shift {
+                        self.pending.push_back(UsageEvent::Press(SHIFT)).ok();
+                    }
+                    self.pending.push_back(UsageEvent::Press(usage)).ok();
+                    self.pending.push_back(UsageEvent::Release(usage)).ok();
+                    if shift {
+                        self.pending.push_back(UsageEvent::Release(SHIFT)).ok();
+                    }
+                }
+                None => defmt::warn!("macro contains untypeable character {}", b),
+            }
+            return;
+        }
+
+        match (self.next_byte(), self.next_byte()) {
+            (Some(SS_TAP_CODE), Some(usage)) => {
+                self.pending.push_back(UsageEvent::Press(usage)).ok();
+                self.pending.push_back(UsageEvent::Release(usage)).ok();
+            }
+            (Some(SS_DOWN_CODE), Some(usage)) => {
+                self.pending.push_back(UsageEvent::Press(usage)).ok();
+            }
+            (Some(SS_UP_CODE), Some(usage)) => {
+                self.pending.push_back(UsageEvent::Release(usage)).ok();
+            }
+            (Some(SS_DELAY_CODE), Some(first_digit)) => {
+                let mut ms = 0u64;
+                let mut digit = Some(first_digit);
+                while let Some(d) = digit {
+                    if d == SS_DELAY_END {
+                        break;
+                    }
+                    if d.is_ascii_digit() {
+                        ms = ms.saturating_mul(10).saturating_add((d - b'0') as u64);
+                    }
+                    digit = self.next_byte();
+                }
+                self.resume_at = now + Duration::from_millis(ms.min(MAX_DELAY_MS));
+            }
+            (code, _) => defmt::warn!("bad macro step code {}", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A player already partway into `steps`, so the tests don't touch the shared macro buffer.
+    fn playing(steps: &[u8]) -> MacroPlayer {
+        let mut player = MacroPlayer::new();
+        player.current = Vec::from_slice(steps).unwrap();
+        player
+    }
+
+    #[test]
+    fn delay_holds_playback() {
+        let mut player = playing(&[SS_QMK_PREFIX, SS_DELAY_CODE, b'2', b'5', SS_DELAY_END, b'a']);
+        assert_eq!(player.poll(Instant::from_millis(100)), None);
+        assert_eq!(player.deadline(), Some(Instant::from_millis(125)));
+        assert_eq!(player.poll(Instant::from_millis(124)), None);
+        assert_eq!(player.poll(Instant::from_millis(125)), Some(UsageEvent::Press(KeyboardUsage::KeyboardAa as u8)));
+    }
+
+    #[test]
+    fn oversized_delay_is_capped() {
+        let mut steps = std::vec![SS_QMK_PREFIX, SS_DELAY_CODE];
+        steps.extend_from_slice(&[b'9'; 40]);
+        steps.extend_from_slice(&[SS_DELAY_END, b'a']);
+        let mut player = playing(&steps);
+        assert_eq!(player.poll(Instant::from_millis(0)), None);
+        assert_eq!(player.deadline(), Some(Instant::from_millis(MAX_DELAY_MS)));
+        assert_eq!(player.poll(Instant::from_millis(MAX_DELAY_MS)), Some(UsageEvent::Press(KeyboardUsage::KeyboardAa as u8)));
+    }
+}
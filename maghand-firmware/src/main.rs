@@ -4,9 +4,14 @@
 use embedded_hal::digital::OutputPin;
 
 use embassy_executor::Spawner;
-use embassy_nrf::gpio::{Flex, Level, Output, OutputDrive, Pull};
+use embassy_nrf::gpio::{Flex, Input, Level, Output, OutputDrive, Pull};
 use embassy_nrf::pwm::DutyCycle;
 use embassy_nrf::{Peri, bind_interrupts, peripherals, pwm, saadc, spim, twim, usb};
+use embassy_nrf::saadc::Input as _;
+#[cfg(all(feature = "split", not(feature = "right_hand")))]
+use embassy_nrf::buffered_uarte;
+#[cfg(all(feature = "split", feature = "right_hand"))]
+use embassy_nrf::uarte;
 use embassy_time::Timer;
 use embassy_nrf::timer::Frequency;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
@@ -14,7 +19,7 @@ use embassy_sync::mutex::Mutex;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::pubsub::PubSubChannel;
 use embassy_nrf::usb::Driver;
-use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
+use embassy_nrf::usb::vbus_detect::{HardwareVbusDetect, VbusDetect};
 use {defmt_rtt as _, panic_probe as _};
 
 use static_cell::ConstStaticCell;
@@ -24,19 +29,49 @@ use heapless::index_map::FnvIndexMap;
 use smart_leds::SmartLedsWrite;
 use ws2812_spi::Ws2812;
 
-mod hardware_consts;
-use hardware_consts::*;
-mod keys;
+use maghand_firmware::hardware_consts::{self, *};
+use maghand_firmware::{extra_keys, host_leds, key_processor, keys, lamp_array, macros, mouse_keys, raw_hid_protocol,
+                       report};
+#[cfg(feature = "midi")]
+use maghand_firmware::midi;
+#[cfg(feature = "split")]
+use maghand_firmware::split_protocol;
+use maghand_firmware::keys::{KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS};
+mod battery;
+mod bootloader;
+mod hid_class;
+mod identity;
+#[cfg(feature = "dfu_runtime")]
+mod dfu_runtime;
+mod power;
+mod raw_hid;
+mod shell;
+mod sleep;
+mod split;
+#[cfg(all(feature = "split", not(feature = "right_hand")))]
+mod split_central;
+#[cfg(all(feature = "split", feature = "right_hand"))]
+mod split_peripheral;
+mod storage;
 mod usb_kb;
+#[cfg(feature = "midi")]
+mod usb_midi;
+mod via;
+
+// eight USB interfaces is as many as embassy-usb can have, and MIDI takes two of them
+#[cfg(all(feature = "midi", feature = "dfu_runtime"))]
+compile_error!("the midi and dfu_runtime features can't be used together - see Cargo.toml");
 
 const MAX_KEY_LED: u8 = 100;
+/// how bright the key LEDs can get while the battery is low
+const LOW_BATTERY_MAX_KEY_LED: u8 = 20;
+const LOW_BATTERY_FLASHES: usize = 3;
 
 #[cfg(feature = "adc_debug")]
 use embassy_time::Instant;
 
 
 // alloc only needed for lsm6ds3tr crate.  Might not be worth that.
-use lsm6ds3tr;
 use embedded_alloc::LlffHeap as Heap;
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
@@ -44,8 +79,6 @@ static HEAP: Heap = Heap::empty();
 // static sync structures
 //const N_CHANNEL_BUFFER: usize = 32;
 //static CHANNEL: Channel<ThreadModeRawMutex, keys::KeySignal, N_CHANNEL_BUFFER> = Channel::new();
-const KEYCHANGE_BUS_CAP: usize = 32;
-const KEYCHANGE_BUS_SUBS: usize = 5;
 static KEYCHANGE_BUS: PubSubChannel<ThreadModeRawMutex, keys::KeySignal, KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, N_KEYS> = PubSubChannel::new();
 
 static KEYS_MUTEX_LAZY: LazyLock<Mutex<ThreadModeRawMutex, [keys::AnalogKey<ThreadModeRawMutex>; N_KEYS]>> = LazyLock::new(
@@ -66,7 +99,9 @@ static KEY_INDEX_MAP: LazyLock<FnvIndexMap<u8, usize, 32>> = LazyLock::new(|| {
 
 bind_interrupts!(struct Irqs {
     SAADC => saadc::InterruptHandler;
-    //UARTE0 => uarte::InterruptHandler<peripherals::UARTE0>;
+    #[cfg(feature = "split")]
+    UARTE0 => #[cfg(not(feature = "right_hand"))] buffered_uarte::InterruptHandler<peripherals::UARTE0>,
+              #[cfg(feature = "right_hand")] uarte::InterruptHandler<peripherals::UARTE0>;
     SPIM3 => spim::InterruptHandler<peripherals::SPI3>;
     TWISPI0 => twim::InterruptHandler<peripherals::TWISPI0>;
     USBD => usb::InterruptHandler<peripherals::USBD>;
@@ -88,7 +123,9 @@ async fn main(spawner: Spawner) {
     nrf_config.hfclk_source = embassy_nrf::config::HfclkSource::ExternalXtal;
     nrf_config.lfclk_source = embassy_nrf::config::LfclkSource::ExternalXtal;
     //nrf_config.dcdc = embassy_nrf::config::DcdcConfig {reg0: false, reg1:true, reg0_voltage: None}; // TODO: decide if dc/dc is worth it in vusb or battery mode
-    let mut p = embassy_nrf::init(nrf_config);
+    let p = embassy_nrf::init(nrf_config);
+    // everything starts over after System OFF, apart from whatever the IMU was left doing
+    let woke_from_system_off = power::take_woke_from_system_off();
 
 
     // setup vhi gpio
@@ -142,6 +179,7 @@ async fn main(spawner: Spawner) {
     // 6D_i2C_SDA: p.P0_07
     // 6D_i2C_SCL: p.P0_27
     let mut imu_pwr_pin = Output::new(p.P1_08, Level::Low, OutputDrive::Standard);
+    let mut imu_int1 = p.P0_11; // only used to wake from System OFF - see `sleep`
     imu_pwr_pin.set_high();
     Timer::after(IMU_POWERUP_TIME).await;
 
@@ -168,6 +206,10 @@ async fn main(spawner: Spawner) {
         .with_accel(accel_settings);
     let mut imu = lsm6ds3tr::LSM6DS3TR::new(lsm6ds3tr::interface::I2cInterface::new(i2c))
         .with_settings(imu_settings);
+    // it kept power through System OFF, so it still has its wake-up interrupts set up
+    if woke_from_system_off && imu.software_reset().is_err() {
+        defmt::warn!("LSM6DS3TR-C reset after System OFF failed");
+    }
     imu.init().expect("LSM6DS3TR-C initialization failure!");
 
     // setup GPIO to enable various keys
@@ -179,34 +221,17 @@ async fn main(spawner: Spawner) {
     let mut mux_a = Output::new(p.P0_09, Level::Low, OutputDrive::Standard);
     let mut mux_b = Output::new(p.P0_10, Level::Low, OutputDrive::Standard);
 
-    // setup ADC for key position reading
-    let keyset0_channel_config = saadc::ChannelConfig::single_ended(p.P0_02.reborrow()); // 01X / 0
-    let keyset1_channel_config = saadc::ChannelConfig::single_ended(p.P0_03.reborrow()); // 01Y / 1
-    let keyset2_channel_config = saadc::ChannelConfig::single_ended(p.P0_04.reborrow()); // 23X / 2
-    let keyset3_channel_config = saadc::ChannelConfig::single_ended(p.P0_05.reborrow()); // 23Y / 3
-    let keyset4_channel_config = saadc::ChannelConfig::single_ended(p.P0_28.reborrow()); // 45X / 4
-    let keyset5_channel_config = saadc::ChannelConfig::single_ended(p.P0_29.reborrow()); // 45Y / 5
-    let mut channel_configs = [
-        keyset0_channel_config,
-        keyset1_channel_config,
-        keyset2_channel_config,
-        keyset3_channel_config,
-        keyset4_channel_config,
-        keyset5_channel_config,
+    // setup ADC inputs for key position reading - the sampler makes the ADC from them, since it
+    // shares the SAADC with the battery readings
+    let key_inputs = [
+        p.P0_02.degrade_saadc(), // 01X / 0
+        p.P0_03.degrade_saadc(), // 01Y / 1
+        p.P0_04.degrade_saadc(), // 23X / 2
+        p.P0_05.degrade_saadc(), // 23Y / 3
+        p.P0_28.degrade_saadc(), // 45X / 4
+        p.P0_29.degrade_saadc(), // 45Y / 5
     ];
-    for config in channel_configs.iter_mut() {
-        config.reference = saadc::Reference::VDD1_4;
-        config.gain = saadc::Gain::GAIN1_4;
-        config.resistor = saadc::Resistor::BYPASS;
-        config.time = saadc::Time::_10US;
-    }
-
-    let mut saadc_config = saadc::Config::default();
-    saadc_config.resolution = saadc::Resolution::_12BIT;
-    saadc_config.oversample = saadc::Oversample::BYPASS;
-
-    let adc = saadc::Saadc::new(p.SAADC, Irqs, saadc_config, channel_configs);
-    adc.calibrate().await;
+    let battery_monitor = battery::BatteryMonitor::new(p.P0_14, p.P0_17, p.P0_31);
 
 
     // select key 2 on each channel
@@ -229,71 +254,303 @@ async fn main(spawner: Spawner) {
         DutyCycle::normal(0),
     ]);
 
+    // load any saved keymap and key settings before keys start being read
+    storage::init(embassy_nrf::nvmc::Nvmc::new(p.NVMC));
+    storage::load().await;
+
     // set up USB
-    let usb_driver = Driver::new(p.USBD, Irqs, HardwareVbusDetect::new(Irqs));
+    let vbus_detect = HardwareVbusDetect::new(Irqs);
+    // the USB device handler follows VBUS from here on
+    power::set_vbus(vbus_detect.is_usb_detected());
+    let usb_driver = Driver::new(p.USBD, Irqs, vbus_detect);
 
     spawner.spawn(usb_kb::usb_task(usb_driver, 
         KEYCHANGE_BUS.subscriber().expect("couldn't make usb keychange subscriber"))
     ).expect("failed to spawn USB task");
+    spawner.spawn(bootloader::bootloader_task()).expect("failed to spawn bootloader task");
+    spawner.spawn(sleep::inactivity_task()).expect("failed to spawn inactivity task");
+
+    // the other half's keys come in on D6 - see `split`
+    #[cfg(all(feature = "split", not(feature = "right_hand")))]
+    {
+        static SPLIT_RX_BUFFER: ConstStaticCell<[u8; split_central::RX_BUFFER_SIZE]> = ConstStaticCell::new([0; split_central::RX_BUFFER_SIZE]);
+        let split_rx = buffered_uarte::BufferedUarteRx::new(p.UARTE0, p.TIMER1, p.PPI_CH2, p.PPI_CH3, p.PPI_GROUP0, Irqs,
+                                                            p.P1_11, split::uart_config(), SPLIT_RX_BUFFER.take());
+        spawner.spawn(split_central::central_task(split_rx, KEYCHANGE_BUS.immediate_publisher()))
+            .expect("failed to spawn split link task");
+    }
+    #[cfg(all(feature = "split", feature = "right_hand"))]
+    {
+        let split_tx = uarte::UarteTx::new(p.UARTE0, Irqs, p.P1_11, split::uart_config());
+        spawner.spawn(split_peripheral::peripheral_task(split_tx,
+            KEYCHANGE_BUS.subscriber().expect("couldn't make split link keychange subscriber"))
+        ).expect("failed to spawn split link task");
+    }
 
 
     //green LED on to indicate setup complete
     pwm.set_all_duties([
         DutyCycle::normal(0),
-        DutyCycle::normal(100*0),
+        DutyCycle::normal(0), // 100 for a visible green
         DutyCycle::normal(0),
         DutyCycle::normal(0),
     ]);
 
     defmt::debug!("starting adc and main loop");
 
-    spawner.spawn(adc_sampler(adc, 
-                              p.TIMER0, 
-                              p.PPI_CH0,
-                              p.PPI_CH1,
+    let key_adc = KeyAdc { saadc: p.SAADC, inputs: key_inputs, timer: p.TIMER0, ppi1: p.PPI_CH0, ppi2: p.PPI_CH1 };
+    spawner.spawn(adc_sampler(key_adc,
+                              battery_monitor,
                               mux_a,
-                              mux_b)).expect("failed to spawn adc sampler");
+                              mux_b,
+                              vhi_pin)).expect("failed to spawn adc sampler");
 
 
+    let mut battery_low = false;
     let mut loop_count = 0u32;
     let mut leddata = [smart_leds::RGB8::new(0, 0, 0); N_KEYS];
     loop {
-        defmt::debug!("looptop {}", vhi_pin.is_set_high());
+        defmt::debug!("looptop");
 
-        let low_count = (loop_count % MAX_KEY_LED as u32) as u8;
+        let low_count = (loop_count % MAX_KEY_LED as u32 * max_key_led() as u32 / MAX_KEY_LED as u32) as u8;
         let high_count = (loop_count / MAX_KEY_LED as u32 % 8) as u8;
         let r_on = (high_count & 0b1 > 0) as u8;
         let g_on = (high_count & 0b10 > 0) as u8;
         let b_on = (high_count & 0b100 > 0) as u8;
-        for i in 0..N_KEYS {
-            leddata[i] = smart_leds::RGB8 {r: low_count * r_on, g:low_count * g_on, b:low_count * b_on};
+        for led in leddata.iter_mut() {
+            *led = smart_leds::RGB8 {r: low_count * r_on, g:low_count * g_on, b:low_count * b_on};
         }
 
         #[cfg(feature = "leds_pulse_override")]
         {
-            keyleds.write(leddata.iter().cloned()).expect("couldn't set key leds");
+            // vhi is only on for scanning while suspended, and the LEDs should be off anyway
+            if !power::is_suspended() {
+                match lamp_array::LAMP_ARRAY.lock(|lamp_array| lamp_array.borrow().colors()) {
+                    Some(host_colors) => write_key_leds(&mut keyleds, &host_colors),
+                    None => keyleds.write(leddata.iter().cloned()).expect("couldn't set key leds"),
+                }
+            }
             Timer::after_millis(60).await;
+            if let Some(suspended) = power::POWER_CHANGED.try_take() {
+                set_power_state(suspended, &mut pwm, &mut imu_pwr_pin, &mut imu).await;
+            }
+            if let Some(battery) = battery::BATTERY_CHANGED.try_take() {
+                battery_changed(battery, &mut battery_low, &mut pwm).await;
+            }
+            if let Some(reason) = power::SYSTEM_OFF.try_take() {
+                enter_system_off(reason, &mut keyleds, &mut pwm, &mut imu_pwr_pin, &mut imu, &mut muxens, imu_int1.reborrow()).await;
+            }
         }
 
-        // just wait, all the action should happen in usb
+        // just wait for the host to change the LEDs or the power state, all the rest of the action happens in usb
         #[cfg(not(feature = "leds_pulse_override"))]
-        Timer::after_millis(500).await;
+        match embassy_futures::select::select4(host_leds::HOST_LEDS_CHANGED.wait(),
+                                               lamp_array::KEY_COLORS_CHANGED.wait(),
+                                               power::POWER_CHANGED.wait(),
+                                               embassy_futures::select::select3(battery::BATTERY_CHANGED.wait(),
+                                                                                power::SYSTEM_OFF.wait(),
+                                                                                Timer::after_millis(500))).await {
+            embassy_futures::select::Either4::First(leds) => {
+                if !power::is_suspended() {
+                    set_board_leds(&mut pwm, leds.board_led_duties());
+                }
+            }
+            embassy_futures::select::Either4::Second(()) => {
+                if !power::is_suspended() {
+                    // back to off when the firmware takes over again
+                    let host_colors = lamp_array::LAMP_ARRAY.lock(|lamp_array| lamp_array.borrow().colors());
+                    write_key_leds(&mut keyleds, &host_colors.unwrap_or_default());
+                }
+            }
+            embassy_futures::select::Either4::Third(suspended) => {
+                set_power_state(suspended, &mut pwm, &mut imu_pwr_pin, &mut imu).await;
+                // the key LEDs lost power along with vhi
+                if !suspended {
+                    lamp_array::KEY_COLORS_CHANGED.signal(());
+                }
+            }
+            embassy_futures::select::Either4::Fourth(embassy_futures::select::Either3::First(battery)) => {
+                battery_changed(battery, &mut battery_low, &mut pwm).await;
+            }
+            embassy_futures::select::Either4::Fourth(embassy_futures::select::Either3::Second(reason)) => {
+                enter_system_off(reason, &mut keyleds, &mut pwm, &mut imu_pwr_pin, &mut imu, &mut muxens, imu_int1.reborrow()).await;
+            }
+            embassy_futures::select::Either4::Fourth(embassy_futures::select::Either3::Third(())) => {}
+        }
 
         loop_count += 1;
     }
 }
 
+/// the brightest the key LEDs may be, which is lower while the battery is low
+fn max_key_led() -> u8 {
+    if battery::is_low() { LOW_BATTERY_MAX_KEY_LED } else { MAX_KEY_LED }
+}
+
+/// Shows `colors` on the key LEDs, dimmed to `max_key_led`.
+fn write_key_leds(keyleds: &mut Ws2812<spim::Spim<'_>>, colors: &[smart_leds::RGB8; N_KEYS]) {
+    let max = max_key_led();
+    let dim = |level: u8| (level as u16 * max as u16 / 255) as u8;
+    let dimmed = colors.iter().map(|c| smart_leds::RGB8::new(dim(c.r), dim(c.g), dim(c.b)));
+    if keyleds.write(dimmed).is_err() {
+        defmt::warn!("couldn't set key leds");
+    }
+}
+
+fn set_board_leds(pwm: &mut pwm::SimplePwm, [r, g, b]: [u16; 3]) {
+    pwm.set_all_duties([
+        DutyCycle::normal(r),
+        DutyCycle::normal(g),
+        DutyCycle::normal(b),
+        DutyCycle::normal(0),
+    ]);
+}
+
+/// Redraws the key LEDs when the battery becomes low or stops being low, so they're dimmed or
+/// brightened again, and flashes a warning on the board LED after each low reading.  (A critical
+/// battery is the ADC sampler's to act on, by asking for System OFF.)
+async fn battery_changed(battery: battery::BatteryState, battery_low: &mut bool, pwm: &mut pwm::SimplePwm<'_>) {
+    if battery.low != *battery_low {
+        *battery_low = battery.low;
+        lamp_array::KEY_COLORS_CHANGED.signal(());
+    }
+    if battery.low && !power::is_suspended() {
+        for _ in 0..LOW_BATTERY_FLASHES {
+            set_board_leds(pwm, [255, 0, 0]);
+            Timer::after_millis(150).await;
+            set_board_leds(pwm, [0; 3]);
+            Timer::after_millis(150).await;
+        }
+        set_board_leds(pwm, host_leds::host_leds().board_led_duties());
+    }
+}
+
+/// Switches everything off and puts the chip into System OFF - see `power`.  For sleep, the IMU is
+/// left watching for motion and taps to wake the chip on INT1; for a critical battery it is powered
+/// down too, so only USB wakes the chip.  Either way the wake is a reset.
+async fn enter_system_off(reason: power::SystemOff, keyleds: &mut Ws2812<spim::Spim<'_>>, pwm: &mut pwm::SimplePwm<'_>,
+                          imu_pwr_pin: &mut Output<'_>,
+                          imu: &mut lsm6ds3tr::LSM6DS3TR<lsm6ds3tr::interface::I2cInterface<twim::Twim<'_>>>,
+                          muxens: &mut [&mut Output<'_>; 3], imu_int1: Peri<'_, peripherals::P0_11>) -> ! {
+    // vhi is likely still on, in which case the key LEDs would otherwise stay lit until it goes off
+    write_key_leds(keyleds, &[smart_leds::RGB8::default(); N_KEYS]);
+    power::SCANNING_STOPPED.wait().await;
+    set_board_leds(pwm, [0; 3]);
+    for mux in muxens.iter_mut() {
+        mux.set_high();
+    }
+
+    // kept alive until System OFF, which keeps the pin configured through it
+    let _int1 = Input::new(imu_int1, Pull::Down);
+    match reason {
+        power::SystemOff::Sleep => {
+            imu.settings = sleep::imu_wake_settings();
+            if let Err(e) = imu.init() {
+                defmt::warn!("LSM6DS3TR-C wake-up setup failed: {}", e);
+            }
+            // an interrupt still latched from before would wake the chip straight away
+            if let Err(e) = imu.read_interrupt_sources() {
+                defmt::warn!("LSM6DS3TR-C interrupt clear failed: {}", e);
+            }
+            embassy_nrf::pac::P0.pin_cnf(11).modify(|w| w.set_sense(embassy_nrf::pac::gpio::vals::Sense::HIGH));
+        }
+        power::SystemOff::CriticalBattery => imu_pwr_pin.set_low(),
+    }
+
+    defmt::info!("entering System OFF");
+    embassy_nrf::power::set_system_off();
+    // System OFF is only emulated under a debugger, so the CPU carries on here
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+/// Switches the LEDs and IMU off for a USB suspend, and back on again on resume.  The key scanning
+/// slows down by itself - see `power`.
+async fn set_power_state(suspended: bool, pwm: &mut pwm::SimplePwm<'_>, imu_pwr_pin: &mut Output<'_>,
+                         imu: &mut lsm6ds3tr::LSM6DS3TR<lsm6ds3tr::interface::I2cInterface<twim::Twim<'_>>>) {
+    if suspended {
+        set_board_leds(pwm, [0; 3]);
+        imu_pwr_pin.set_low();
+    } else {
+        imu_pwr_pin.set_high();
+        Timer::after(IMU_POWERUP_TIME).await;
+        // powering down lost its settings
+        if let Err(e) = imu.init() {
+            defmt::warn!("LSM6DS3TR-C re-initialization failed: {}", e);
+        }
+        set_board_leds(pwm, host_leds::host_leds().board_led_duties());
+    }
+}
+
 // 12.5 usec (10+2.5) is 80 kHz sample rate
 // sample rate is then 80 kHz / 6 channels / 4 mux settings / NSAMP = 5 msec for NSAMP=25
 const NCHAN: usize = 6;
 const NSAMP: usize = 256;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MuxSpec {
+    // the Mux configuration for a specific key
+    pub a: Level,
+    pub b: Level,
+}
+
+impl MuxSpec {
+    pub fn iterator() -> core::slice::Iter<'static, MuxSpec> {
+        static ALLSPECS: [MuxSpec; 4] = [MuxSpec { a: Level::Low, b: Level::Low }, 
+                                         MuxSpec { a: Level::High, b: Level::Low }, 
+                                         MuxSpec { a: Level::Low, b: Level::High }, 
+                                         MuxSpec { a: Level::High, b: Level::High }];
+        ALLSPECS.iter()
+    }
+    pub fn index(&self) -> u8 {
+        match (self.a, self.b) {
+            (Level::Low, Level::Low) => 0,
+            (Level::High, Level::Low) => 1,
+            (Level::Low, Level::High) => 2,
+            (Level::High, Level::High) => 3,
+        }
+    }
+}
+
+impl Default for MuxSpec {
+    fn default() -> Self { MuxSpec { a: Level::Low, b: Level::Low } }
+}
+
+/// Makes the ADC for the key inputs, which has to be calibrated before use.
+fn make_key_adc<'a>(saadc: Peri<'a, peripherals::SAADC>, key_inputs: &'a mut [saadc::AnyInput<'static>; NCHAN]) -> saadc::Saadc<'a, NCHAN> {
+    let channel_configs = key_inputs.each_mut().map(|input| {
+        let mut config = saadc::ChannelConfig::single_ended(input.reborrow());
+        config.reference = saadc::Reference::VDD1_4;
+        config.gain = saadc::Gain::GAIN1_4;
+        config.resistor = saadc::Resistor::BYPASS;
+        config.time = saadc::Time::_10US;
+        config
+    });
+
+    let mut saadc_config = saadc::Config::default();
+    saadc_config.resolution = saadc::Resolution::_12BIT;
+    saadc_config.oversample = saadc::Oversample::BYPASS;
+
+    saadc::Saadc::new(saadc, Irqs, saadc_config, channel_configs)
+}
+
+/// The SAADC with the key inputs, and the timer and PPI channels that pace its sampling.
+struct KeyAdc {
+    saadc: Peri<'static, peripherals::SAADC>,
+    inputs: [saadc::AnyInput<'static>; NCHAN],
+    timer: Peri<'static, peripherals::TIMER0>,
+    ppi1: Peri<'static, peripherals::PPI_CH0>,
+    ppi2: Peri<'static, peripherals::PPI_CH1>,
+}
+
 #[embassy_executor::task]
-async fn adc_sampler(mut adc: saadc::Saadc<'static, NCHAN>, 
-                     mut timer: Peri<'static, peripherals::TIMER0>, 
-                     mut ppi1: Peri<'static, peripherals::PPI_CH0>, 
-                     mut ppi2: Peri<'static, peripherals::PPI_CH1>,
+async fn adc_sampler(key_adc: KeyAdc,
+                     mut battery_monitor: battery::BatteryMonitor,
                      mut mux_a: Output<'static>,
-                     mut mux_b: Output<'static>,) {
+                     mut mux_b: Output<'static>,
+                     mut vhi_pin: Flex<'static>) {
+    let KeyAdc { mut saadc, inputs: mut key_inputs, mut timer, mut ppi1, mut ppi2 } = key_adc;
 
     let keys_mutex= KEYS_MUTEX_LAZY.get();
     
@@ -319,8 +576,19 @@ async fn adc_sampler(mut adc: saadc::Saadc<'static, NCHAN>,
         }
     }
 
+    let mut adc = make_key_adc(saadc.reborrow(), &mut key_inputs);
+    adc.calibrate().await;
+
+    // main turned vhi on for the key LEDs, but while suspended it is only on for each scan - see `power`
+    let mut vhi_powered = true;
     loop {
-        for muxsetting in keys::MuxSpec::iterator() {
+        if !vhi_powered {
+            vhi_on(&mut vhi_pin);
+            Timer::after(LED_POWERUP_TIME).await;
+            vhi_powered = true;
+        }
+
+        for muxsetting in MuxSpec::iterator() {
             mux_a.set_level(muxsetting.a);
             mux_b.set_level(muxsetting.b);
             if let Some(settle_time) = MUX_SETTLE_TIME {
@@ -385,5 +653,28 @@ async fn adc_sampler(mut adc: saadc::Saadc<'static, NCHAN>,
             } 
         }
 
+        // the battery gets the SAADC to itself in between key scans - see `battery`
+        battery_monitor.check_charging();
+        if battery_monitor.is_due() {
+            drop(adc);
+            if battery_monitor.measure(saadc.reborrow()).await {
+                power::request_system_off(power::SystemOff::CriticalBattery);
+            }
+            adc = make_key_adc(saadc.reborrow(), &mut key_inputs);
+            adc.calibrate().await;
+        }
+
+        if power::system_off_requested() {
+            // main switches off everything else once the sensors are off
+            vhi_off(&mut vhi_pin);
+            power::SCANNING_STOPPED.signal(());
+            core::future::pending::<()>().await;
+        }
+
+        if power::is_suspended() {
+            vhi_off(&mut vhi_pin);
+            vhi_powered = false;
+            Timer::after(power::SUSPENDED_SCAN_PERIOD).await;
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,22 @@
+//! Playing the keys as a MIDI controller, on the `Midi` layer.
+//!
+//! Keys mapped to `Action::MidiNote` send note on and off messages instead of HID reports.  The
+//! note-on velocity comes from how fast the key went down (see `AnalogKey::press_travel_time`), and
+//! while a note sounds the key's depth goes out as polyphonic aftertouch, which only a keyboard that
+//! knows how far each key is pressed can do.  `usb_midi` sends them.
+//!
+//! The USB MIDI interface is only in builds with the `midi` feature, since there is no room for it
+//! alongside the DFU runtime interface.  Without it the notes go nowhere, and the default keymap
+//! leaves the MIDI layer empty.
+
+/// the note the default keymap's MIDI layer starts from - middle C
+pub const MIDI_FIRST_NOTE: u8 = 60;
+
+/// A note starting or stopping.  The key number is `NO_KEY` for notes that didn't come straight
+/// from a key (e.g. a tap dance), which get no velocity or aftertouch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct NoteChange {
+    pub keynumber: u8,
+    pub note: u8,
+    pub on: bool,
+}
@@ -0,0 +1,228 @@
+//! Mouse keys: cursor movement, buttons and scrolling from the keyboard.
+//!
+//! While movement keys are held, a mouse report goes out every `interval` with a speed that ramps up
+//! along the configured acceleration curve.  Since the keys are analog, the speed can also be scaled
+//! by how far each key is pressed, so a light touch gives fine control.  Fractional movement is
+//! carried over between reports, which is what makes slow speeds usable.
+//!
+//! Like `tap_dance`, this is a pure state machine: the key depths come in through the `tick` callback.
+
+use embassy_time::{Duration, Instant};
+
+use heapless::Vec;
+
+pub const MOUSE_REPORT_ID: u8 = 3;
+/// the report ID, a button byte, and x, y, wheel and pan bytes
+pub const MOUSE_REPORT_SIZE: usize = 6;
+/// how many buttons the mouse report has, the left, right, middle, back and forward buttons
+pub const MOUSE_BUTTONS: u8 = 5;
+/// stands in for the key number when a motion doesn't come from a physical key (e.g. a tap dance)
+pub const NO_KEY: u8 = u8::MAX;
+const MAX_HELD: usize = 8;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum MouseDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl MouseDirection {
+    pub fn from_u8(direction: u8) -> Option<MouseDirection> {
+        [MouseDirection::Up, MouseDirection::Down, MouseDirection::Left, MouseDirection::Right].get(direction as usize).copied()
+    }
+}
+
+#[allow(dead_code)] // only one curve is configured at a time
+pub enum AccelCurve {
+    /// always the initial speed
+    Constant,
+    Linear,
+    /// slow to start, for finer control before speeding up
+    Quadratic,
+}
+
+pub struct MouseKeyConfig {
+    /// time between movement reports
+    pub interval: Duration,
+    /// cursor speeds, in counts per report
+    pub move_initial: f32,
+    pub move_max: f32,
+    /// wheel speeds, in detents per report
+    pub wheel_initial: f32,
+    pub wheel_max: f32,
+    /// how long a key has to be held to go from the initial to the max speed
+    pub time_to_max: Duration,
+    pub curve: AccelCurve,
+    /// whether to scale the speed of each key by how far it is pressed
+    pub depth_scaling: bool,
+    /// the depth at or below which the speed is scaled by `depth_min_scale`, rising linearly to full
+    /// speed at full depth.  Around the actuation point, since shallower keys aren't pressed at all.
+    pub depth_min: f32,
+    pub depth_min_scale: f32,
+}
+
+pub const MOUSE_KEY_CONFIG: MouseKeyConfig = MouseKeyConfig {
+    interval: Duration::from_millis(16),
+    move_initial: 1.,
+    move_max: 20.,
+    wheel_initial: 0.1,
+    wheel_max: 1.,
+    time_to_max: Duration::from_millis(1500),
+    curve: AccelCurve::Quadratic,
+    depth_scaling: true,
+    depth_min: 0.5,
+    depth_min_scale: 0.1,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    pub fn to_bytes(self) -> [u8; MOUSE_REPORT_SIZE] {
+        [MOUSE_REPORT_ID, self.buttons, self.x as u8, self.y as u8, self.wheel as u8, self.pan as u8]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HeldMotion {
+    keynumber: u8,
+    wheel: bool,
+    direction: MouseDirection,
+    depth: f32,
+}
+
+pub struct MouseKeys {
+    buttons: u8,
+    held: Vec<HeldMotion, MAX_HELD>,
+    /// when the first of the currently held motion keys went down
+    started: Instant,
+    next_tick: Instant,
+    /// the fractional x, y, wheel and pan movement not yet reported
+    remainder: [f32; 4],
+}
+
+impl MouseKeys {
+    pub const fn new() -> Self {
+        MouseKeys {
+            buttons: 0,
+            held: Vec::new(),
+            started: Instant::MIN,
+            next_tick: Instant::MIN,
+            remainder: [0.; 4],
+        }
+    }
+
+    /// when `tick` should next be called, if anything is moving
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.held.is_empty() { None } else { Some(self.next_tick) }
+    }
+
+    /// Presses or releases button n (0 is the left button) and returns the report to send.
+    pub fn button(&mut self, n: u8, pressed: bool) -> MouseReport {
+        if n >= MOUSE_BUTTONS {
+            defmt::warn!("there is no mouse button {}", n);
+        } else if pressed {
+            self.buttons |= 1 << n;
+        } else {
+            self.buttons &= !(1 << n);
+        }
+        MouseReport { buttons: self.buttons, ..Default::default() }
+    }
+
+    pub fn press_motion(&mut self, keynumber: u8, wheel: bool, direction: MouseDirection, now: Instant) {
+        if self.held.is_empty() {
+            self.started = now;
+            self.next_tick = now;
+        }
+        let motion = HeldMotion { keynumber, wheel, direction, depth: 1. };
+        if self.held.push(motion).is_err() {
+            defmt::warn!("too many mouse keys held, ignoring {}", direction);
+        }
+    }
+
+    pub fn release_motion(&mut self, keynumber: u8, wheel: bool, direction: MouseDirection) {
+        if let Some(i) = self.held.iter().position(|m| m.keynumber == keynumber && m.wheel == wheel && m.direction == direction) {
+            self.held.remove(i);
+        }
+        if self.held.is_empty() {
+            self.remainder = [0.; 4];
+        }
+    }
+
+    /// the speed multiplier from the acceleration curve, `held_for` after the motion started
+    fn acceleration(&self, held_for: Duration) -> f32 {
+        let t = (held_for.as_micros() as f32 / MOUSE_KEY_CONFIG.time_to_max.as_micros() as f32).min(1.);
+        match MOUSE_KEY_CONFIG.curve {
+            AccelCurve::Constant => 0.,
+            AccelCurve::Linear => t,
+            AccelCurve::Quadratic => t * t,
+        }
+    }
+
+    fn depth_scale(depth: f32) -> f32 {
+        if !MOUSE_KEY_CONFIG.depth_scaling {
+            return 1.;
+        }
+        let min = MOUSE_KEY_CONFIG.depth_min;
+        let fraction = ((depth - min) / (1. - min)).clamp(0., 1.);
+        MOUSE_KEY_CONFIG.depth_min_scale + (1. - MOUSE_KEY_CONFIG.depth_min_scale) * fraction
+    }
+
+    /// Returns the movement report that is due at `now`, if any.  `key_depth` gives the current depth
+    /// of a key (0 up to 1 fully pressed), or None if it isn't known - in which case the last known
+    /// depth is used.
+    pub fn tick(&mut self, now: Instant, key_depth: impl Fn(u8) -> Option<f32>) -> Option<MouseReport> {
+        if self.held.is_empty() || now < self.next_tick {
+            return None;
+        }
+        self.next_tick = (self.next_tick + MOUSE_KEY_CONFIG.interval).max(now);
+
+        let accel = self.acceleration(now - self.started);
+        let mut movement = [0f32; 4];
+        for motion in self.held.iter_mut() {
+            if motion.keynumber != NO_KEY && let Some(depth) = key_depth(motion.keynumber) {
+                motion.depth = depth;
+            }
+            let (initial, max) = if motion.wheel {
+                (MOUSE_KEY_CONFIG.wheel_initial, MOUSE_KEY_CONFIG.wheel_max)
+            } else {
+                (MOUSE_KEY_CONFIG.move_initial, MOUSE_KEY_CONFIG.move_max)
+            };
+            let speed = (initial + (max - initial) * accel) * Self::depth_scale(motion.depth);
+            // the wheel axis counts up for scrolling up, the opposite of y
+            let (axis, sign) = match (motion.wheel, motion.direction) {
+                (false, MouseDirection::Right) => (0, 1.),
+                (false, MouseDirection::Left) => (0, -1.),
+                (false, MouseDirection::Down) => (1, 1.),
+                (false, MouseDirection::Up) => (1, -1.),
+                (true, MouseDirection::Up) => (2, 1.),
+                (true, MouseDirection::Down) => (2, -1.),
+                (true, MouseDirection::Right) => (3, 1.),
+                (true, MouseDirection::Left) => (3, -1.),
+            };
+            movement[axis] += sign * speed;
+        }
+
+        let mut counts = [0i8; 4];
+        for (axis, count) in counts.iter_mut().enumerate() {
+            let total = self.remainder[axis] + movement[axis];
+            let whole = (total as i32).clamp(i8::MIN as i32 + 1, i8::MAX as i32);
+            self.remainder[axis] = total - whole as f32;
+            *count = whole as i8;
+        }
+
+        let [x, y, wheel, pan] = counts;
+        if counts == [0; 4] {
+            return None;
+        }
+        Some(MouseReport { buttons: self.buttons, x, y, wheel, pan })
+    }
+}
@@ -0,0 +1,62 @@
+//! One-shot modifiers: a modifier that is tapped rather than held applies to the next key only.
+//!
+//! Held down, a one-shot modifier is an ordinary modifier - it only "arms" if released without
+//! any other key being pressed in the meantime.  Armed modifiers are dropped if no key follows
+//! within `ONESHOT_TIMEOUT`.
+
+use embassy_time::{Duration, Instant};
+
+pub const ONESHOT_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub struct OneShotMods {
+    /// modifier bits waiting for the next key
+    armed: u8,
+    /// modifier bits of one-shot keys that are down
+    held: u8,
+    /// whether another key was pressed while a one-shot key was down
+    used_while_held: bool,
+    deadline: Instant,
+}
+
+impl OneShotMods {
+    pub const fn new() -> Self {
+        OneShotMods { armed: 0, held: 0, used_while_held: false, deadline: Instant::MIN }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.armed != 0 { Some(self.deadline) } else { None }
+    }
+
+    pub fn timeout(&mut self, now: Instant) {
+        if self.armed != 0 && now >= self.deadline {
+            defmt::debug!("one-shot modifiers {} timed out", self.armed);
+            self.armed = 0;
+        }
+    }
+
+    /// a one-shot key for modifier bit `bit` went down
+    pub fn press(&mut self, bit: u8) {
+        if self.held == 0 {
+            self.used_while_held = false;
+        }
+        self.held |= bit;
+    }
+
+    /// a one-shot key for modifier bit `bit` came up
+    pub fn release(&mut self, bit: u8, now: Instant) {
+        self.held &= !bit;
+        if !self.used_while_held {
+            self.armed |= bit;
+            self.deadline = now + ONESHOT_TIMEOUT;
+        }
+    }
+
+    /// Another (non-modifier) key went down.  Returns the armed modifiers to add to its report, and
+    /// disarms them.
+    pub fn other_key_pressed(&mut self) -> u8 {
+        if self.held != 0 {
+            self.used_while_held = true;
+        }
+        core::mem::take(&mut self.armed)
+    }
+}
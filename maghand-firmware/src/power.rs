@@ -0,0 +1,96 @@
+//! The system power state, which follows USB suspend and resume.
+//!
+//! A suspended device may only draw 2.5 mA from the bus, so while suspended the key LEDs and board
+//! LEDs are off, the IMU is powered down, and the keys are only scanned every `SUSPENDED_SCAN_PERIOD`
+//! with vhi (which also powers the key sensors) switched off in between.  A key press seen by that
+//! slow scan still goes through the key processor as usual, which asks the host for a remote wakeup
+//! and sends the key once the bus has resumed.
+//!
+//! The USB device handler sets the state here; the ADC sampler polls `is_suspended`, and the main
+//! loop waits on `POWER_CHANGED` to switch everything else.
+//!
+//! On battery there is also System OFF, the nRF52840's deepest sleep, for when the keyboard has been
+//! left alone (see `sleep`) or the battery is nearly flat (see `battery`).  Only a reset wakes the
+//! chip from it, so the firmware starts over from the top of `main`.  Going there takes two steps:
+//! `request_system_off` stops the ADC sampler, which switches vhi off and signals `SCANNING_STOPPED`,
+//! and the main loop, woken by `SYSTEM_OFF`, switches off everything else and goes in.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+/// how often the keys are scanned while suspended, which is how long a wake-up press can be missed for
+pub const SUSPENDED_SCAN_PERIOD: Duration = Duration::from_millis(50);
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+static VBUS: AtomicBool = AtomicBool::new(false);
+/// carries the new suspended state whenever it changes
+pub static POWER_CHANGED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// signalled when the bus resumes, for anything waiting on a remote wakeup
+pub static RESUMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Why the keyboard is going into System OFF, which decides what can wake it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SystemOff {
+    /// the keyboard has been left alone, so a bump or a tap picked up by the IMU wakes it
+    Sleep,
+    /// the battery is nearly flat, so only plugging in USB wakes it
+    CriticalBattery,
+}
+
+static SYSTEM_OFF_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// carries the reason when System OFF is requested
+pub static SYSTEM_OFF: Signal<CriticalSectionRawMutex, SystemOff> = Signal::new();
+/// signalled once the ADC sampler has stopped for System OFF, leaving vhi off
+pub static SCANNING_STOPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Acquire)
+}
+
+pub fn set_suspended(suspended: bool) {
+    let previous = SUSPENDED.swap(suspended, Ordering::AcqRel);
+    if previous != suspended {
+        defmt::info!("{}", if suspended { "suspending" } else { "resuming" });
+        POWER_CHANGED.signal(suspended);
+    }
+    if !suspended {
+        RESUMED.signal(());
+    }
+}
+
+/// whether USB power is plugged in
+pub fn vbus_present() -> bool {
+    VBUS.load(Ordering::Relaxed)
+}
+
+/// Called with whether VBUS is present at startup and whenever it changes.
+pub fn set_vbus(present: bool) {
+    VBUS.store(present, Ordering::Relaxed);
+}
+
+/// Starts going into System OFF.  Only the first request counts.
+pub fn request_system_off(reason: SystemOff) {
+    if !SYSTEM_OFF_REQUESTED.swap(true, Ordering::AcqRel) {
+        defmt::info!("going into System OFF for {}", reason);
+        SYSTEM_OFF.signal(reason);
+    }
+}
+
+pub fn system_off_requested() -> bool {
+    SYSTEM_OFF_REQUESTED.load(Ordering::Acquire)
+}
+
+/// Whether this start was a wake from System OFF, clearing the reset reasons for next time.
+pub fn take_woke_from_system_off() -> bool {
+    let power = embassy_nrf::pac::POWER;
+    let reasons = power.resetreas().read();
+    // the reasons accumulate until cleared by writing ones to them
+    power.resetreas().write_value(reasons);
+    if reasons.off() || reasons.vbus() {
+        defmt::info!("woke from System OFF by {}", if reasons.off() { "the IMU" } else { "USB" });
+    }
+    reasons.off() || reasons.vbus()
+}
@@ -0,0 +1,197 @@
+//! The vendor-defined raw HID interface, for configurator tools that need no driver.  The commands
+//! are in `raw_hid_protocol`; this carries them out, and builds the analog stream reports.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_time::Duration;
+
+use crate::hardware_consts::{Hand, ALL_KEY_NAMES, HAND, HARDWARE_REVISION, KEY_NAMES, N_KEYS};
+use crate::identity::IDENTITY;
+use crate::key_processor;
+use crate::keys::{keymap_get, keymap_set, Action, KeySettings, Layer, N_LAYERS, NO_ACTION_ENCODED};
+use crate::raw_hid_protocol::*;
+use crate::bootloader;
+use crate::sleep;
+use crate::storage;
+use crate::{KEYS_MUTEX_LAZY, KEY_INDEX_MAP};
+
+/// keys pressed less than this are left out of analog stream reports, so sensor noise on keys at
+/// rest doesn't make every report different
+const ANALOG_DEAD_ZONE: f32 = 0.02;
+
+/// the analog stream period in ms, 0 when it's off
+static ANALOG_STREAM_PERIOD_MS: AtomicU8 = AtomicU8::new(0);
+
+/// 64-byte input and output reports on a vendor usage page ("M" for maghand)
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x4D, 0xFF,  // Usage Page (Vendor 0xFF4D)
+    0x09, 0x01,        // Usage (1)
+    0xA1, 0x01,        // Collection (Application)
+    0x09, 0x02,        //   Usage (2)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, REPORT_SIZE as u8, // Report Count
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0x09, 0x03,        //   Usage (3)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, REPORT_SIZE as u8, // Report Count
+    0x91, 0x02,        //   Output (Data, Variable, Absolute)
+    0xC0,              // End Collection
+];
+
+/// how often to send analog stream reports, if the host asked for them
+pub fn analog_stream_period() -> Option<Duration> {
+    match ANALOG_STREAM_PERIOD_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// The current analog stream report: the depth of every pressed key, by the usage it types.
+pub async fn analog_report() -> [u8; REPORT_SIZE] {
+    let mut response = Response::new(CMD_ANALOG_REPORT, Status::Ok);
+    let keys = KEYS_MUTEX_LAZY.get().lock().await;
+    let entries = keys.iter().filter_map(|key| {
+        let Some(Action::Key(usage)) = keymap_get(key.keynumber, Layer::Default) else { return None };
+        let depth = key.depth().filter(|depth| *depth >= ANALOG_DEAD_ZONE)?;
+        let mut entry = [0u8; ANALOG_ENTRY_SIZE];
+        entry[..2].copy_from_slice(&(usage as u16).to_be_bytes());
+        entry[2] = (depth.min(1.) * 255.) as u8;
+        Some(entry)
+    });
+    // if more keys are down than fit, the last ones are left out
+    for entry in entries.take(MAX_ANALOG_ENTRIES) {
+        response.push(&entry).ok();
+    }
+    response.encode()
+}
+
+fn known_key(keynumber: u8) -> Result<u8, Status> {
+    if KEY_NAMES.contains(&keynumber) { Ok(keynumber) } else { Err(Status::InvalidArgument) }
+}
+
+/// like `known_key`, but also taking the other half's keys on the central half of a split keyboard
+fn keymap_key(keynumber: u8) -> Result<u8, Status> {
+    if ALL_KEY_NAMES.contains(&keynumber) { Ok(keynumber) } else { Err(Status::InvalidArgument) }
+}
+
+fn layer(index: u8) -> Result<Layer, Status> {
+    Layer::from_index(index).ok_or(Status::InvalidArgument)
+}
+
+/// Carries out the request in an output report, and returns the input report to answer with.
+pub async fn handle(report: &[u8]) -> [u8; REPORT_SIZE] {
+    let command = report.first().copied().unwrap_or(0);
+    let response = match Request::decode(report) {
+        Ok(request) => {
+            let mut response = Response::new(command, Status::Ok);
+            if let Err(status) = execute(request, &mut response).await {
+                defmt::warn!("raw hid request {} failed: {}", request, status);
+                response = Response::new(command, status);
+            }
+            response
+        }
+        Err(status) => {
+            defmt::warn!("raw hid command {} not understood", command);
+            Response::new(command, status)
+        }
+    };
+    response.encode()
+}
+
+async fn execute(request: Request, response: &mut Response) -> Result<(), Status> {
+    let keys_mutex = KEYS_MUTEX_LAZY.get();
+    let key_index_map = KEY_INDEX_MAP.get();
+    match request {
+        Request::GetInfo => {
+            let version = |part: &str| part.parse().unwrap_or(0);
+            response.push(&[
+                PROTOCOL_VERSION,
+                version(env!("CARGO_PKG_VERSION_MAJOR")),
+                version(env!("CARGO_PKG_VERSION_MINOR")),
+                version(env!("CARGO_PKG_VERSION_PATCH")),
+                N_KEYS as u8,
+                N_LAYERS as u8,
+            ])?;
+            response.push(&KEY_NAMES)?;
+        }
+        Request::GetKeymapEntry { layer: index, keynumber } => {
+            let action = keymap_get(keymap_key(keynumber)?, layer(index)?);
+            response.push(&action.map_or(NO_ACTION_ENCODED, |a| a.encode()))?;
+        }
+        Request::SetKeymapEntry { layer: index, keynumber, action: encoded } => {
+            let action = Action::decode(encoded);
+            if action.is_none() && encoded != NO_ACTION_ENCODED {
+                return Err(Status::InvalidArgument);
+            }
+            keymap_set(keymap_key(keynumber)?, layer(index)?, action);
+        }
+        Request::GetKeySettings { keynumber } => {
+            let index = key_index_map[&known_key(keynumber)?];
+            let settings = keys_mutex.lock().await[index].settings();
+            response.push(&settings.encode())?;
+        }
+        Request::SetKeySettings { keynumber, settings } => {
+            let index = key_index_map[&known_key(keynumber)?];
+            let settings = KeySettings::decode(&settings).ok_or(Status::InvalidArgument)?;
+            keys_mutex.lock().await[index].set_settings(settings);
+        }
+        Request::GetAnalogValues { first_index } => {
+            let keys = keys_mutex.lock().await;
+            let values = keys.get(first_index as usize..).ok_or(Status::InvalidArgument)?;
+            let count = values.len().min(MAX_ANALOG_VALUES);
+            response.push(&[count as u8])?;
+            for key in &values[..count] {
+                let [lo, hi] = (key.value.unwrap_or(0.) as u16).to_le_bytes();
+                let depth = key.depth().map_or(DEPTH_UNKNOWN, |d| (d.clamp(0., 1.) * 254.) as u8);
+                response.push(&[key.keynumber, lo, hi, depth])?;
+            }
+        }
+        Request::Calibrate { keynumber } => {
+            if let Some(keynumber) = keynumber {
+                known_key(keynumber)?;
+            }
+            let mut keys = keys_mutex.lock().await;
+            for key in keys.iter_mut().filter(|k| keynumber.is_none_or(|n| k.keynumber == n)) {
+                key.reset_calibration();
+            }
+        }
+        Request::Save => {
+            storage::save().await.map_err(|e| {
+                defmt::warn!("saving settings failed: {}", e);
+                Status::Failed
+            })?;
+        }
+        Request::GetDeviceId => {
+            response.push(&IDENTITY.get().device_id.to_le_bytes())?;
+            response.push(&[(HAND == Hand::Right) as u8, HARDWARE_REVISION])?;
+        }
+        Request::Bootloader => bootloader::request(),
+        Request::AnalogStream { period_ms } => {
+            defmt::info!("analog stream period set to {} ms", period_ms);
+            ANALOG_STREAM_PERIOD_MS.store(period_ms, Ordering::Relaxed);
+        }
+        Request::GetSetting { setting } => {
+            let value = match setting {
+                SETTING_STUCK_KEY_TIMEOUT => key_processor::stuck_key_timeout_ms(),
+                SETTING_SLEEP_TIMEOUT => sleep::sleep_timeout_ms(),
+                _ => return Err(Status::InvalidArgument),
+            };
+            response.push(&value.to_le_bytes())?;
+        }
+        Request::SetSetting { setting, value } => {
+            let taken = match setting {
+                SETTING_STUCK_KEY_TIMEOUT => key_processor::set_stuck_key_timeout_ms(value),
+                SETTING_SLEEP_TIMEOUT => sleep::set_sleep_timeout_ms(value),
+                _ => false,
+            };
+            if !taken {
+                return Err(Status::InvalidArgument);
+            }
+        }
+    }
+    Ok(())
+}
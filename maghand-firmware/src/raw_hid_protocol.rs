@@ -0,0 +1,333 @@
+//! The configuration and telemetry protocol spoken over the raw HID interface (see `raw_hid`).
+//!
+//! Every report is `REPORT_SIZE` bytes in both directions, zero padded.  The host sends a command
+//! byte followed by its arguments; the device answers each one with a report starting with the same
+//! command byte and a `Status`, followed by the reply.  Multi-byte values are little-endian.
+//!
+//! | command | arguments | reply |
+//! |---------|-----------|-------|
+//! | 0x01 get info | - | protocol version, firmware major, minor, patch, key count n, layer count, n key numbers |
+//! | 0x02 get keymap entry | layer, key number | action (3 bytes) |
+//! | 0x03 set keymap entry | layer, key number, action (3 bytes) | - |
+//! | 0x04 get key settings | key number | switch point, hysteresis, filter alpha, valid range (f32 each) |
+//! | 0x05 set key settings | key number, the 4 f32s as above | - |
+//! | 0x06 get analog values | first key index | count n, then n × (key number, smoothed ADC value u16, depth u8) |
+//! | 0x07 calibrate | key number, or 0xFF for all keys | - |
+//! | 0x08 save | - | - |
+//! | 0x09 get device id | - | device ID (u64, also the USB serial number in hex), hand (0 left, 1 right), hardware revision |
+//! | 0x0A bootloader | - | - (the keyboard then reboots into its UF2 bootloader) |
+//! | 0x0B analog stream | period in ms, or 0 to stop | - |
+//! | 0x0D get setting | setting | value (u32) |
+//! | 0x0E set setting | setting, value (u32) | - |
+//!
+//! Actions are a kind byte and a u16 parameter, as in `Action::encode` - kind 0 unmaps the key.
+//! On the central half of a split keyboard the keymap commands also take the other half's keys,
+//! numbered `REMOTE_KEY_OFFSET` above its own, though the info reply only lists this half's.
+//! Depths run from 0 (up) to 254 (fully pressed), with 255 meaning the key isn't calibrated yet.
+//! Calibrating forgets a key's min and max, so it should then be pressed all the way once.  Settings
+//! are the keyboard-wide ones, by `SETTING_*` number: 0x01 is the stuck key timeout and 0x02 the
+//! sleep timeout, both in ms and 0 for none.  Changes take effect immediately but are lost on reset
+//! unless saved.
+//!
+//! The protocol version goes up whenever a command changes in a way old hosts would misread; new
+//! commands alone don't change it, since old hosts just won't send them.
+//!
+//! # Analog stream
+//!
+//! While an analog stream is on, the keyboard also sends reports nobody asked for, laid out like a
+//! response to command 0x0C with status 0: up to `MAX_ANALOG_ENTRIES` entries of (HID keyboard
+//! usage u16 big-endian, depth u8), zero padded.  Those are the (code, value) triplets of Wooting's
+//! own analog reports, so a Wooting Analog SDK plugin for this keyboard only has to send the 0x0B
+//! request, match the command byte and skip the status byte.  It finds the keyboard by its USB
+//! vendor and product IDs and the 0xFF4D usage page.
+//!
+//! - Each key is keyed by the usage it types on the default layer; keys that don't type a usage are
+//!   left out.
+//! - Depth runs from 0 (up) to 255 (fully pressed).  Only keys pressed past a small dead zone are
+//!   listed, so a key that is missing from a report is up, and an empty report means all of them are.
+//! - Reports go out at most once per period, and only when something changed (or after a request,
+//!   so a new reader always gets a full picture).
+//! - Command responses can come in between stream reports, so hosts should go by the command byte.
+
+use heapless::Vec;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const REPORT_SIZE: usize = 64;
+/// room for a reply after the command and status bytes
+pub const MAX_REPLY_SIZE: usize = REPORT_SIZE - 2;
+pub const ALL_KEYS: u8 = 0xFF;
+pub const DEPTH_UNKNOWN: u8 = 0xFF;
+/// bytes per key in a get analog values reply
+pub const ANALOG_VALUE_SIZE: usize = 4;
+pub const MAX_ANALOG_VALUES: usize = (MAX_REPLY_SIZE - 1) / ANALOG_VALUE_SIZE;
+/// bytes per key in an analog stream report
+pub const ANALOG_ENTRY_SIZE: usize = 3;
+pub const MAX_ANALOG_ENTRIES: usize = MAX_REPLY_SIZE / ANALOG_ENTRY_SIZE;
+
+pub const CMD_GET_INFO: u8 = 0x01;
+pub const CMD_GET_KEYMAP_ENTRY: u8 = 0x02;
+pub const CMD_SET_KEYMAP_ENTRY: u8 = 0x03;
+pub const CMD_GET_KEY_SETTINGS: u8 = 0x04;
+pub const CMD_SET_KEY_SETTINGS: u8 = 0x05;
+pub const CMD_GET_ANALOG_VALUES: u8 = 0x06;
+pub const CMD_CALIBRATE: u8 = 0x07;
+pub const CMD_SAVE: u8 = 0x08;
+pub const CMD_GET_DEVICE_ID: u8 = 0x09;
+pub const CMD_BOOTLOADER: u8 = 0x0A;
+pub const CMD_ANALOG_STREAM: u8 = 0x0B;
+/// only ever sent by the keyboard, for analog stream reports
+pub const CMD_ANALOG_REPORT: u8 = 0x0C;
+pub const CMD_GET_SETTING: u8 = 0x0D;
+pub const CMD_SET_SETTING: u8 = 0x0E;
+
+pub const SETTING_STUCK_KEY_TIMEOUT: u8 = 0x01;
+pub const SETTING_SLEEP_TIMEOUT: u8 = 0x02;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    InvalidArgument = 2,
+    Failed = 3,
+}
+
+impl Status {
+    pub fn from_u8(status: u8) -> Option<Status> {
+        [Status::Ok, Status::UnknownCommand, Status::InvalidArgument, Status::Failed].get(status as usize).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Request {
+    GetInfo,
+    GetKeymapEntry { layer: u8, keynumber: u8 },
+    SetKeymapEntry { layer: u8, keynumber: u8, action: [u8; 3] },
+    GetKeySettings { keynumber: u8 },
+    SetKeySettings { keynumber: u8, settings: [u8; 16] },
+    GetAnalogValues { first_index: u8 },
+    /// None for all keys
+    Calibrate { keynumber: Option<u8> },
+    Save,
+    GetDeviceId,
+    Bootloader,
+    /// 0 stops the stream
+    AnalogStream { period_ms: u8 },
+    GetSetting { setting: u8 },
+    SetSetting { setting: u8, value: u32 },
+}
+
+fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0u8; N];
+    array.copy_from_slice(&bytes[..N]);
+    array
+}
+
+impl Request {
+    /// Reads a request from an output report.  Reports shorter than `REPORT_SIZE` are treated as if
+    /// zero padded.
+    pub fn decode(report: &[u8]) -> Result<Request, Status> {
+        let mut padded = [0u8; REPORT_SIZE];
+        let len = report.len().min(REPORT_SIZE);
+        padded[..len].copy_from_slice(&report[..len]);
+        let args = &padded[1..];
+
+        let request = match padded[0] {
+            CMD_GET_INFO => Request::GetInfo,
+            CMD_GET_KEYMAP_ENTRY => Request::GetKeymapEntry { layer: args[0], keynumber: args[1] },
+            CMD_SET_KEYMAP_ENTRY => Request::SetKeymapEntry { layer: args[0], keynumber: args[1], action: array(&args[2..]) },
+            CMD_GET_KEY_SETTINGS => Request::GetKeySettings { keynumber: args[0] },
+            CMD_SET_KEY_SETTINGS => Request::SetKeySettings { keynumber: args[0], settings: array(&args[1..]) },
+            CMD_GET_ANALOG_VALUES => Request::GetAnalogValues { first_index: args[0] },
+            CMD_CALIBRATE => Request::Calibrate { keynumber: (args[0] != ALL_KEYS).then_some(args[0]) },
+            CMD_SAVE => Request::Save,
+            CMD_GET_DEVICE_ID => Request::GetDeviceId,
+            CMD_BOOTLOADER => Request::Bootloader,
+            CMD_ANALOG_STREAM => Request::AnalogStream { period_ms: args[0] },
+            CMD_GET_SETTING => Request::GetSetting { setting: args[0] },
+            CMD_SET_SETTING => Request::SetSetting { setting: args[0], value: u32::from_le_bytes(array(&args[1..])) },
+            _ => return Err(Status::UnknownCommand),
+        };
+        Ok(request)
+    }
+
+    pub fn command(&self) -> u8 {
+        match self {
+            Request::GetInfo => CMD_GET_INFO,
+            Request::GetKeymapEntry { .. } => CMD_GET_KEYMAP_ENTRY,
+            Request::SetKeymapEntry { .. } => CMD_SET_KEYMAP_ENTRY,
+            Request::GetKeySettings { .. } => CMD_GET_KEY_SETTINGS,
+            Request::SetKeySettings { .. } => CMD_SET_KEY_SETTINGS,
+            Request::GetAnalogValues { .. } => CMD_GET_ANALOG_VALUES,
+            Request::Calibrate { .. } => CMD_CALIBRATE,
+            Request::Save => CMD_SAVE,
+            Request::GetDeviceId => CMD_GET_DEVICE_ID,
+            Request::Bootloader => CMD_BOOTLOADER,
+            Request::AnalogStream { .. } => CMD_ANALOG_STREAM,
+            Request::GetSetting { .. } => CMD_GET_SETTING,
+            Request::SetSetting { .. } => CMD_SET_SETTING,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0u8; REPORT_SIZE];
+        report[0] = self.command();
+        let args = &mut report[1..];
+        match *self {
+            Request::GetInfo | Request::Save | Request::GetDeviceId | Request::Bootloader => {}
+            Request::GetKeymapEntry { layer, keynumber } => args[..2].copy_from_slice(&[layer, keynumber]),
+            Request::SetKeymapEntry { layer, keynumber, action } => {
+                args[..2].copy_from_slice(&[layer, keynumber]);
+                args[2..5].copy_from_slice(&action);
+            }
+            Request::GetKeySettings { keynumber } => args[0] = keynumber,
+            Request::SetKeySettings { keynumber, settings } => {
+                args[0] = keynumber;
+                args[1..17].copy_from_slice(&settings);
+            }
+            Request::GetAnalogValues { first_index } => args[0] = first_index,
+            Request::Calibrate { keynumber } => args[0] = keynumber.unwrap_or(ALL_KEYS),
+            Request::AnalogStream { period_ms } => args[0] = period_ms,
+            Request::GetSetting { setting } => args[0] = setting,
+            Request::SetSetting { setting, value } => {
+                args[0] = setting;
+                args[1..5].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        report
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub command: u8,
+    pub status: Status,
+    pub reply: Vec<u8, MAX_REPLY_SIZE>,
+}
+
+impl Response {
+    pub fn new(command: u8, status: Status) -> Self {
+        Response { command, status, reply: Vec::new() }
+    }
+
+    /// Appends to the reply, failing if it would no longer fit in a report.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), Status> {
+        self.reply.extend_from_slice(bytes).map_err(|_| Status::Failed)
+    }
+
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0u8; REPORT_SIZE];
+        report[0] = self.command;
+        report[1] = self.status as u8;
+        report[2..2 + self.reply.len()].copy_from_slice(&self.reply);
+        report
+    }
+
+    /// Reads a response from an input report.  The reply is the rest of the report, padding and
+    /// all, since only the command knows how long it is.
+    pub fn decode(report: &[u8]) -> Option<Response> {
+        let (&command, rest) = report.split_first()?;
+        let (&status, reply) = rest.split_first()?;
+        Some(Response {
+            command,
+            status: Status::from_u8(status)?,
+            reply: Vec::from_slice(&reply[..reply.len().min(MAX_REPLY_SIZE)]).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_STATUSES: [Status; 4] = [Status::Ok, Status::UnknownCommand, Status::InvalidArgument, Status::Failed];
+
+    fn every_request() -> [Request; 16] {
+        [
+            Request::GetInfo,
+            Request::GetKeymapEntry { layer: 2, keynumber: 31 },
+            Request::SetKeymapEntry { layer: 1, keynumber: 44, action: [6, 1, 0] },
+            Request::GetKeySettings { keynumber: 12 },
+            Request::SetKeySettings { keynumber: 12, settings: core::array::from_fn(|i| i as u8 + 1) },
+            Request::GetAnalogValues { first_index: 15 },
+            Request::Calibrate { keynumber: Some(23) },
+            Request::Calibrate { keynumber: None },
+            Request::Save,
+            Request::GetDeviceId,
+            Request::Bootloader,
+            Request::AnalogStream { period_ms: 10 },
+            Request::AnalogStream { period_ms: 0 },
+            Request::GetSetting { setting: SETTING_STUCK_KEY_TIMEOUT },
+            Request::SetSetting { setting: SETTING_STUCK_KEY_TIMEOUT, value: 0x0102_0304 },
+            Request::SetSetting { setting: SETTING_SLEEP_TIMEOUT, value: 0 },
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in every_request() {
+            let report = request.encode();
+            assert_eq!(report[0], request.command());
+            assert_eq!(Request::decode(&report), Ok(request));
+        }
+    }
+
+    #[test]
+    fn every_command_has_a_request() {
+        let commands: Vec<u8, 16> = every_request().iter().map(Request::command).collect();
+        for command in (CMD_GET_INFO..=CMD_ANALOG_STREAM).chain(CMD_GET_SETTING..=CMD_SET_SETTING) {
+            assert!(commands.contains(&command), "no request for command {command:#04x}");
+        }
+    }
+
+    #[test]
+    fn short_requests_are_zero_padded() {
+        assert_eq!(Request::decode(&[CMD_GET_KEYMAP_ENTRY, 1]), Ok(Request::GetKeymapEntry { layer: 1, keynumber: 0 }));
+        assert_eq!(Request::decode(&[CMD_CALIBRATE]), Ok(Request::Calibrate { keynumber: Some(0) }));
+        assert_eq!(Request::decode(&[]), Err(Status::UnknownCommand));
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        for command in [0x00, CMD_ANALOG_REPORT, 0x0F, 0xFF] {
+            assert_eq!(Request::decode(&[command]), Err(Status::UnknownCommand));
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for request in every_request() {
+            for status in ALL_STATUSES {
+                let mut response = Response::new(request.command(), status);
+                if status == Status::Ok {
+                    response.push(&[0xAB; 5]).unwrap();
+                }
+                let report = response.encode();
+                let decoded = Response::decode(&report).unwrap();
+                assert_eq!(decoded.command, response.command);
+                assert_eq!(decoded.status, status);
+                // the reply comes back padded out to the end of the report
+                assert_eq!(decoded.reply.len(), MAX_REPLY_SIZE);
+                assert_eq!(&decoded.reply[..response.reply.len()], &response.reply[..]);
+                assert!(decoded.reply[response.reply.len()..].iter().all(|b| *b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn statuses_round_trip() {
+        for status in ALL_STATUSES {
+            assert_eq!(Status::from_u8(status as u8), Some(status));
+        }
+        assert_eq!(Status::from_u8(ALL_STATUSES.len() as u8), None);
+        assert_eq!(Response::decode(&[CMD_SAVE, 4]), None);
+    }
+
+    #[test]
+    fn replies_are_limited_to_a_report() {
+        let mut response = Response::new(CMD_GET_ANALOG_VALUES, Status::Ok);
+        response.push(&[1; MAX_REPLY_SIZE]).unwrap();
+        assert_eq!(response.push(&[1]), Err(Status::Failed));
+        assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+    }
+}
@@ -0,0 +1,254 @@
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::extra_keys::{EXTRA_REPORT_DESCRIPTOR, EXTRA_REPORT_SIZE};
+use crate::lamp_array::LAMP_ARRAY_REPORT_DESCRIPTOR;
+use crate::midi::NoteChange;
+use crate::mouse_keys::MouseReport;
+
+const FIRST_MODIFIER: u8 = KeyboardUsage::KeyboardLeftControl as u8;
+const LAST_MODIFIER: u8 = KeyboardUsage::KeyboardRightGUI as u8;
+
+/// usages 0..NKRO_KEYS each get a bit in the NKRO report - everything a keyboard array report can carry
+const NKRO_KEYS: usize = 0xE0;
+/// after the `extra_keys` report IDs, which share its interface
+pub const NKRO_REPORT_ID: u8 = 4;
+pub const NKRO_REPORT_SIZE: usize = 2 + NKRO_KEYS / 8;
+/// the standard boot keyboard report: modifiers, a reserved byte and 6 keycodes
+pub const BOOT_REPORT_SIZE: usize = 8;
+
+/// An n-key rollover keyboard: the modifier byte followed by one bit per usage, with the same LED
+/// output report as the boot keyboard (but both behind a report ID).
+pub const NKRO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop)
+    0x09, 0x06,        // Usage (Keyboard)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, NKRO_REPORT_ID, // Report ID
+    0x05, 0x07,        //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,        //   Usage Minimum (Left Control)
+    0x29, 0xE7,        //   Usage Maximum (Right GUI)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x08,        //   Report Count (8)
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0x05, 0x08,        //   Usage Page (LEDs)
+    0x19, 0x01,        //   Usage Minimum (Num Lock)
+    0x29, 0x05,        //   Usage Maximum (Kana)
+    0x95, 0x05,        //   Report Count (5)
+    0x91, 0x02,        //   Output (Data, Variable, Absolute)
+    0x95, 0x01,        //   Report Count (1)
+    0x75, 0x03,        //   Report Size (3)
+    0x91, 0x01,        //   Output (Constant)
+    0x05, 0x07,        //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,        //   Usage Minimum (0)
+    0x29, (NKRO_KEYS - 1) as u8, // Usage Maximum
+    0x75, 0x01,        //   Report Size (1)
+    0x95, NKRO_KEYS as u8, //   Report Count
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0xC0,              // End Collection
+];
+
+/// The report protocol keyboard interface: the n-key rollover keyboard, the `extra_keys` reports and
+/// the `lamp_array`.  They share one interface because the nRF52840 only has seven IN endpoints to
+/// go round.
+pub const KEYBOARD_REPORT_DESCRIPTOR: [u8; NKRO_REPORT_DESCRIPTOR.len() + EXTRA_REPORT_DESCRIPTOR.len() + LAMP_ARRAY_REPORT_DESCRIPTOR.len()] =
+    concat(&[NKRO_REPORT_DESCRIPTOR, EXTRA_REPORT_DESCRIPTOR, LAMP_ARRAY_REPORT_DESCRIPTOR]);
+/// the largest report on the keyboard interface
+pub const KEYBOARD_REPORT_SIZE: usize = NKRO_REPORT_SIZE;
+const _: () = assert!(EXTRA_REPORT_SIZE <= KEYBOARD_REPORT_SIZE);
+
+const fn concat<const N: usize>(parts: &[&[u8]]) -> [u8; N] {
+    let mut joined = [0u8; N];
+    let (mut part, mut i, mut j) = (0, 0, 0);
+    while part < parts.len() {
+        if i < parts[part].len() {
+            joined[j] = parts[part][i];
+            i += 1;
+            j += 1;
+        } else {
+            part += 1;
+            i = 0;
+        }
+    }
+    assert!(j == N, "the parts don't add up to the descriptor length");
+    joined
+}
+
+/// the bit for a modifier usage in the report modifier byte, if it is one
+pub fn modifier_bit(usage: u8) -> Option<u8> {
+    (FIRST_MODIFIER..=LAST_MODIFIER).contains(&usage).then(|| 1 << (usage - FIRST_MODIFIER))
+}
+
+/// A single change to the held keyboard usages.  Changes that come in together are batched into
+/// one report where that doesn't lose any of them - see `KeyboardState::must_send_before`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UsageEvent {
+    Press(u8),
+    Release(u8),
+    /// a press whose report also carries extra ("weak") modifiers, which are dropped again on the
+    /// next event.  Used for one-shot modifiers and caps word.
+    PressWeak { usage: u8, modifier: u8 },
+    /// consumer and system control usages, which go in their own reports - see `extra_keys`
+    ConsumerPress(u16),
+    ConsumerRelease(u16),
+    SystemPress(u8),
+    SystemRelease(u8),
+    /// a complete mouse report - see `mouse_keys`
+    Mouse(MouseReport),
+    /// a MIDI note starting or stopping - see `midi`
+    Note(NoteChange),
+}
+
+impl UsageEvent {
+    /// whether the event changes the keyboard report, rather than one of the `extra_keys` reports or MIDI
+    pub fn is_keyboard(&self) -> bool {
+        matches!(self, UsageEvent::Press(_) | UsageEvent::Release(_) | UsageEvent::PressWeak { .. })
+    }
+}
+
+/// The set of keyboard usages currently held down, from which HID reports are built.
+///
+/// Each usage carries a press count rather than a flag, so that a usage held by more than one source
+/// (e.g. a physical key and a macro) stays down until all of them have released it.
+///
+/// It also remembers what the host was last sent, so that several changes can go out in one report
+/// without a press and release in between reports cancelling out.
+#[derive(Debug)]
+pub struct KeyboardState {
+    counts: [u8; 256],
+    weak_modifier: u8,
+    /// one bit per usage that was down in the last report sent
+    reported: [u32; 8],
+    /// whether anything changed since the last report sent
+    dirty: bool,
+}
+
+impl KeyboardState {
+    pub const fn new() -> Self {
+        KeyboardState { counts: [0; 256], weak_modifier: 0, reported: [0; 8], dirty: false }
+    }
+
+    fn was_reported_down(&self, usage: u8) -> bool {
+        self.reported[usage as usize / 32] & (1 << (usage % 32)) != 0
+    }
+
+    /// whether there are changes the host hasn't been sent yet
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Whether the changes so far have to be sent before `event` is applied, because applying it
+    /// would undo one of them before the host saw it (e.g. a tap within one report interval), or
+    /// because weak modifiers would leak onto other keys.
+    pub fn must_send_before(&self, event: UsageEvent) -> bool {
+        if !self.dirty {
+            return false;
+        }
+        // weak modifiers only belong in the report of the press they came with
+        if self.weak_modifier != 0 {
+            return true;
+        }
+        match event {
+            UsageEvent::PressWeak { .. } => true,
+            UsageEvent::Press(usage) => !self.is_down(usage) && self.was_reported_down(usage),
+            UsageEvent::Release(usage) => self.counts[usage as usize] == 1 && !self.was_reported_down(usage),
+            _ => false,
+        }
+    }
+
+    /// Makes the next report go out even if nothing changed, and treats every usage as new to the
+    /// host, for when the host may have lost track.
+    pub fn resend(&mut self) {
+        self.reported = [0; 8];
+        self.dirty = true;
+    }
+
+    /// Records that a report of the current state has been sent.
+    pub fn mark_sent(&mut self) {
+        self.reported = [0; 8];
+        for (usage, count) in self.counts.iter().enumerate() {
+            if *count > 0 {
+                self.reported[usage / 32] |= 1 << (usage % 32);
+            }
+        }
+        self.dirty = false;
+    }
+
+    pub fn press(&mut self, usage: u8) {
+        self.counts[usage as usize] = self.counts[usage as usize].saturating_add(1);
+    }
+
+    /// Returns false if the usage was not down
+    pub fn release(&mut self, usage: u8) -> bool {
+        let count = &mut self.counts[usage as usize];
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    pub fn apply(&mut self, event: UsageEvent) {
+        self.dirty = true;
+        self.weak_modifier = 0;
+        match event {
+            UsageEvent::Press(usage) => self.press(usage),
+            UsageEvent::PressWeak { usage, modifier } => {
+                self.weak_modifier = modifier;
+                self.press(usage);
+            }
+            UsageEvent::Release(usage) => {
+                if !self.release(usage) {
+                    defmt::warn!("On keyup, {} wasnt down", usage);
+                }
+            }
+            _ => defmt::warn!("{} is not a keyboard event", event),
+        }
+    }
+
+    pub fn is_down(&self, usage: u8) -> bool {
+        self.counts[usage as usize] > 0
+    }
+
+    /// the modifier byte of a keyboard report, built from the 0xE0-0xE7 usages
+    pub fn modifier(&self) -> u8 {
+        let mut modifier = self.weak_modifier;
+        for usage in FIRST_MODIFIER..=LAST_MODIFIER {
+            if self.is_down(usage) {
+                modifier |= 1 << (usage - FIRST_MODIFIER);
+            }
+        }
+        modifier
+    }
+
+    /// iterates over the held usages that go in the keycodes array (i.e. everything but modifiers)
+    pub fn keycodes(&self) -> impl Iterator<Item = u8> + '_ {
+        (1..FIRST_MODIFIER).filter(|usage| self.is_down(*usage))
+    }
+
+    /// the n-key rollover report, which never needs to report a rollover error
+    pub fn nkro_report(&self) -> [u8; NKRO_REPORT_SIZE] {
+        let mut report = [0u8; NKRO_REPORT_SIZE];
+        report[0] = NKRO_REPORT_ID;
+        report[1] = self.modifier();
+        for usage in self.keycodes().filter(|usage| (*usage as usize) < NKRO_KEYS) {
+            report[2 + usage as usize / 8] |= 1 << (usage % 8);
+        }
+        report
+    }
+
+    /// the 6-key boot protocol report, as laid out by usbd_hid's `KeyboardReport`
+    pub fn boot_report(&self) -> [u8; BOOT_REPORT_SIZE] {
+        let mut report = [0u8; BOOT_REPORT_SIZE];
+        report[0] = self.modifier();
+        let keycodes = &mut report[2..];
+        if self.keycodes().count() > keycodes.len() {
+            keycodes.fill(KeyboardUsage::KeyboardErrorRollOver as u8);
+        } else {
+            for (slot, kc) in keycodes.iter_mut().zip(self.keycodes()) {
+                *slot = kc;
+            }
+        }
+        report
+    }
+}
@@ -0,0 +1,407 @@
+//! A line-based command shell on the USB CDC-ACM serial port, for debugging a board without a probe.
+//!
+//! Type `help` for the commands.  `stream` prints one line of comma-separated key values per period,
+//! in `KEY_NAMES` order, which is what `realtime_mag.py` plots.  Settings changed here only last
+//! until the next reset, unless they are saved.
+
+use core::fmt::Write;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::{Driver, EndpointError};
+
+use heapless::String;
+
+use crate::hardware_consts::{KEY_NAMES, N_KEYS};
+use crate::battery;
+use crate::bootloader;
+use crate::host_leds::host_leds;
+use crate::identity::IDENTITY;
+use crate::key_processor;
+use crate::keys::KeySettings;
+use crate::sleep;
+use crate::storage;
+use crate::{KEYS_MUTEX_LAZY, KEY_INDEX_MAP};
+
+const LINE_LEN: usize = 64;
+/// long enough for a stream line of every key: a raw value is at most "4096.000," (the volts, norm
+/// and depth values are shorter), and a few more for a sign or a stray larger value, then the CRLF
+const STREAM_LINE_LEN: usize = 10 * N_KEYS + 2;
+/// long enough for the `keys` table
+const OUTPUT_LEN: usize = 64 * N_KEYS;
+const DEFAULT_STREAM_PERIOD: Duration = Duration::from_millis(20);
+const MIN_STREAM_PERIOD: Duration = Duration::from_millis(5);
+/// the ADC reads VDD/4 through a 1/4 gain, so full scale (12 bits) is the 3.3 V supply
+const ADC_VOLTS_PER_COUNT: f32 = 3.3 / 4096.;
+const CTRL_C: u8 = 0x03;
+
+const HELP: &str = "\
+commands:\r
+  help                        this text\r
+  version                     firmware version, board and serial number\r
+  keys                        values and calibration of every key\r
+  key <n>                     details of key n\r
+  cal reset [n]               forget the calibration of key n, or all keys\r
+  set <setting> <value> [n]   change a setting for key n, or all keys\r
+      settings: point, hysteresis, alpha, range\r
+  timeout <kind> [s|off]      show or change a timeout, in seconds\r
+      kinds: stuck (keys held this long are released), sleep (on battery)\r
+  stream <kind> [ms]          print all key values every ms (default 20)\r
+      kinds: volts, raw, norm, depth\r
+  stream off                  stop streaming (so does ctrl-c)\r
+  leds                        host lock leds\r
+  battery                     battery voltage, charge and charging status\r
+  save                        save the keymap, settings and timeouts to flash\r
+  bootloader                  reboot into the UF2 bootloader to update the firmware\r
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Setting {
+    /// normalized value at which the key switches
+    Point,
+    Hysteresis,
+    /// smoothing filter coefficient
+    Alpha,
+    /// smallest min-max range that counts as calibrated
+    Range,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Timeout {
+    /// how long a key can be held before it is released as stuck
+    Stuck,
+    /// how long without a key change on battery before sleeping
+    Sleep,
+}
+
+impl Timeout {
+    fn name(self) -> &'static str {
+        match self {
+            Timeout::Stuck => "stuck key",
+            Timeout::Sleep => "sleep",
+        }
+    }
+
+    /// in ms, 0 for off
+    fn ms(self) -> u32 {
+        match self {
+            Timeout::Stuck => key_processor::stuck_key_timeout_ms(),
+            Timeout::Sleep => sleep::sleep_timeout_ms(),
+        }
+    }
+
+    /// whether the timeout was taken
+    fn set_ms(self, ms: u32) -> bool {
+        match self {
+            Timeout::Stuck => key_processor::set_stuck_key_timeout_ms(ms),
+            Timeout::Sleep => sleep::set_sleep_timeout_ms(ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum StreamKind {
+    Volts,
+    Raw,
+    Norm,
+    Depth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+enum Command {
+    Help,
+    Version,
+    Keys,
+    Key(u8),
+    CalReset(Option<u8>),
+    Set(Setting, f32, Option<u8>),
+    /// the new timeout in ms (0 for off), or None to show it
+    Timeout(Timeout, Option<u32>),
+    Stream(Option<(StreamKind, Duration)>),
+    Leds,
+    Battery,
+    Save,
+    Bootloader,
+}
+
+fn parse_key(word: Option<&str>) -> Result<Option<u8>, &'static str> {
+    let Some(word) = word else { return Ok(None) };
+    let keynumber = word.parse().map_err(|_| "bad key number")?;
+    if KEY_NAMES.contains(&keynumber) { Ok(Some(keynumber)) } else { Err("no such key") }
+}
+
+fn parse(line: &str) -> Result<Option<Command>, &'static str> {
+    let mut words = line.split_ascii_whitespace();
+    let Some(command) = words.next() else { return Ok(None) };
+    let command = match command {
+        "help" | "?" => Command::Help,
+        "version" => Command::Version,
+        "keys" => Command::Keys,
+        "key" => Command::Key(parse_key(words.next())?.ok_or("which key?")?),
+        "cal" => match words.next() {
+            Some("reset") => Command::CalReset(parse_key(words.next())?),
+            _ => return Err("usage: cal reset [n]"),
+        },
+        "set" => {
+            let setting = match words.next() {
+                Some("point") => Setting::Point,
+                Some("hysteresis") => Setting::Hysteresis,
+                Some("alpha") => Setting::Alpha,
+                Some("range") => Setting::Range,
+                _ => return Err("settings are point, hysteresis, alpha and range"),
+            };
+            let value = words.next().and_then(|w| w.parse().ok()).ok_or("bad value")?;
+            Command::Set(setting, value, parse_key(words.next())?)
+        }
+        "timeout" => {
+            let timeout = match words.next() {
+                Some("stuck") => Timeout::Stuck,
+                Some("sleep") => Timeout::Sleep,
+                _ => return Err("timeouts are stuck and sleep"),
+            };
+            let ms = match words.next() {
+                None => None,
+                Some("off") => Some(0),
+                Some(s) => Some(s.parse::<u32>().ok().and_then(|s| s.checked_mul(1000)).ok_or("bad timeout")?),
+            };
+            Command::Timeout(timeout, ms)
+        }
+        "stream" => {
+            let kind = match words.next() {
+                Some("off") => return Ok(Some(Command::Stream(None))),
+                Some("volts") | None => StreamKind::Volts,
+                Some("raw") => StreamKind::Raw,
+                Some("norm") => StreamKind::Norm,
+                Some("depth") => StreamKind::Depth,
+                _ => return Err("kinds are volts, raw, norm and depth"),
+            };
+            let period = match words.next() {
+                Some(ms) => Duration::from_millis(ms.parse().map_err(|_| "bad period")?),
+                None => DEFAULT_STREAM_PERIOD,
+            };
+            Command::Stream(Some((kind, period.max(MIN_STREAM_PERIOD))))
+        }
+        "leds" => Command::Leds,
+        "battery" => Command::Battery,
+        "save" => Command::Save,
+        "bootloader" => Command::Bootloader,
+        _ => return Err("unknown command, try help"),
+    };
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+    Ok(Some(command))
+}
+
+struct OptF32(Option<f32>);
+
+impl core::fmt::Display for OptF32 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(v) => write!(f, "{:.3}", v),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+async fn write_str<'d, D: Driver<'d>>(class: &mut CdcAcmClass<'d, D>, s: &str) -> Result<(), EndpointError> {
+    let max_packet_size = class.max_packet_size() as usize;
+    for chunk in s.as_bytes().chunks(max_packet_size) {
+        class.write_packet(chunk).await?;
+    }
+    if !s.is_empty() && s.len().is_multiple_of(max_packet_size) {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+/// Runs the shell for as long as the device is up, one session per host connection.
+pub async fn run<'d, D: Driver<'d>>(class: &mut CdcAcmClass<'d, D>) -> ! {
+    loop {
+        class.wait_connection().await;
+        defmt::info!("serial shell connected");
+        match session(class).await {
+            Ok(()) => {}
+            Err(e) => defmt::info!("serial shell disconnected: {:?}", e),
+        }
+    }
+}
+
+async fn session<'d, D: Driver<'d>>(class: &mut CdcAcmClass<'d, D>) -> Result<(), EndpointError> {
+    let mut line: String<LINE_LEN> = String::new();
+    let mut stream: Option<(StreamKind, Duration)> = None;
+    let mut buf = [0u8; 64];
+
+    write_str(class, "maghand shell - type help for commands\r\n> ").await?;
+    loop {
+        let n = match stream {
+            Some((kind, period)) => match select(class.read_packet(&mut buf), Timer::after(period)).await {
+                Either::First(n) => n?,
+                Either::Second(()) => {
+                    write_str(class, &stream_line(kind).await).await?;
+                    continue;
+                }
+            },
+            None => class.read_packet(&mut buf).await?,
+        };
+
+        for &byte in &buf[..n] {
+            match byte {
+                b'\r' | b'\n' => {
+                    if byte == b'\n' && line.is_empty() {
+                        continue; // the second half of a CRLF
+                    }
+                    write_str(class, "\r\n").await?;
+                    let output = match parse(&line) {
+                        Ok(Some(Command::Stream(s))) => {
+                            stream = s;
+                            String::new()
+                        }
+                        Ok(Some(command)) => execute(command).await,
+                        Ok(None) => String::new(),
+                        Err(e) => {
+                            let mut output = String::new();
+                            write!(output, "error: {}\r\n", e).ok();
+                            output
+                        }
+                    };
+                    write_str(class, &output).await?;
+                    line.clear();
+                    write_str(class, "> ").await?;
+                }
+                CTRL_C => {
+                    stream = None;
+                    line.clear();
+                    write_str(class, "^C\r\n> ").await?;
+                }
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        write_str(class, "\x08 \x08").await?;
+                    }
+                }
+                b' '..=b'~' => {
+                    if line.push(byte as char).is_ok() {
+                        class.write_packet(&[byte]).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn stream_line(kind: StreamKind) -> String<STREAM_LINE_LEN> {
+    let keys = KEYS_MUTEX_LAZY.get().lock().await;
+    let mut output = String::new();
+    for (i, key) in keys.iter().enumerate() {
+        let value = match kind {
+            StreamKind::Volts => key.value.map(|v| v * ADC_VOLTS_PER_COUNT),
+            StreamKind::Raw => key.value,
+            StreamKind::Norm => key.normalized_value(),
+            StreamKind::Depth => key.depth(),
+        };
+        let separator = if i + 1 < keys.len() { "," } else { "\r\n" };
+        write!(output, "{:.3}{}", value.unwrap_or(0.), separator).ok();
+    }
+    output
+}
+
+async fn execute(command: Command) -> String<OUTPUT_LEN> {
+    let mut output = String::new();
+    let keys_mutex = KEYS_MUTEX_LAZY.get();
+    let key_index_map = KEY_INDEX_MAP.get();
+    match command {
+        Command::Help => {
+            output.push_str(HELP).ok();
+        }
+        Command::Version => {
+            let identity = IDENTITY.get();
+            write!(output, "maghand-firmware {}\r\n{} serial {}\r\n", env!("CARGO_PKG_VERSION"),
+                   identity.product, identity.serial_number).ok();
+        }
+        Command::Keys => {
+            let keys = keys_mutex.lock().await;
+            output.push_str("key  value     min       max       norm   on\r\n").ok();
+            for key in keys.iter() {
+                write!(output, "{:02}   {:<9} {:<9} {:<9} {:<6} {}\r\n",
+                       key.keynumber, OptF32(key.value), OptF32(key.min_value), OptF32(key.max_value),
+                       OptF32(key.normalized_value()),
+                       key.is_on().map_or("-", |on| if on { "on" } else { "off" })).ok();
+            }
+        }
+        Command::Key(keynumber) => {
+            let keys = keys_mutex.lock().await;
+            let key = &keys[key_index_map[&keynumber]];
+            write!(output, "key {:02}: value {} min {} max {} norm {} depth {}\r\n",
+                   key.keynumber, OptF32(key.value), OptF32(key.min_value), OptF32(key.max_value),
+                   OptF32(key.normalized_value()), OptF32(key.depth())).ok();
+            write!(output, "  point {} hysteresis {} alpha {} range {} high_is_on {}\r\n",
+                   key.switch_point(), key.switch_hysteresis_fraction, key.filter_alpha,
+                   key.norm_valid_range, key.high_is_on).ok();
+            let travel_ms = key.press_travel_time().map(|travel| travel.as_micros() as f32 / 1000.);
+            write!(output, "  last press took {} ms\r\n", OptF32(travel_ms)).ok();
+        }
+        Command::CalReset(keynumber) => {
+            let mut keys = keys_mutex.lock().await;
+            for key in keys.iter_mut().filter(|k| keynumber.is_none_or(|n| k.keynumber == n)) {
+                key.reset_calibration();
+            }
+            output.push_str("calibration reset\r\n").ok();
+        }
+        Command::Set(setting, value, keynumber) => {
+            let changed = |settings: KeySettings| match setting {
+                Setting::Point => KeySettings { switch_point: value, ..settings },
+                Setting::Hysteresis => KeySettings { hysteresis_fraction: value, ..settings },
+                Setting::Alpha => KeySettings { filter_alpha: value, ..settings },
+                Setting::Range => KeySettings { norm_valid_range: value, ..settings },
+            };
+            let mut keys = keys_mutex.lock().await;
+            let chosen = |n: u8| keynumber.is_none_or(|k| k == n);
+            // checked for every key before any of them changes, and rejected the way raw HID would
+            if keys.iter().filter(|k| chosen(k.keynumber)).all(|key| changed(key.settings()).is_valid()) {
+                for key in keys.iter_mut().filter(|k| chosen(k.keynumber)) {
+                    key.set_settings(changed(key.settings()));
+                }
+                defmt::info!("shell set {} to {} for key {}", setting, value, keynumber);
+                output.push_str("ok\r\n").ok();
+            } else {
+                output.push_str("error: value out of range\r\n").ok();
+            }
+        }
+        Command::Timeout(timeout, ms) => {
+            if let Some(ms) = ms && !timeout.set_ms(ms) {
+                output.push_str("error: timeout too short\r\n").ok();
+            } else {
+                match timeout.ms() {
+                    0 => write!(output, "{} timeout off\r\n", timeout.name()).ok(),
+                    ms => write!(output, "{} timeout {} s\r\n", timeout.name(), ms / 1000).ok(),
+                };
+            }
+        }
+        Command::Stream(_) => {} // handled by the session, which owns the stream state
+        Command::Leds => {
+            let leds = host_leds();
+            write!(output, "num {} caps {} scroll {}\r\n", leds.num_lock(), leds.caps_lock(), leds.scroll_lock()).ok();
+        }
+        Command::Battery => {
+            let state = battery::state();
+            match (state.millivolts, state.percent) {
+                (Some(millivolts), Some(percent)) => write!(output, "{} mV, about {}%", millivolts, percent).ok(),
+                _ => write!(output, "not read yet").ok(),
+            };
+            write!(output, ", {:?}{}\r\n", state.charge, if state.low { ", low" } else { "" }).ok();
+        }
+        Command::Save => {
+            match storage::save().await {
+                Ok(()) => output.push_str("saved\r\n").ok(),
+                Err(e) => write!(output, "error: save failed: {:?}\r\n", e).ok(),
+            };
+        }
+        Command::Bootloader => {
+            bootloader::request();
+            output.push_str("rebooting into the bootloader\r\n").ok();
+        }
+    }
+    output
+}
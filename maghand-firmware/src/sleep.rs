@@ -0,0 +1,94 @@
+//! Deep sleep, so the keyboard can be left on battery for weeks.
+//!
+//! After the sleep timeout with no key changes while on battery, the keyboard goes into System OFF
+//! (see `power`): vhi off, which takes the key sensors and key LEDs with it, the muxes disabled, the
+//! board LEDs off, and the IMU left running slowly with its wake-up (motion) and single tap
+//! interrupts on INT1 (P0.11), which the chip senses to wake up.  The keys themselves can't wake it,
+//! since reading them needs vhi, but the jolt of a keystroke is a tap as far as the IMU is concerned,
+//! so typing wakes it all the same - just without that first key, which is lost along with
+//! everything else when the chip starts over.  Plugging in USB wakes it too.
+//!
+//! While on USB the keyboard never sleeps, since the host decides that with a USB suspend.
+//!
+//! The timeout is a runtime setting, saved with the others, `DEFAULT_SLEEP_TIMEOUT_MS` until it is
+//! changed.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use crate::power::{self, SystemOff};
+
+pub const DEFAULT_SLEEP_TIMEOUT_MS: u32 = 10 * 60 * 1000;
+/// anything shorter and the keyboard would be asleep (and lose a key) after every pause in typing
+pub const MIN_SLEEP_TIMEOUT_MS: u32 = 30_000;
+/// the accelerometer rate while asleep, the slowest that still picks up taps well
+const WAKE_ACCEL_RATE: lsm6ds3tr::AccelSampleRate = lsm6ds3tr::AccelSampleRate::_208Hz;
+/// in 1/64ths of the 2 g full scale, so about 60 mg - enough to ignore a desk being walked past
+const WAKE_UP_THRESHOLD: u8 = 2;
+/// in 1/32nds of the 2 g full scale, so about 190 mg
+const TAP_THRESHOLD: u8 = 3;
+
+/// how long without a key change before sleeping, in ms, or 0 to never sleep
+static SLEEP_TIMEOUT_MS: AtomicU32 = AtomicU32::new(DEFAULT_SLEEP_TIMEOUT_MS);
+static ACTIVITY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The sleep timeout in ms, 0 if the keyboard never sleeps.
+pub fn sleep_timeout_ms() -> u32 {
+    SLEEP_TIMEOUT_MS.load(Ordering::Relaxed)
+}
+
+/// Sets the sleep timeout, in ms or 0 to never sleep, starting it over.  Returns false, leaving it as
+/// it was, if it is shorter than `MIN_SLEEP_TIMEOUT_MS`.
+pub fn set_sleep_timeout_ms(ms: u32) -> bool {
+    if ms != 0 && ms < MIN_SLEEP_TIMEOUT_MS {
+        return false;
+    }
+    SLEEP_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+    ACTIVITY.signal(());
+    true
+}
+
+/// Called on every key change, putting sleep off for another sleep timeout.
+pub fn activity() {
+    ACTIVITY.signal(());
+}
+
+/// Requests System OFF once there has been no `activity` for the sleep timeout on battery.
+#[embassy_executor::task]
+pub async fn inactivity_task() {
+    loop {
+        let timeout = match sleep_timeout_ms() {
+            0 => {
+                ACTIVITY.wait().await;
+                continue;
+            }
+            ms => Duration::from_millis(ms as u64),
+        };
+        if let Either::Second(()) = select(ACTIVITY.wait(), Timer::after(timeout)).await
+            && !power::vbus_present() {
+            power::request_system_off(SystemOff::Sleep);
+            return;
+        }
+    }
+}
+
+/// IMU settings for System OFF: just the accelerometer, latching its wake-up and single tap
+/// interrupts on INT1 so the level stays up until the chip wakes to see it.
+pub fn imu_wake_settings() -> lsm6ds3tr::LsmSettings {
+    let mut irq_settings = lsm6ds3tr::IrqSettings::default();
+    irq_settings.enable_wake_up_irq(WAKE_UP_THRESHOLD, lsm6ds3tr::InterruptRoute::Int1, true);
+    irq_settings.enable_tap_irq(lsm6ds3tr::TapRecognitionMode::Single,
+                                lsm6ds3tr::XYZ { x: true, y: true, z: true },
+                                TAP_THRESHOLD, 2, 1, 0, lsm6ds3tr::InterruptRoute::Int1, true);
+    let accel_settings = lsm6ds3tr::AccelSettings::default()
+        .with_sample_rate(WAKE_ACCEL_RATE)
+        .with_scale(lsm6ds3tr::AccelScale::_2G);
+    lsm6ds3tr::LsmSettings::default()
+        .with_low_performance_mode()
+        .with_accel(accel_settings)
+        .with_irq(irq_settings)
+}
@@ -0,0 +1,44 @@
+//! Linking the two maghand halves into one split keyboard.
+//!
+//! With the `split` feature the left half is the central one, which is plugged into the host, and
+//! the right half (built with `right_hand` as well) is the peripheral.  The peripheral sends its key
+//! changes to the central half over a one-wire UART link, from its D6 (P1.11) to the central half's
+//! D6 - see `split_protocol` for the frames, `split_peripheral` for the sending end and
+//! `split_central` for the receiving one.  The central half publishes them on the key change bus
+//! numbered `REMOTE_KEY_OFFSET` above the peripheral's own key numbers, and from there on they are
+//! keys like any other: the keymap covers them (see `ALL_KEY_NAMES`), so layers, modifiers and
+//! everything else work across both halves.
+//!
+//! Along with every change, the peripheral sends its whole key state whenever it has been idle for a
+//! while, which puts right any frames that were lost.  If the central half hears nothing for long
+//! enough it takes the link to be down and releases all of the peripheral's keys.
+//!
+//! A BLE link could carry the same frames, but needs a BLE stack this firmware doesn't have yet.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_nrf::uarte;
+
+use crate::hardware_consts::{KEY_NAMES, N_KEYS, REMOTE_KEY_OFFSET};
+
+pub const BAUDRATE: uarte::Baudrate = uarte::Baudrate::BAUD115200;
+
+const _: () = assert!(N_KEYS <= 32, "the key state frame has a bit per key in a u32");
+
+/// on the central half, the peripheral's keys that are down, bit n for its `KEY_NAMES[n]`
+pub static REMOTE_KEYS: AtomicU32 = AtomicU32::new(0);
+
+#[allow(dead_code)] // only used with the split feature
+pub fn uart_config() -> uarte::Config {
+    let mut config = uarte::Config::default();
+    config.baudrate = BAUDRATE;
+    config.parity = uarte::Parity::EXCLUDED;
+    config
+}
+
+/// Whether a key of the other half is down, by its key number on this half.  Always false unless
+/// this is the central half of a split keyboard.
+pub fn remote_key_is_down(keynumber: u8) -> bool {
+    let index = keynumber.checked_sub(REMOTE_KEY_OFFSET).and_then(|k| KEY_NAMES.iter().position(|n| *n == k));
+    index.is_some_and(|index| REMOTE_KEYS.load(Ordering::Relaxed) & (1 << index) != 0)
+}
@@ -0,0 +1,89 @@
+//! The receiving end of the split link, on the central half - see `split`.
+
+use core::sync::atomic::Ordering;
+
+use embassy_nrf::buffered_uarte::BufferedUarteRx;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::pubsub::ImmediatePublisher;
+use embassy_time::{with_timeout, Duration};
+
+use crate::hardware_consts::{KEY_NAMES, N_KEYS, REMOTE_KEY_OFFSET};
+use crate::keys::KeySignal;
+use crate::split::REMOTE_KEYS;
+use crate::split_protocol::{missed, Decoder, Message};
+use crate::{KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS};
+
+/// several of the peripheral's state periods, so one corrupted frame doesn't take the link down
+const LINK_TIMEOUT: Duration = Duration::from_millis(500);
+/// room for a few frames, in case the task is held up
+pub const RX_BUFFER_SIZE: usize = 64;
+
+pub type KeyPublisher = ImmediatePublisher<'static, ThreadModeRawMutex, KeySignal, KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, N_KEYS>;
+
+/// Takes the peripheral's key state, publishing a key change for every key that changed.
+fn set_remote_keys(bits: u32, publisher: &KeyPublisher) {
+    let changed = REMOTE_KEYS.swap(bits, Ordering::Relaxed) ^ bits;
+    for (index, keynumber) in KEY_NAMES.iter().enumerate().filter(|(index, _)| changed & (1 << index) != 0) {
+        let signal = KeySignal { toggle_on: bits & (1 << index) != 0, keynumber: keynumber + REMOTE_KEY_OFFSET };
+        // the key processor resyncs from `remote_key_is_down` anyway
+        if publisher.try_publish(signal).is_err() {
+            defmt::warn!("Failed to publish key toggle signal for remote key {}", signal.keynumber);
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn central_task(mut rx: BufferedUarteRx<'static>, publisher: KeyPublisher) {
+    let mut decoder = Decoder::new();
+    // None while the link is down
+    let mut last_seq: Option<u8> = None;
+    let mut buf = [0u8; RX_BUFFER_SIZE / 2];
+    loop {
+        let n = match with_timeout(LINK_TIMEOUT, rx.read(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
+                defmt::warn!("split link read failed: {:?}", e);
+                continue;
+            }
+            Err(_) => {
+                if last_seq.take().is_some() {
+                    defmt::warn!("split link down, releasing the other half's keys");
+                    set_remote_keys(0, &publisher);
+                }
+                continue;
+            }
+        };
+        for byte in &buf[..n] {
+            let frame = match decoder.push(*byte) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    defmt::warn!("bad split link frame: {}", e);
+                    continue;
+                }
+                None => continue,
+            };
+            match last_seq {
+                Some(previous) if missed(previous, frame.seq) > 0 => {
+                    defmt::warn!("missed {} split link frames", missed(previous, frame.seq));
+                }
+                Some(_) => {}
+                None => defmt::info!("split link up"),
+            }
+            last_seq = Some(frame.seq);
+
+            let bits = REMOTE_KEYS.load(Ordering::Relaxed);
+            let bits = match frame.message {
+                Message::KeyChange { keynumber, pressed } => match KEY_NAMES.iter().position(|k| *k == keynumber) {
+                    Some(index) if pressed => bits | 1 << index,
+                    Some(index) => bits & !(1 << index),
+                    None => {
+                        defmt::warn!("the other half has no key {}", keynumber);
+                        bits
+                    }
+                },
+                Message::KeyState(state) => state & ((1 << N_KEYS) - 1),
+            };
+            set_remote_keys(bits, &publisher);
+        }
+    }
+}
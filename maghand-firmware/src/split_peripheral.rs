@@ -0,0 +1,41 @@
+//! The sending end of the split link, on the peripheral half - see `split`.
+
+use embassy_futures::select::{select, Either};
+use embassy_nrf::uarte::UarteTx;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Timer};
+
+use crate::usb_kb::KeySubscriber;
+use crate::split_protocol::{Frame, Message};
+use crate::KEYS_MUTEX_LAZY;
+
+/// how long the link can go without a key change before the whole key state is sent
+const STATE_PERIOD: Duration = Duration::from_millis(100);
+
+/// This half's key state, bit n for `KEY_NAMES[n]`.
+async fn key_state() -> u32 {
+    let keys = KEYS_MUTEX_LAZY.get().lock().await;
+    keys.iter().enumerate()
+        .filter(|(_, key)| key.is_on() == Some(true))
+        .fold(0, |bits, (index, _)| bits | 1 << index)
+}
+
+#[embassy_executor::task]
+pub async fn peripheral_task(mut tx: UarteTx<'static>, mut key_subscriber: KeySubscriber) {
+    let mut seq = 0u8;
+    loop {
+        let message = match select(key_subscriber.next_message(), Timer::after(STATE_PERIOD)).await {
+            Either::First(WaitResult::Message(signal)) => Message::KeyChange { keynumber: signal.keynumber, pressed: signal.toggle_on },
+            Either::First(WaitResult::Lagged(n)) => {
+                defmt::warn!("split link key change subscriber lagged by {}", n);
+                Message::KeyState(key_state().await)
+            }
+            Either::Second(()) => Message::KeyState(key_state().await),
+        };
+        let frame = Frame { seq, message }.encode();
+        seq = seq.wrapping_add(1);
+        if let Err(e) = tx.write(&frame).await {
+            defmt::warn!("split link write failed: {:?}", e);
+        }
+    }
+}
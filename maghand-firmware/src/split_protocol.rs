@@ -0,0 +1,235 @@
+//! The link protocol between the two halves of a split keyboard (see `split`).
+//!
+//! The peripheral half sends frames and the central half only listens, so the link needs just one
+//! wire.  Every frame is:
+//!
+//! ```text
+//! sync 0xA5 | sequence u8 | kind u8 | payload length u8 | payload | CRC-8 of everything after sync
+//! ```
+//!
+//! | kind | payload |
+//! |------|---------|
+//! | 0x01 key change | key number (the peripheral's own), 1 pressed or 0 released |
+//! | 0x02 key state | u32 little-endian, bit n set if the peripheral's `KEY_NAMES[n]` is down |
+//!
+//! The sequence number goes up by one per frame, wrapping, so the receiver can tell how many frames
+//! it missed.  Since nothing goes back the other way, missed or corrupted frames aren't resent;
+//! instead the key state is sent every so often, which also tells the central half the link is up.
+//! The CRC is CRC-8/SMBUS (polynomial 0x07, no reflection, starting from 0).
+//!
+//! A receiver that loses its place (a corrupted length, or starting up mid-frame) skips bytes until
+//! the next sync byte.  Nothing in here touches the hardware, so both ends can be run against each
+//! other on a host.
+
+use heapless::Vec;
+
+pub const SYNC: u8 = 0xA5;
+pub const HEADER_SIZE: usize = 4;
+pub const MAX_PAYLOAD_SIZE: usize = 4;
+pub const MAX_FRAME_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE + 1;
+
+pub const KIND_KEY_CHANGE: u8 = 0x01;
+pub const KIND_KEY_STATE: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Message {
+    KeyChange { keynumber: u8, pressed: bool },
+    /// bit n for the sender's `KEY_NAMES[n]`
+    KeyState(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Frame {
+    pub seq: u8,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameError {
+    Checksum,
+    UnknownKind(u8),
+    BadLength,
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+/// How many frames were missed between sequence numbers `previous` and `seq`.
+pub fn missed(previous: u8, seq: u8) -> u8 {
+    seq.wrapping_sub(previous).wrapping_sub(1)
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8, MAX_FRAME_SIZE> {
+        let (kind, payload): (u8, Vec<u8, MAX_PAYLOAD_SIZE>) = match self.message {
+            Message::KeyChange { keynumber, pressed } => (KIND_KEY_CHANGE, Vec::from_slice(&[keynumber, pressed as u8]).unwrap()),
+            Message::KeyState(bits) => (KIND_KEY_STATE, Vec::from_slice(&bits.to_le_bytes()).unwrap()),
+        };
+        let mut frame = Vec::new();
+        // MAX_FRAME_SIZE has room for the largest payload
+        frame.extend_from_slice(&[SYNC, self.seq, kind, payload.len() as u8]).ok();
+        frame.extend_from_slice(&payload).ok();
+        frame.push(crc8(&frame[1..])).ok();
+        frame
+    }
+
+    /// Reads a frame from its bytes after the sync byte, the checksum included.
+    fn decode(bytes: &[u8]) -> Result<Frame, FrameError> {
+        let (checksum, body) = bytes.split_last().ok_or(FrameError::BadLength)?;
+        if crc8(body) != *checksum {
+            return Err(FrameError::Checksum);
+        }
+        let [seq, kind, len, payload @ ..] = body else {
+            return Err(FrameError::BadLength);
+        };
+        if payload.len() != *len as usize {
+            return Err(FrameError::BadLength);
+        }
+        let message = match (*kind, payload) {
+            (KIND_KEY_CHANGE, [keynumber, pressed]) => Message::KeyChange { keynumber: *keynumber, pressed: *pressed != 0 },
+            (KIND_KEY_STATE, [a, b, c, d]) => Message::KeyState(u32::from_le_bytes([*a, *b, *c, *d])),
+            (KIND_KEY_CHANGE | KIND_KEY_STATE, _) => return Err(FrameError::BadLength),
+            (kind, _) => return Err(FrameError::UnknownKind(kind)),
+        };
+        Ok(Frame { seq: *seq, message })
+    }
+}
+
+/// Picks frames out of a stream of bytes, one byte at a time.
+pub struct Decoder {
+    /// the frame so far, without its sync byte, or None while waiting for one
+    partial: Option<Vec<u8, { MAX_FRAME_SIZE - 1 }>>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder { partial: None }
+    }
+
+    /// Takes the next byte, returning the frame (or what was wrong with it) once it is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, FrameError>> {
+        let Some(partial) = self.partial.as_mut() else {
+            if byte == SYNC {
+                self.partial = Some(Vec::new());
+            }
+            return None;
+        };
+        partial.push(byte).ok();
+        let len = *partial.get(HEADER_SIZE - 2)? as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            self.partial = None;
+            return Some(Err(FrameError::BadLength));
+        }
+        // the header after the sync byte, the payload and the checksum
+        if partial.len() < HEADER_SIZE - 1 + len + 1 {
+            return None;
+        }
+        let result = Frame::decode(partial);
+        self.partial = None;
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_change(seq: u8, keynumber: u8, pressed: bool) -> Frame {
+        Frame { seq, message: Message::KeyChange { keynumber, pressed } }
+    }
+
+    /// Everything the decoder makes of the bytes, in order.
+    fn decode_all(decoder: &mut Decoder, bytes: &[u8]) -> std::vec::Vec<Result<Frame, FrameError>> {
+        bytes.iter().filter_map(|byte| decoder.push(*byte)).collect()
+    }
+
+    #[test]
+    fn crc_is_smbus() {
+        // the standard check value for CRC-8/SMBUS
+        assert_eq!(crc8(b"123456789"), 0xF4);
+    }
+
+    #[test]
+    fn frames_loop_back() {
+        let frames = [key_change(0, 12, true), key_change(1, 12, false), Frame { seq: 2, message: Message::KeyState(0xA5A5_0001) },
+                      Frame { seq: 3, message: Message::KeyState(u32::MAX) }];
+        let bytes: std::vec::Vec<u8> = frames.iter().flat_map(|frame| frame.encode()).collect();
+        let decoded = decode_all(&mut Decoder::new(), &bytes);
+        assert_eq!(decoded, frames.map(Ok));
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let mut decoder = Decoder::new();
+        let mut previous = None;
+        for seq in [253, 254, 255, 0, 1] {
+            let decoded = decode_all(&mut decoder, &key_change(seq, 3, true).encode());
+            assert_eq!(decoded, [Ok(key_change(seq, 3, true))]);
+            if let Some(previous) = previous {
+                assert_eq!(missed(previous, seq), 0);
+            }
+            previous = Some(seq);
+        }
+    }
+
+    #[test]
+    fn missed_counts_across_the_wrap() {
+        assert_eq!(missed(10, 11), 0);
+        assert_eq!(missed(10, 14), 3);
+        assert_eq!(missed(255, 0), 0);
+        assert_eq!(missed(254, 1), 2);
+        assert_eq!(missed(250, 3), 8);
+    }
+
+    #[test]
+    fn corrupted_frames_fail_the_checksum() {
+        let frame = Frame { seq: 7, message: Message::KeyState(0x0000_0104) }.encode();
+        // the payload and the checksum itself
+        for i in HEADER_SIZE..frame.len() {
+            let mut corrupted = frame.clone();
+            corrupted[i] ^= 0x10;
+            let mut decoder = Decoder::new();
+            assert_eq!(decode_all(&mut decoder, &corrupted), [Err(FrameError::Checksum)], "byte {i}");
+            // and the next frame still comes through
+            assert_eq!(decode_all(&mut decoder, &frame), [Ok(Frame::decode(&frame[1..]).unwrap())]);
+        }
+    }
+
+    #[test]
+    fn bad_lengths_are_rejected() {
+        let mut decoder = Decoder::new();
+        // longer than any payload, rejected as soon as the length is in
+        assert_eq!(decode_all(&mut decoder, &[SYNC, 0, KIND_KEY_CHANGE, MAX_PAYLOAD_SIZE as u8 + 1]), [Err(FrameError::BadLength)]);
+        // a valid checksum, but the wrong length for the kind
+        let mut body = [1, KIND_KEY_CHANGE, 3, 12, 1, 0];
+        let mut frame: std::vec::Vec<u8> = [SYNC].iter().chain(&body).copied().collect();
+        frame.push(crc8(&body));
+        assert_eq!(decode_all(&mut decoder, &frame), [Err(FrameError::BadLength)]);
+        // and an unknown kind
+        body[1] = 0x7F;
+        let mut frame: std::vec::Vec<u8> = [SYNC].iter().chain(&body).copied().collect();
+        frame.push(crc8(&body));
+        assert_eq!(decode_all(&mut decoder, &frame), [Err(FrameError::UnknownKind(0x7F))]);
+        assert_eq!(decode_all(&mut decoder, &key_change(2, 5, true).encode()), [Ok(key_change(2, 5, true))]);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let frame = key_change(42, 21, true);
+        let mut decoder = Decoder::new();
+        // the end of a frame, as if starting up partway through one, then noise without a sync byte
+        let tail = &key_change(41, 20, false).encode()[3..];
+        assert_eq!(decode_all(&mut decoder, tail), []);
+        assert_eq!(decode_all(&mut decoder, &[0x00, 0xFF, 0x13, 0x5A]), []);
+        assert_eq!(decode_all(&mut decoder, &frame.encode()), [Ok(frame)]);
+
+        // a stray sync byte starts a frame that fails, after which the decoder waits for the next one
+        let garbage = [0x00, SYNC, 0x07, KIND_KEY_CHANGE, 2, 0x05, 0x01, 0x99, 0x3C];
+        let decoded = decode_all(&mut decoder, &garbage);
+        assert_eq!(decoded, [Err(FrameError::Checksum)]);
+        assert_eq!(decode_all(&mut decoder, &frame.encode()), [Ok(frame)]);
+    }
+}
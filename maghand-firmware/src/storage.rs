@@ -0,0 +1,294 @@
+//! Settings saved in the internal flash: the keymap, the per-key analog settings, the macros, VIA's
+//! layout options and the stuck key and sleep timeouts.  The central half of a split keyboard saves
+//! the other half's keymap too, in a section of its own.
+//!
+//! Everything is saved as one record at the start of one of the first two pages of `STORAGE_START`, a
+//! region set aside for it in `memory.x`.  Each save goes to the page not holding the newest record,
+//! with the next sequence number, so a save cut short by a reset or a flat battery leaves the previous
+//! record intact, and the loader takes whichever page has the newest record with a good checksum.
+//!
+//! The record is a header followed by tagged sections, so sections can be added later without
+//! invalidating what is already saved - the loader skips tags it doesn't know and sections whose size
+//! doesn't match this build (e.g. after the key count changes).  A keymap with fewer layers than this
+//! build loads into the first ones, since layers are only ever added at the end.
+//!
+//! ```text
+//! record:  magic "MAGH" | format version u16 | payload length u16 | FNV-1a checksum u32 | sequence u32 | payload
+//! section: tag u8 | 0 u8 | length u16 | data
+//! ```
+//!
+//! The checksum covers everything after it, the sequence number and the payload.
+//!
+//! All integers are little-endian.  Flash is only written on an explicit save (or, for VIA edits, a
+//! delayed one), since erasing a page stalls the CPU for ~85 ms.
+
+use core::cell::RefCell;
+
+use embassy_nrf::nvmc::{self, Nvmc, PAGE_SIZE};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use heapless::Vec;
+
+use crate::hardware_consts::{ALL_KEY_NAMES, N_KEYS, N_REMOTE_KEYS, SPLIT_CENTRAL};
+use crate::keys::{keymap_get, keymap_set, Action, KeySettings, ACTION_ENCODED_SIZE, LAYERS, N_LAYERS, NO_ACTION_ENCODED};
+use crate::key_processor;
+use crate::macros::{MACRO_BUFFER, MACRO_BUFFER_SIZE};
+use crate::sleep;
+use crate::via::{layout_options, set_layout_options};
+use crate::KEYS_MUTEX_LAZY;
+
+/// must match the gap left after FLASH in `memory.x`
+pub const STORAGE_START: u32 = 0xEC000;
+pub const STORAGE_SIZE: u32 = 0x8000;
+
+const MAGIC: [u8; 4] = *b"MAGH";
+const FORMAT_VERSION: u16 = 2;
+const HEADER_SIZE: usize = 16;
+/// where the checksummed part of the record starts
+const CHECKSUMMED_START: usize = 12;
+const SECTION_HEADER_SIZE: usize = 4;
+const MAX_RECORD_SIZE: usize = 2048;
+/// the record alternates between these, so there is always a good copy while the other is erased
+const PAGES: [u32; 2] = [STORAGE_START, STORAGE_START + PAGE_SIZE as u32];
+const _: () = assert!(MAX_RECORD_SIZE <= PAGE_SIZE && 2 * PAGE_SIZE as u32 <= STORAGE_SIZE,
+                      "the record must fit in one flash page, and there must be two of them");
+
+const TAG_KEYMAP: u8 = 1;
+const TAG_KEY_SETTINGS: u8 = 2;
+const TAG_MACROS: u8 = 3;
+const TAG_LAYOUT_OPTIONS: u8 = 4;
+const TAG_REMOTE_KEYMAP: u8 = 5;
+const TAG_STUCK_KEY_TIMEOUT: u8 = 6;
+const TAG_SLEEP_TIMEOUT: u8 = 7;
+
+/// every layer's action for every key, in `LAYERS` and `KEY_NAMES` order
+const KEYMAP_SECTION_SIZE: usize = N_LAYERS * LAYER_SECTION_SIZE;
+const LAYER_SECTION_SIZE: usize = N_KEYS * ACTION_ENCODED_SIZE;
+/// the same for the other half of a split keyboard, in the order of its key numbers
+const REMOTE_KEYMAP_SECTION_SIZE: usize = N_LAYERS * REMOTE_LAYER_SECTION_SIZE;
+const REMOTE_LAYER_SECTION_SIZE: usize = N_REMOTE_KEYS * ACTION_ENCODED_SIZE;
+const KEY_SETTINGS_SECTION_SIZE: usize = N_KEYS * KeySettings::ENCODED_SIZE;
+const LAYOUT_OPTIONS_SECTION_SIZE: usize = 4;
+/// a timeout in ms, 0 for none
+const TIMEOUT_SECTION_SIZE: usize = 4;
+
+static FLASH: Mutex<ThreadModeRawMutex, RefCell<Option<Nvmc<'static>>>> = Mutex::new(RefCell::new(None));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StorageError {
+    NotInitialized,
+    TooLarge,
+    Flash(nvmc::Error),
+}
+
+impl From<nvmc::Error> for StorageError {
+    fn from(e: nvmc::Error) -> Self {
+        StorageError::Flash(e)
+    }
+}
+
+pub fn init(nvmc: Nvmc<'static>) {
+    FLASH.lock(|flash| flash.borrow_mut().replace(nvmc));
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C9DC5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+type Record = Vec<u8, MAX_RECORD_SIZE>;
+
+fn push_section(record: &mut Record, tag: u8, data: &[u8]) -> Result<(), StorageError> {
+    let len = u16::try_from(data.len()).map_err(|_| StorageError::TooLarge)?;
+    let [lo, hi] = len.to_le_bytes();
+    record.extend_from_slice(&[tag, 0, lo, hi]).map_err(|_| StorageError::TooLarge)?;
+    record.extend_from_slice(data).map_err(|_| StorageError::TooLarge)
+}
+
+/// Writes the current keymap, key settings, macros, layout options and timeouts to flash.
+pub async fn save() -> Result<(), StorageError> {
+    let mut record = Record::new();
+    record.resize(HEADER_SIZE, 0).ok();
+
+    let mut keymap = [0u8; KEYMAP_SECTION_SIZE];
+    encode_keymap(&mut keymap, &ALL_KEY_NAMES[..N_KEYS]);
+    push_section(&mut record, TAG_KEYMAP, &keymap)?;
+    if SPLIT_CENTRAL {
+        let mut remote_keymap = [0u8; REMOTE_KEYMAP_SECTION_SIZE];
+        encode_keymap(&mut remote_keymap, &ALL_KEY_NAMES[N_KEYS..]);
+        push_section(&mut record, TAG_REMOTE_KEYMAP, &remote_keymap)?;
+    }
+
+    let mut settings = [0u8; KEY_SETTINGS_SECTION_SIZE];
+    {
+        let keys = KEYS_MUTEX_LAZY.get().lock().await;
+        for (chunk, key) in settings.chunks_exact_mut(KeySettings::ENCODED_SIZE).zip(keys.iter()) {
+            chunk.copy_from_slice(&key.settings().encode());
+        }
+    }
+    push_section(&mut record, TAG_KEY_SETTINGS, &settings)?;
+
+    let macros = MACRO_BUFFER.get().lock(|buffer| *buffer.borrow());
+    push_section(&mut record, TAG_MACROS, &macros)?;
+    push_section(&mut record, TAG_LAYOUT_OPTIONS, &layout_options().to_le_bytes())?;
+    push_section(&mut record, TAG_STUCK_KEY_TIMEOUT, &key_processor::stuck_key_timeout_ms().to_le_bytes())?;
+    push_section(&mut record, TAG_SLEEP_TIMEOUT, &sleep::sleep_timeout_ms().to_le_bytes())?;
+
+    let payload_len = (record.len() - HEADER_SIZE) as u16;
+    record[0..4].copy_from_slice(&MAGIC);
+    record[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    record[6..8].copy_from_slice(&payload_len.to_le_bytes());
+
+    let (page, sequence) = FLASH.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        let flash = flash.as_mut().ok_or(StorageError::NotInitialized)?;
+        let (page, sequence) = match newest_page(flash) {
+            Some((newest, sequence)) => (PAGES[1 - newest], sequence.wrapping_add(1)),
+            None => (PAGES[0], 1),
+        };
+        record[12..16].copy_from_slice(&sequence.to_le_bytes());
+        let record_checksum = checksum(&record[CHECKSUMMED_START..]);
+        record[8..12].copy_from_slice(&record_checksum.to_le_bytes());
+        // flash is written a word at a time - erased flash reads as 0xFF, so pad with that
+        while !record.len().is_multiple_of(4) {
+            record.push(0xFF).ok();
+        }
+        flash.erase(page, page + PAGE_SIZE as u32)?;
+        flash.write(page, &record)?;
+        Ok::<_, StorageError>((page, sequence))
+    })?;
+    defmt::info!("saved {} bytes of settings to flash at {:x} (save {})", record.len(), page, sequence);
+    Ok(())
+}
+
+/// The index in `PAGES` of the page holding the newest valid record, and its sequence number.
+fn newest_page(flash: &mut Nvmc<'static>) -> Option<(usize, u32)> {
+    let mut record = [0u8; MAX_RECORD_SIZE];
+    let mut newest = None;
+    for (index, page) in PAGES.iter().enumerate() {
+        if flash.read(*page, &mut record).is_err() {
+            continue;
+        }
+        if let Some((sequence, _)) = validate(&record)
+            && newest.is_none_or(|(_, newest_sequence)| is_newer(sequence, newest_sequence)) {
+            newest = Some((index, sequence));
+        }
+    }
+    newest
+}
+
+/// Whether sequence number `a` was saved after `b`, allowing for them wrapping round.
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Applies the saved settings, if there are any.  Returns whether anything was loaded.
+pub async fn load() -> bool {
+    let mut record = [0u8; MAX_RECORD_SIZE];
+    let read = FLASH.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        let flash = flash.as_mut()?;
+        let newest = newest_page(flash);
+        if let Some((index, _)) = newest {
+            flash.read(PAGES[index], &mut record).ok()?;
+        }
+        Some(newest)
+    });
+    let Some(newest) = read else {
+        defmt::warn!("flash storage not initialized, not loading settings");
+        return false;
+    };
+
+    let Some((sequence, payload)) = newest.and_then(|_| validate(&record)) else {
+        defmt::info!("no saved settings in flash, using the defaults");
+        return false;
+    };
+
+    let mut rest = payload;
+    while rest.len() >= SECTION_HEADER_SIZE {
+        let tag = rest[0];
+        let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let Some(data) = rest.get(SECTION_HEADER_SIZE..SECTION_HEADER_SIZE + len) else {
+            defmt::warn!("saved settings section {} truncated", tag);
+            break;
+        };
+        match (tag, len) {
+            // keymaps saved before a layer was added leave the new layer's defaults alone
+            (TAG_KEYMAP, len) if keymap_section_fits(len, LAYER_SECTION_SIZE) => load_keymap(data, &ALL_KEY_NAMES[..N_KEYS]),
+            (TAG_REMOTE_KEYMAP, len) if SPLIT_CENTRAL && keymap_section_fits(len, REMOTE_LAYER_SECTION_SIZE) => {
+                load_keymap(data, &ALL_KEY_NAMES[N_KEYS..])
+            }
+            (TAG_KEY_SETTINGS, KEY_SETTINGS_SECTION_SIZE) => load_key_settings(data).await,
+            (TAG_MACROS, MACRO_BUFFER_SIZE) => MACRO_BUFFER.get().lock(|buffer| buffer.borrow_mut().copy_from_slice(data)),
+            (TAG_LAYOUT_OPTIONS, LAYOUT_OPTIONS_SECTION_SIZE) => set_layout_options(u32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+            (TAG_STUCK_KEY_TIMEOUT, TIMEOUT_SECTION_SIZE) => {
+                if !key_processor::set_stuck_key_timeout_ms(u32::from_le_bytes([data[0], data[1], data[2], data[3]])) {
+                    defmt::warn!("invalid saved stuck key timeout");
+                }
+            }
+            (TAG_SLEEP_TIMEOUT, TIMEOUT_SECTION_SIZE) => {
+                if !sleep::set_sleep_timeout_ms(u32::from_le_bytes([data[0], data[1], data[2], data[3]])) {
+                    defmt::warn!("invalid saved sleep timeout");
+                }
+            }
+            _ => defmt::warn!("skipping saved settings section {} of {} bytes", tag, len),
+        }
+        rest = &rest[SECTION_HEADER_SIZE + len..];
+    }
+    defmt::info!("loaded saved settings from flash (save {})", sequence);
+    true
+}
+
+/// the sequence number and payload of a record, if it has a good header and checksum
+fn validate(record: &[u8]) -> Option<(u32, &[u8])> {
+    if record[0..4] != MAGIC {
+        return None;
+    }
+    let version = u16::from_le_bytes([record[4], record[5]]);
+    if version != FORMAT_VERSION {
+        defmt::warn!("saved settings are format {}, expected {}", version, FORMAT_VERSION);
+        return None;
+    }
+    let len = u16::from_le_bytes([record[6], record[7]]) as usize;
+    let checked = record.get(CHECKSUMMED_START..HEADER_SIZE + len)?;
+    let expected = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+    if checksum(checked) != expected {
+        defmt::warn!("saved settings checksum mismatch");
+        return None;
+    }
+    let sequence = u32::from_le_bytes([record[12], record[13], record[14], record[15]]);
+    Some((sequence, &record[HEADER_SIZE..HEADER_SIZE + len]))
+}
+
+/// Whether a keymap section of `len` bytes is whole layers, and no more of them than there are.
+fn keymap_section_fits(len: usize, layer_section_size: usize) -> bool {
+    len <= N_LAYERS * layer_section_size && len.is_multiple_of(layer_section_size)
+}
+
+/// Fills `keymap` with every layer's action for each of `key_names`, layer by layer.
+fn encode_keymap(keymap: &mut [u8], key_names: &[u8]) {
+    let entries = LAYERS.iter().flat_map(|layer| key_names.iter().map(move |keynumber| (*keynumber, *layer)));
+    for (chunk, (keynumber, layer)) in keymap.chunks_exact_mut(ACTION_ENCODED_SIZE).zip(entries) {
+        let encoded = keymap_get(keynumber, layer).map_or(NO_ACTION_ENCODED, |action| action.encode());
+        chunk.copy_from_slice(&encoded);
+    }
+}
+
+fn load_keymap(data: &[u8], key_names: &[u8]) {
+    let entries = LAYERS.iter().flat_map(|layer| key_names.iter().map(move |keynumber| (*keynumber, *layer)));
+    for (chunk, (keynumber, layer)) in data.chunks_exact(ACTION_ENCODED_SIZE).zip(entries) {
+        let encoded: [u8; ACTION_ENCODED_SIZE] = chunk.try_into().unwrap_or(NO_ACTION_ENCODED);
+        keymap_set(keynumber, layer, Action::decode(encoded));
+    }
+}
+
+async fn load_key_settings(data: &[u8]) {
+    let mut keys = KEYS_MUTEX_LAZY.get().lock().await;
+    for (chunk, key) in data.chunks_exact(KeySettings::ENCODED_SIZE).zip(keys.iter_mut()) {
+        match chunk.try_into().ok().and_then(KeySettings::decode) {
+            Some(settings) => key.set_settings(settings),
+            None => defmt::warn!("invalid saved settings for key {}", key.keynumber),
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Tap dance: one key that does different things depending on how many times it is tapped in a row,
+//! and whether the last tap is held.
+//!
+//! This is a pure state machine - the caller passes in the current time and acts on the returned
+//! `Resolution`s - so its behaviour depends only on the sequence of calls, not on any hardware.
+
+use embassy_time::{Duration, Instant};
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+use heapless::Vec;
+
+use crate::keys::Action;
+
+/// how long after a tap (or press) the dance waits for the next one before resolving
+pub const TAPPING_TERM: Duration = Duration::from_millis(200);
+pub const MAX_TAPS: usize = 3;
+const MAX_HELD: usize = 4;
+
+pub struct TapDanceDef {
+    /// the action for 1, 2, ... `MAX_TAPS` taps
+    pub taps: [Option<Action>; MAX_TAPS],
+    /// the action if the last tap is still held when the tapping term runs out.  If None, the tap
+    /// action for that count is held instead.
+    pub hold: Option<Action>,
+}
+
+pub static TAP_DANCES: [TapDanceDef; 1] = [
+    // 0: escape, tab on double tap, caps lock on triple tap, control on hold
+    TapDanceDef {
+        taps: [
+            Some(Action::Key(KeyboardUsage::KeyboardEscape)),
+            Some(Action::Key(KeyboardUsage::KeyboardTab)),
+            Some(Action::Key(KeyboardUsage::KeyboardCapsLock)),
+        ],
+        hold: Some(Action::Key(KeyboardUsage::KeyboardLeftControl)),
+    },
+];
+
+/// What the caller should do once a dance has been decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// press and immediately release the action
+    Tap(Action),
+    /// press the action - a matching `Release` follows when the key comes up
+    Press(Action),
+    Release(Action),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingDance {
+    id: u8,
+    keynumber: u8,
+    count: u8,
+    pressed: bool,
+    deadline: Instant,
+}
+
+pub struct TapDance {
+    pending: Option<PendingDance>,
+    /// resolved dances whose key is still down, and the action to release when it comes up
+    held: Vec<(u8, Action), MAX_HELD>,
+}
+
+impl TapDance {
+    pub const fn new() -> Self {
+        TapDance { pending: None, held: Vec::new() }
+    }
+
+    /// when `timeout` should next be called, if a dance is undecided
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|p| p.deadline)
+    }
+
+    /// A key mapped to tap dance `id` went down.
+    pub fn press(&mut self, id: u8, keynumber: u8, now: Instant) -> Option<Resolution> {
+        let mut resolution = None;
+        match &mut self.pending {
+            Some(p) if p.keynumber == keynumber => {
+                p.count = (p.count + 1).min(MAX_TAPS as u8);
+                p.pressed = true;
+                p.deadline = now + TAPPING_TERM;
+                return None;
+            }
+            Some(_) => resolution = self.interrupt(),
+            None => {}
+        }
+
+        if (id as usize) < TAP_DANCES.len() {
+            self.pending = Some(PendingDance {
+                id,
+                keynumber,
+                count: 1,
+                pressed: true,
+                deadline: now + TAPPING_TERM,
+            });
+        } else {
+            defmt::warn!("no tap dance {}", id);
+        }
+        resolution
+    }
+
+    /// A key mapped to a tap dance came up.
+    pub fn release(&mut self, keynumber: u8, now: Instant) -> Option<Resolution> {
+        if let Some(i) = self.held.iter().position(|(kn, _)| *kn == keynumber) {
+            let (_, action) = self.held.swap_remove(i);
+            return Some(Resolution::Release(action));
+        }
+
+        let p = self.pending.as_mut().filter(|p| p.keynumber == keynumber)?;
+        p.pressed = false;
+        p.deadline = now + TAPPING_TERM;
+        if p.count as usize >= MAX_TAPS {
+            // no more taps can change the outcome, so don't wait
+            return self.resolve(false);
+        }
+        None
+    }
+
+    /// Another key was pressed, which decides any pending dance as it stands: the tap action for the
+    /// count so far, held if the dance key is still down.
+    pub fn interrupt(&mut self) -> Option<Resolution> {
+        self.resolve(false)
+    }
+
+    /// Decides a pending dance whose tapping term has run out.
+    pub fn timeout(&mut self, now: Instant) -> Option<Resolution> {
+        match self.pending {
+            Some(p) if now >= p.deadline => self.resolve(true),
+            _ => None,
+        }
+    }
+
+    fn resolve(&mut self, timed_out: bool) -> Option<Resolution> {
+        let p = self.pending.take()?;
+        let def = &TAP_DANCES[p.id as usize];
+        let tap_action = def.taps[p.count as usize - 1];
+
+        if !p.pressed {
+            defmt::debug!("tap dance {} resolved to {} taps", p.id, p.count);
+            return tap_action.map(Resolution::Tap);
+        }
+
+        let action = if timed_out { def.hold.or(tap_action) } else { tap_action }?;
+        defmt::debug!("tap dance {} resolved to {} taps, held", p.id, p.count);
+        if self.held.push((p.keynumber, action)).is_err() {
+            defmt::warn!("too many held tap dances, tapping instead");
+            return Some(Resolution::Tap(action));
+        }
+        Some(Resolution::Press(action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: u8 = 30;
+    const OTHER_KEY: u8 = 31;
+    const ESCAPE: Action = Action::Key(KeyboardUsage::KeyboardEscape);
+    const TAB: Action = Action::Key(KeyboardUsage::KeyboardTab);
+    const CAPS_LOCK: Action = Action::Key(KeyboardUsage::KeyboardCapsLock);
+    const CONTROL: Action = Action::Key(KeyboardUsage::KeyboardLeftControl);
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(1000 + ms)
+    }
+
+    /// Taps the dance key `n` times, 50 ms down and 50 ms up, returning when the last tap came up.
+    fn tap(dance: &mut TapDance, n: u64) -> Instant {
+        for i in 0..n {
+            assert_eq!(dance.press(0, KEY, at(100 * i)), None);
+            assert_eq!(dance.release(KEY, at(100 * i + 50)), None);
+        }
+        at(100 * (n - 1) + 50)
+    }
+
+    #[test]
+    fn single_tap() {
+        let mut dance = TapDance::new();
+        let released = tap(&mut dance, 1);
+        assert_eq!(dance.deadline(), Some(released + TAPPING_TERM));
+        // nothing until the tapping term is up, since another tap could follow
+        assert_eq!(dance.timeout(released + TAPPING_TERM - Duration::from_millis(1)), None);
+        assert_eq!(dance.timeout(released + TAPPING_TERM), Some(Resolution::Tap(ESCAPE)));
+        assert_eq!(dance.deadline(), None);
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut dance = TapDance::new();
+        let released = tap(&mut dance, 2);
+        assert_eq!(dance.timeout(released + TAPPING_TERM), Some(Resolution::Tap(TAB)));
+    }
+
+    #[test]
+    fn last_tap_resolves_at_once() {
+        let mut dance = TapDance::new();
+        assert_eq!(dance.press(0, KEY, at(0)), None);
+        assert_eq!(dance.release(KEY, at(50)), None);
+        assert_eq!(dance.press(0, KEY, at(100)), None);
+        assert_eq!(dance.release(KEY, at(150)), None);
+        assert_eq!(dance.press(0, KEY, at(200)), None);
+        assert_eq!(dance.release(KEY, at(250)), Some(Resolution::Tap(CAPS_LOCK)));
+        assert_eq!(dance.deadline(), None);
+    }
+
+    #[test]
+    fn hold() {
+        let mut dance = TapDance::new();
+        assert_eq!(dance.press(0, KEY, at(0)), None);
+        assert_eq!(dance.timeout(at(0) + TAPPING_TERM), Some(Resolution::Press(CONTROL)));
+        // the hold is released with the key, whenever that is
+        assert_eq!(dance.timeout(at(5000)), None);
+        assert_eq!(dance.release(KEY, at(5000)), Some(Resolution::Release(CONTROL)));
+    }
+
+    #[test]
+    fn hold_after_a_tap() {
+        let mut dance = TapDance::new();
+        assert_eq!(dance.press(0, KEY, at(0)), None);
+        assert_eq!(dance.release(KEY, at(50)), None);
+        assert_eq!(dance.press(0, KEY, at(100)), None);
+        // the hold action is the same whatever the count
+        assert_eq!(dance.timeout(at(100) + TAPPING_TERM), Some(Resolution::Press(CONTROL)));
+        assert_eq!(dance.release(KEY, at(400)), Some(Resolution::Release(CONTROL)));
+    }
+
+    #[test]
+    fn interrupted_tap() {
+        let mut dance = TapDance::new();
+        tap(&mut dance, 1);
+        assert_eq!(dance.interrupt(), Some(Resolution::Tap(ESCAPE)));
+        assert_eq!(dance.timeout(at(1000)), None);
+    }
+
+    #[test]
+    fn interrupted_while_held() {
+        let mut dance = TapDance::new();
+        assert_eq!(dance.press(0, KEY, at(0)), None);
+        // another key before the tapping term holds the tap action, not the hold action
+        assert_eq!(dance.interrupt(), Some(Resolution::Press(ESCAPE)));
+        assert_eq!(dance.release(KEY, at(300)), Some(Resolution::Release(ESCAPE)));
+    }
+
+    #[test]
+    fn another_dance_key_interrupts() {
+        let mut dance = TapDance::new();
+        tap(&mut dance, 2);
+        assert_eq!(dance.press(0, OTHER_KEY, at(200)), Some(Resolution::Tap(TAB)));
+        assert_eq!(dance.release(OTHER_KEY, at(250)), None);
+        assert_eq!(dance.timeout(at(250) + TAPPING_TERM), Some(Resolution::Tap(ESCAPE)));
+    }
+
+    #[test]
+    fn timeout_before_the_deadline_does_nothing() {
+        let mut dance = TapDance::new();
+        assert_eq!(dance.timeout(at(0)), None);
+        assert_eq!(dance.press(0, KEY, at(0)), None);
+        assert_eq!(dance.timeout(at(0)), None);
+        // a second press puts the deadline off again
+        assert_eq!(dance.release(KEY, at(150)), None);
+        assert_eq!(dance.press(0, KEY, at(300)), None);
+        assert_eq!(dance.timeout(at(150) + TAPPING_TERM), None);
+        assert_eq!(dance.timeout(at(300) + TAPPING_TERM), Some(Resolution::Press(CONTROL)));
+    }
+
+    #[test]
+    fn unknown_dance_is_ignored() {
+        let mut dance = TapDance::new();
+        assert_eq!(dance.press(TAP_DANCES.len() as u8, KEY, at(0)), None);
+        assert_eq!(dance.deadline(), None);
+        assert_eq!(dance.release(KEY, at(50)), None);
+    }
+}
@@ -1,7 +1,25 @@
-use crate::keys::{KeySignal, Layer, KEYMAP};
-use crate::{KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS};
-use crate::hardware_consts::N_KEYS;
-const N_KEYS_POWEROF2: usize = N_KEYS.next_power_of_two();
+use crate::keys::KeySignal;
+use crate::key_processor::KeyProcessor;
+use crate::extra_keys::ExtraKeysState;
+use crate::raw_hid::{self, RAW_HID_REPORT_DESCRIPTOR};
+use crate::raw_hid_protocol::REPORT_SIZE as RAW_HID_REPORT_SIZE;
+use crate::bootloader::{self, BOOTLOADER_COMBO};
+use crate::power;
+use crate::shell;
+use crate::sleep;
+use crate::split;
+use crate::storage;
+use crate::via::{self, VIA_REPORT_DESCRIPTOR, VIA_REPORT_SIZE};
+use crate::report::{KeyboardState, UsageEvent, BOOT_REPORT_SIZE, KEYBOARD_REPORT_DESCRIPTOR, KEYBOARD_REPORT_SIZE, NKRO_REPORT_ID};
+use crate::hid_class::{self, BootDevice, HidWriter, Protocol};
+use crate::host_leds::{set_host_leds, HostLeds};
+use crate::identity::IDENTITY;
+use crate::keys::{keymap_get, Action, Layer};
+use crate::lamp_array::{LAMP_ARRAY, KEY_COLORS_CHANGED};
+#[cfg(feature = "midi")]
+use crate::usb_midi::{self, NoteQueue, MIDI_PACKET_SIZE};
+use crate::{KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, KEYS_MUTEX_LAZY, KEY_INDEX_MAP};
+use crate::hardware_consts::{KEY_NAMES, N_KEYS};
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -10,31 +28,32 @@ use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
 use embassy_sync::signal::Signal;
 use embassy_sync::pubsub::{Subscriber, WaitResult};
-use embassy_futures::select::{select, Either};
-use embassy_futures::join::join;
-use embassy_time::Timer;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_futures::join::{join, join5};
+use embassy_time::{Instant, Timer};
 
 use embassy_usb::{Builder, Handler};
-use embassy_usb::class::hid::{RequestHandler, State, HidReaderWriter, ReportId};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use embassy_usb::class::hid::{self as embassy_hid, HidReaderWriter, RequestHandler, ReportId};
 use embassy_usb::control::OutResponse;
 
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor, KeyboardUsage};
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
-use heapless::index_set::FnvIndexSet;
+pub type KeySubscriber = Subscriber<'static, ThreadModeRawMutex, KeySignal, KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, N_KEYS>;
 
-const READ_REPORT_SIZE: usize = 1;
-const WRITE_REPORT_SIZE: usize = 8;
-
-static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// set when the host may have lost track of which keys are down (a bus reset or resume), so the
+/// whole keyboard state gets sent again
+static HOST_STATE_LOST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 #[embassy_executor::task]
 pub async fn usb_task(driver: Driver<'static, HardwareVbusDetect>, 
-                      mut key_subscriber: Subscriber<'static, ThreadModeRawMutex, KeySignal, KEYCHANGE_BUS_CAP, KEYCHANGE_BUS_SUBS, N_KEYS>) {
+                      mut key_subscriber: KeySubscriber) {
     
     let mut config = embassy_usb::Config::new(0xc0de, 0x1983);
     config.manufacturer = Some("Erik's Not-Industries");
-    config.product = Some("maghand-keyboard");
-    config.serial_number = Some("12345678");
+    let identity = IDENTITY.get();
+    config.product = Some(&identity.product);
+    config.serial_number = Some(&identity.serial_number);
     config.max_power = 500;
     config.max_packet_size_0 = 64;
     config.supports_remote_wakeup = true;
@@ -43,14 +62,22 @@ pub async fn usb_task(driver: Driver<'static, HardwareVbusDetect>,
     // config.device_sub_class = 0;
     // config.device_protocol = 0;
 
-    let mut config_descriptor = [0; 256];
+    let mut config_descriptor = [0; 512];
     let mut bos_descriptor = [0; 256];
     let mut msos_descriptor = [0; 256];
     let mut control_buf = [0; 64];
-    let mut request_handler = MaghandRequestHandler {};
+    let mut boot_request_handler = MaghandRequestHandler {};
+    let mut keyboard_request_handler = MaghandRequestHandler {};
     let mut device_handler = MaghandDeviceHandler::new();
+    // set up with the builder below, but has to outlive it
+    #[cfg(feature = "dfu_runtime")]
+    let mut dfu_runtime;
 
-    let mut state = State::new();
+    let mut boot_state = hid_class::State::new();
+    let mut keyboard_state = hid_class::State::new();
+    let mut cdc_state = cdc_acm::State::new();
+    let mut raw_hid_state = embassy_hid::State::new();
+    let mut via_state = embassy_hid::State::new();
 
     let mut builder = Builder::new(
         driver,
@@ -64,13 +91,61 @@ pub async fn usb_task(driver: Driver<'static, HardwareVbusDetect>,
     builder.handler(&mut device_handler);
     
     // Create classes on the builder.
-    let config = embassy_usb::class::hid::Config {
+    // The boot keyboard is what BIOSes and boot loaders see.  Everything else gets n-key rollover
+    // from the second interface, unless the host switches the boot interface into boot protocol.
+    // The nRF52840 only has seven IN endpoints, and MIDI only fits in them because the consumer,
+    // system and mouse reports share the second interface.
+    let boot_config = hid_class::Config {
         report_descriptor: KeyboardReport::desc(),
-        request_handler: None,
-        poll_ms: 60,
+        request_handler: Some(&mut boot_request_handler),
+        boot_device: BootDevice::Keyboard,
+        poll_ms: 1,
+        max_packet_size: 8,
+    };
+    let mut boot_writer = HidWriter::<_, BOOT_REPORT_SIZE>::new(&mut builder, &mut boot_state, boot_config);
+
+    let keyboard_config = hid_class::Config {
+        report_descriptor: &KEYBOARD_REPORT_DESCRIPTOR,
+        request_handler: Some(&mut keyboard_request_handler),
+        boot_device: BootDevice::None,
+        poll_ms: 1,
         max_packet_size: 64,
     };
-    let hid = HidReaderWriter::<_, READ_REPORT_SIZE, WRITE_REPORT_SIZE>::new(&mut builder, &mut state, config);
+    let mut keyboard_writer = HidWriter::<_, KEYBOARD_REPORT_SIZE>::new(&mut builder, &mut keyboard_state, keyboard_config);
+
+    // serial console for debugging without a probe - see `shell`
+    let mut cdc = CdcAcmClass::new(&mut builder, &mut cdc_state, 64);
+
+    // configuration and telemetry for host tools - see `raw_hid_protocol`
+    let raw_hid_config = embassy_hid::Config {
+        report_descriptor: RAW_HID_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: RAW_HID_REPORT_SIZE as u16,
+    };
+    let raw_hid = HidReaderWriter::<_, RAW_HID_REPORT_SIZE, RAW_HID_REPORT_SIZE>::new(&mut builder, &mut raw_hid_state, raw_hid_config);
+
+    // keymap editing from VIA - see `via`
+    let via_config = embassy_hid::Config {
+        report_descriptor: VIA_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: VIA_REPORT_SIZE as u16,
+    };
+    let via_hid = HidReaderWriter::<_, VIA_REPORT_SIZE, VIA_REPORT_SIZE>::new(&mut builder, &mut via_state, via_config);
+
+    // notes from the MIDI layer - see `midi`
+    #[cfg(feature = "midi")]
+    let mut midi_class = embassy_usb::class::midi::MidiClass::new(&mut builder, 1, 1, MIDI_PACKET_SIZE);
+    #[cfg(feature = "midi")]
+    let midi_notes = NoteQueue::new();
+
+    // lets `dfu-util -e` reboot into the bootloader - see `dfu_runtime`
+    #[cfg(feature = "dfu_runtime")]
+    {
+        dfu_runtime = crate::dfu_runtime::DfuRuntime::new(&mut builder);
+        builder.handler(&mut dfu_runtime);
+    }
 
     // Build the builder.
     let mut usb = builder.build();
@@ -93,99 +168,259 @@ pub async fn usb_task(driver: Driver<'static, HardwareVbusDetect>,
         }
     };
 
-    let (reader, mut writer) = hid.split();
-
     //let key_index_map = KEY_INDEX_MAP.get();
-    let keymap = KEYMAP.get();
-    let mut keysdown: FnvIndexSet<u8, N_KEYS_POWEROF2> = FnvIndexSet::new(); //TODO: add some check that this is the next power-of-two greater than N_KEYS
+    let mut kbstate = KeyboardState::new();
+    let mut extra_keys = ExtraKeysState::new();
+    let mut processor = KeyProcessor::new();
+    let keys_mutex = KEYS_MUTEX_LAZY.get();
+    let key_index_map = KEY_INDEX_MAP.get();
+    // the ADC sampler holds the keys only briefly, so if they're busy the last depth will do
+    let key_depth = |keynumber: u8| {
+        let keys = keys_mutex.try_lock().ok()?;
+        keys[*key_index_map.get(&keynumber)?].depth()
+    };
 
     // this is where the signal comes in and the key press is sent
     let in_fut = async {
         loop {
-            let toggle_data =  match key_subscriber.next_message().await {
-                WaitResult::Lagged(n) => {
-                    defmt::warn!("Key change subscriber lagged by {}", n);
-                    continue;
-                },
-                WaitResult::Message(data) => { data }
+            // timed work (macro steps, tap dance timeouts) is done in between key changes, so it never holds up typing
+            let deadline = processor.deadline();
+            let timed_work = async {
+                match deadline {
+                    Some(deadline) => Timer::at(deadline).await,
+                    None => core::future::pending().await,
+                }
             };
-            defmt::debug!("toggled key {} to {}", toggle_data.keynumber, toggle_data.toggle_on);
-
-            
-            if SUSPENDED.load(Ordering::Acquire) {
-                defmt::info!("Triggering remote wakeup");
-                remote_wakeup.signal(());
-                while SUSPENDED.load(Ordering::Acquire) {
-                    //TODO: test the right delay time here
-                    Timer::after(embassy_time::Duration::from_millis(5)).await;
+            let woken_by = select3(key_subscriber.next_message(), timed_work, HOST_STATE_LOST.wait()).await;
+
+            // take every key change that is already waiting too, so they can share a report
+            let mut message = match woken_by {
+                Either3::First(message) => Some(message),
+                Either3::Second(()) => None,
+                Either3::Third(()) => {
+                    defmt::debug!("resending the keyboard state");
+                    kbstate.resend();
+                    None
+                }
+            };
+            while let Some(message) = message.take().or_else(|| key_subscriber.try_next_message()) {
+                let toggle_data = match message {
+                    WaitResult::Lagged(n) => {
+                        defmt::warn!("Key change subscriber lagged by {}", n);
+                        continue;
+                    }
+                    WaitResult::Message(data) => data,
+                };
+                defmt::debug!("toggled key {} to {}", toggle_data.keynumber, toggle_data.toggle_on);
+                sleep::activity();
+
+                // the key is held back rather than dropped until the host is listening again
+                if power::is_suspended() {
+                    defmt::info!("Triggering remote wakeup");
+                    power::RESUMED.reset();
+                    remote_wakeup.signal(());
+                    power::RESUMED.wait().await;
                 }
-            }
 
-            match keymap.get(&(toggle_data.keynumber, Layer::Default)) {
-                Some(keycoderef) => {
-                    let keycode = (*keycoderef) as u8;
+                processor.key_changed(toggle_data, Instant::now());
+                if BOOTLOADER_COMBO.iter().all(|k| processor.is_held(*k)) {
+                    bootloader::request();
+                }
+            }
 
-                    if toggle_data.toggle_on {
-                        keysdown.insert(keycode).expect("keysdown full");
-                    } else {
-                        let was_present = keysdown.remove(&keycode);
-                        if !was_present {
-                            defmt::warn!("On keyup, {} wasnt down", keycode);
-                        }
+            let now = Instant::now();
+            // the key states are authoritative, in case any changes were dropped on the way here
+            if let Ok(keys) = keys_mutex.try_lock() {
+                processor.resync(|keynumber| match key_index_map.get(&keynumber) {
+                    Some(index) => keys[*index].is_on() == Some(true),
+                    None => split::remote_key_is_down(keynumber),
+                }, now);
+            }
+            processor.poll(now, key_depth);
+            // everything that is due goes out in as few reports as possible, while the last one is
+            // still waiting for the host to poll it
+            while let Some(usage_event) = processor.next_event(now) {
+                if usage_event.is_keyboard() {
+                    if kbstate.must_send_before(usage_event) {
+                        send_report(&mut boot_writer, &mut keyboard_writer, &mut kbstate).await;
                     }
-
-                    let keycodes = {
-                        if keysdown.len() > 6 {
-                            [KeyboardUsage::KeyboardErrorRollOver as u8 ; 6]
-                        } else {
-                            let mut arr = [0u8; 6];
-                            for (i, kc) in keysdown.iter().enumerate() {
-                                arr[i] = *kc;
-                            }
-                            arr
+                    kbstate.apply(usage_event);
+                } else if let UsageEvent::Note(change) = usage_event {
+                    #[cfg(feature = "midi")]
+                    if midi_notes.try_send(change).is_err() {
+                        defmt::warn!("MIDI note queue full, dropping {}", change);
+                    }
+                    #[cfg(not(feature = "midi"))]
+                    defmt::debug!("built without MIDI, dropping {}", change);
+                } else {
+                    // keyboard changes that came first go first
+                    if kbstate.is_dirty() {
+                        send_report(&mut boot_writer, &mut keyboard_writer, &mut kbstate).await;
+                    }
+                    if let Some(report) = extra_keys.apply(usage_event) {
+                        defmt::debug!("Sending usb extra keys report: {}", report);
+                        if let Err(e) = keyboard_writer.write(&report).await {
+                            defmt::warn!("Failed to send extra keys report: {:?}", e);
                         }
-                    };
-
-                    let report = KeyboardReport {
-                        keycodes: keycodes,
-                        leds: 0,
-                        modifier: 0,
-                        reserved: 0,
-                    };
+                    }
+                }
+            }
+            if kbstate.is_dirty() {
+                send_report(&mut boot_writer, &mut keyboard_writer, &mut kbstate).await;
+            }
+        }
+    };
 
-                    defmt::debug!("Sending usb kb keycodes: {}", keycodes);
+    let shell_fut = shell::run(&mut cdc);
 
-                    match writer.write_serialize(&report).await {
-                        Ok(()) => {}
-                        Err(e) => defmt::warn!("Failed to send report: {:?}", e),
-                    };
-                },
-                None => { defmt::warn!("No keycode mapped for keynumber {}, skipping", toggle_data.keynumber);  },
+    let (mut raw_hid_reader, mut raw_hid_writer) = raw_hid.split();
+    let raw_hid_fut = async {
+        let mut request = [0u8; RAW_HID_REPORT_SIZE];
+        // the last analog stream report sent, so unchanged ones aren't sent again
+        let mut last_analog_report = [0u8; RAW_HID_REPORT_SIZE];
+        loop {
+            let read = raw_hid_reader.read(&mut request);
+            let result = match raw_hid::analog_stream_period() {
+                Some(period) => select(read, Timer::after(period)).await,
+                None => Either::First(read.await),
+            };
+            match result {
+                Either::First(Ok(n)) => {
+                    let response = raw_hid::handle(&request[..n]).await;
+                    if let Err(e) = raw_hid_writer.write(&response).await {
+                        defmt::warn!("Failed to send raw hid response: {:?}", e);
+                    }
+                    // whoever sent it might be a new reader of the stream
+                    last_analog_report = [0; RAW_HID_REPORT_SIZE];
+                }
+                Either::First(Err(e)) => {
+                    defmt::warn!("Failed to read raw hid request: {:?}", e);
+                    raw_hid_reader.ready().await;
+                }
+                Either::Second(()) => {
+                    let report = raw_hid::analog_report().await;
+                    if report != last_analog_report {
+                        if let Err(e) = raw_hid_writer.write(&report).await {
+                            defmt::warn!("Failed to send analog report: {:?}", e);
+                        }
+                        last_analog_report = report;
+                    }
+                }
             }
         }
     };
 
-    let out_fut = async {
-        reader.run(false, &mut request_handler).await;
+    let (mut via_reader, mut via_writer) = via_hid.split();
+    let via_fut = async {
+        let mut report = [0u8; VIA_REPORT_SIZE];
+        // VIA edits are saved once they stop coming, rather than erasing flash for every key
+        let mut save_at: Option<Instant> = None;
+        loop {
+            let read = via_reader.read(&mut report);
+            let result = match save_at {
+                Some(deadline) => select(read, Timer::at(deadline)).await,
+                None => Either::First(read.await),
+            };
+            match result {
+                Either::First(Ok(n)) => {
+                    report[n..].fill(0);
+                    if via::handle(&mut report).await {
+                        save_at = Some(Instant::now() + via::SAVE_DELAY);
+                    }
+                    if let Err(e) = via_writer.write(&report).await {
+                        defmt::warn!("Failed to send VIA response: {:?}", e);
+                    }
+                }
+                Either::First(Err(e)) => {
+                    defmt::warn!("Failed to read VIA request: {:?}", e);
+                    via_reader.ready().await;
+                }
+                Either::Second(()) => {
+                    save_at = None;
+                    if let Err(e) = storage::save().await {
+                        defmt::warn!("Failed to save VIA edits: {}", e);
+                    }
+                }
+            }
+        }
     };
 
+    #[cfg(feature = "midi")]
+    let midi_fut = usb_midi::run(&mut midi_class, &midi_notes);
+    #[cfg(not(feature = "midi"))]
+    let midi_fut = core::future::pending::<()>();
+
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, join(in_fut, out_fut)).await;
+    join(join5(usb_fut, in_fut, shell_fut, raw_hid_fut, via_fut), midi_fut).await;
+}
+
+/// Sends the current state on whichever interface the host is listening to
+async fn send_report<'d>(boot_writer: &mut HidWriter<'d, Driver<'d, HardwareVbusDetect>, BOOT_REPORT_SIZE>,
+                         keyboard_writer: &mut HidWriter<'d, Driver<'d, HardwareVbusDetect>, KEYBOARD_REPORT_SIZE>,
+                         kbstate: &mut KeyboardState) {
+    let result = match boot_writer.protocol() {
+        Protocol::Boot => {
+            let report = kbstate.boot_report();
+            defmt::debug!("Sending usb kb boot report: {}", report);
+            boot_writer.write(&report).await
+        }
+        Protocol::Report => {
+            let report = kbstate.nkro_report();
+            defmt::debug!("Sending usb kb nkro report: {}", report);
+            keyboard_writer.write(&report).await
+        }
+    };
+
+    // a report that failed to go out isn't retried, so don't hold the batching up waiting for it
+    kbstate.mark_sent();
+    match result {
+        Ok(()) => {}
+        Err(e) => defmt::warn!("Failed to send report: {:?}", e),
+    };
 }
 
 
 struct MaghandRequestHandler {}
 
 impl RequestHandler for MaghandRequestHandler {
-    fn get_report(&mut self, id: ReportId, _buf: &mut [u8]) -> Option<usize> {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
         defmt::debug!("Get report for {:?}", id);
-        None
+        match id {
+            // the lamp array is all feature reports - see `lamp_array`
+            ReportId::Feature(id) => LAMP_ARRAY.lock(|lamp_array| {
+                lamp_array.borrow_mut().get_report(id, buf, |lamp| match keymap_get(KEY_NAMES[lamp], Layer::Default) {
+                    Some(Action::Key(usage)) => Some(usage as u8),
+                    _ => None,
+                })
+            }),
+            _ => None,
+        }
     }
 
     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
         defmt::debug!("Set report for {:?}: {=[u8]}", id, data);
-        OutResponse::Accepted
+        match (id, data) {
+            // both keyboard interfaces have the LED byte as their only output report, but only the
+            // n-key rollover one has report IDs
+            (ReportId::Out(0), [leds, ..]) | (ReportId::Out(NKRO_REPORT_ID), [NKRO_REPORT_ID, leds, ..]) => {
+                set_host_leds(HostLeds(*leds));
+                OutResponse::Accepted
+            }
+            (ReportId::Feature(id), data) => match LAMP_ARRAY.lock(|lamp_array| lamp_array.borrow_mut().set_report(id, data)) {
+                Ok(redraw) => {
+                    if redraw {
+                        KEY_COLORS_CHANGED.signal(());
+                    }
+                    OutResponse::Accepted
+                }
+                Err(e) => {
+                    defmt::warn!("bad lamp array report {}: {}", id, e);
+                    OutResponse::Rejected
+                }
+            },
+            _ => OutResponse::Rejected,
+        }
     }
 
     fn set_idle_ms(&mut self, id: Option<ReportId>, dur: u32) {
@@ -213,7 +448,10 @@ impl MaghandDeviceHandler {
 impl Handler for MaghandDeviceHandler {
     fn enabled(&mut self, enabled: bool) {
         self.configured.store(false, Ordering::Relaxed);
-        SUSPENDED.store(false, Ordering::Release);
+        power::set_suspended(false);
+        power::set_vbus(enabled);
+        // a new host (or none) will send its own LED state
+        set_host_leds(HostLeds::default());
         if enabled {
             defmt::debug!("Device enabled");
         } else {
@@ -223,6 +461,7 @@ impl Handler for MaghandDeviceHandler {
 
     fn reset(&mut self) {
         self.configured.store(false, Ordering::Relaxed);
+        HOST_STATE_LOST.signal(());
         defmt::debug!("Bus reset, the Vbus current limit is 100mA");
     }
 
@@ -243,9 +482,10 @@ impl Handler for MaghandDeviceHandler {
     fn suspended(&mut self, suspended: bool) {
         if suspended {
             defmt::debug!("Device suspended, the Vbus current limit is 500µA (or 2.5mA for high-power devices with remote wakeup enabled).");
-            SUSPENDED.store(true, Ordering::Release);
+            power::set_suspended(true);
         } else {
-            SUSPENDED.store(false, Ordering::Release);
+            power::set_suspended(false);
+            HOST_STATE_LOST.signal(());
             if self.configured.load(Ordering::Relaxed) {
                 defmt::debug!("Device resumed, it may now draw up to the configured current limit from Vbus");
             } else {
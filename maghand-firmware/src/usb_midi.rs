@@ -0,0 +1,119 @@
+//! The USB MIDI interface, which plays the `NoteChange`s from the key processor - see `midi`.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::midi::MidiClass;
+use embassy_usb::driver::{Driver, EndpointError};
+
+use heapless::Vec;
+
+use crate::hardware_consts::N_KEYS;
+use crate::midi::NoteChange;
+use crate::{KEYS_MUTEX_LAZY, KEY_INDEX_MAP};
+
+/// room for a change from every key at once
+pub const NOTE_QUEUE_LEN: usize = 2 * N_KEYS;
+pub type NoteQueue = Channel<CriticalSectionRawMutex, NoteChange, NOTE_QUEUE_LEN>;
+pub const MIDI_PACKET_SIZE: u16 = 64;
+
+/// channel 1
+const MIDI_CHANNEL: u8 = 0;
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const POLY_PRESSURE: u8 = 0xA0;
+/// the velocity for presses that weren't timed (e.g. from a tap dance, or a key that was already down)
+const DEFAULT_VELOCITY: u8 = 100;
+const RELEASE_VELOCITY: u8 = 64;
+/// presses this fast or faster are full velocity...
+const FASTEST_PRESS: Duration = Duration::from_millis(4);
+/// ...and this slow or slower the softest
+const SLOWEST_PRESS: Duration = Duration::from_millis(80);
+const AFTERTOUCH_PERIOD: Duration = Duration::from_millis(10);
+/// USB MIDI events are 4 bytes - a full packet would need a zero length packet after it, so stop short
+const MAX_PACKET_EVENTS: usize = MIDI_PACKET_SIZE as usize / 4 - 1;
+
+/// the note-on velocity for a press that took `travel` to go down
+fn velocity(travel: Option<Duration>) -> u8 {
+    let Some(travel) = travel else { return DEFAULT_VELOCITY };
+    let travel = travel.clamp(FASTEST_PRESS, SLOWEST_PRESS);
+    let softness = (travel - FASTEST_PRESS).as_micros() as f32 / (SLOWEST_PRESS - FASTEST_PRESS).as_micros() as f32;
+    1 + ((1. - softness) * 126.) as u8
+}
+
+/// a channel message as a USB MIDI event packet on cable 0, whose code index number is the status
+fn event(status: u8, data1: u8, data2: u8) -> [u8; 4] {
+    [status >> 4, status | MIDI_CHANNEL, data1, data2]
+}
+
+/// a note that is sounding
+struct Held {
+    keynumber: u8,
+    note: u8,
+    /// the last aftertouch sent for it
+    pressure: Option<u8>,
+}
+
+/// Plays the notes from `notes` for as long as the device is up, one session per host connection.
+pub async fn run<'d, D: Driver<'d>>(class: &mut MidiClass<'d, D>, notes: &NoteQueue) -> ! {
+    loop {
+        class.wait_connection().await;
+        defmt::info!("MIDI connected");
+        if let Err(e) = session(class, notes).await {
+            defmt::info!("MIDI disconnected: {:?}", e);
+        }
+    }
+}
+
+async fn session<'d, D: Driver<'d>>(class: &mut MidiClass<'d, D>, notes: &NoteQueue) -> Result<(), EndpointError> {
+    let keys_mutex = KEYS_MUTEX_LAZY.get();
+    let key_index_map = KEY_INDEX_MAP.get();
+    // notes from before the host was listening would only come out late
+    notes.clear();
+    let mut held: Vec<Held, N_KEYS> = Vec::new();
+    loop {
+        let mut change = if held.is_empty() {
+            Some(notes.receive().await)
+        } else {
+            match select(notes.receive(), Timer::after(AFTERTOUCH_PERIOD)).await {
+                Either::First(change) => Some(change),
+                Either::Second(()) => None,
+            }
+        };
+
+        let mut packet: Vec<u8, { MIDI_PACKET_SIZE as usize }> = Vec::new();
+        let keys = keys_mutex.lock().await;
+        if change.is_some() {
+            // take every change that is already waiting too, so they can share a packet
+            while packet.len() < MAX_PACKET_EVENTS * 4
+                && let Some(change) = change.take().or_else(|| notes.try_receive().ok()) {
+                let message = if change.on {
+                    let travel = key_index_map.get(&change.keynumber).and_then(|i| keys[*i].press_travel_time());
+                    if held.push(Held { keynumber: change.keynumber, note: change.note, pressure: None }).is_err() {
+                        defmt::warn!("too many notes held, {} gets no aftertouch", change.note);
+                    }
+                    event(NOTE_ON, change.note, velocity(travel))
+                } else {
+                    held.retain(|h| (h.keynumber, h.note) != (change.keynumber, change.note));
+                    event(NOTE_OFF, change.note, RELEASE_VELOCITY)
+                };
+                packet.extend_from_slice(&message).ok();
+            }
+        } else {
+            for held in held.iter_mut() {
+                let Some(depth) = key_index_map.get(&held.keynumber).and_then(|i| keys[*i].depth()) else { continue };
+                let pressure = (depth.clamp(0., 1.) * 127.) as u8;
+                if held.pressure != Some(pressure) && packet.len() < MAX_PACKET_EVENTS * 4 {
+                    held.pressure = Some(pressure);
+                    packet.extend_from_slice(&event(POLY_PRESSURE, held.note, pressure)).ok();
+                }
+            }
+        }
+        drop(keys);
+
+        if !packet.is_empty() {
+            class.write_packet(&packet).await?;
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! The VIA raw HID protocol, so the keymap and macros can be edited live with VIA (or anything else
+//! that speaks it) alongside our own `raw_hid_protocol`.
+//!
+//! VIA sees the keyboard as a QMK dynamic keymap: a `MATRIX_ROWS` × `MATRIX_COLS` grid per layer,
+//! with key number `rc` at row r and column c, holding 16-bit QMK keycodes.  Those are translated to
+//! and from `Action`s here, so edits land in the same live `KEYMAP` everything else uses.  QMK
+//! keycodes with no matching action (mod-taps, layer-taps and the like) are refused, leaving the key
+//! as it was.  Actions with no QMK keycode read back as `KC_NO`, and since VIA writes back whole
+//! buffers it has read, `KC_NO` over one of them leaves it alone too - `KC_TRNS` unmaps it.  An
+//! unmapped key falls through to the layers below, so it reads back as `KC_TRNS` on every layer but
+//! the default one.
+//!
+//! VIA expects edits to stick without an explicit save, as they do in QMK's EEPROM.  Since erasing
+//! flash stalls the CPU, the caller saves once the edits have stopped for `SAVE_DELAY` rather than
+//! after every one.  Vial's own commands (0xFE) are not implemented and are answered as unhandled.
+//! `via/maghand.json` is the keyboard definition to load into VIA, or `via/maghand-split.json` for
+//! the central half of a split keyboard.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant};
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::bootloader;
+use crate::extra_keys::{ConsumerUsage, SystemUsage};
+use crate::hardware_consts::{ALL_KEY_NAMES, SPLIT_CENTRAL};
+use crate::split;
+use crate::keys::{keymap_get, keymap_reset, keymap_set, Action, Layer, N_LAYERS};
+use crate::macros::{default_macros, MACRO_BUFFER, MACRO_BUFFER_SIZE};
+use crate::mouse_keys::{MouseDirection, MOUSE_BUTTONS};
+use crate::{KEYS_MUTEX_LAZY, KEY_INDEX_MAP};
+
+pub const VIA_REPORT_SIZE: usize = 32;
+pub const VIA_PROTOCOL_VERSION: u16 = 0x000C;
+/// how long after the last edit to save to flash
+pub const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// twice as many on the central half of a split keyboard, for the other half's keys
+pub const MATRIX_ROWS: u8 = if SPLIT_CENTRAL { 12 } else { 6 };
+pub const MATRIX_COLS: u8 = 4;
+/// how many macros VIA shows - they share the `MACRO_BUFFER`, so this is only an upper bound
+const MACRO_COUNT: u8 = 16;
+/// the most data bytes a buffer get or set can carry after the command, offset and size
+const MAX_BUFFER_CHUNK: usize = VIA_REPORT_SIZE - 4;
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_EEPROM_RESET: u8 = 0x0A;
+const ID_BOOTLOADER_JUMP: u8 = 0x0B;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_UNHANDLED: u8 = 0xFF;
+
+const ID_UPTIME: u8 = 0x01;
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+const ID_SWITCH_MATRIX_STATE: u8 = 0x03;
+const ID_FIRMWARE_VERSION: u8 = 0x04;
+const ID_DEVICE_INDICATION: u8 = 0x05;
+
+/// QMK keycodes, as of VIA protocol 12
+const KC_NO: u16 = 0x0000;
+const KC_TRNS: u16 = 0x0001;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_ONE_SHOT_MOD: u16 = 0x52A0;
+const QK_TAP_DANCE: u16 = 0x5700;
+const QK_MIDI_NOTE_C_0: u16 = 0x7100;
+/// QMK has 6 octaves of note keycodes, which play from C3 at its default octave
+const QMK_MIDI_NOTES: u8 = 72;
+const QMK_MIDI_FIRST_NOTE: u8 = 48;
+const QK_MACRO: u16 = 0x7700;
+const QK_DYNAMIC_MACRO_RECORD_START_1: u16 = 0x7C53;
+const QK_DYNAMIC_MACRO_RECORD_STOP: u16 = 0x7C55;
+const QK_DYNAMIC_MACRO_PLAY_1: u16 = 0x7C56;
+const QK_LEADER: u16 = 0x7C58;
+const QK_CAPS_WORD_TOGGLE: u16 = 0x7C73;
+const KC_MS_UP: u16 = 0x00CD;
+const KC_MS_BTN1: u16 = 0x00D1;
+const KC_MS_WH_UP: u16 = 0x00D9;
+/// QMK orders mouse directions as up, down, left, right - the same as `MouseDirection`
+const MOUSE_DIRECTIONS: [MouseDirection; 4] = [MouseDirection::Up, MouseDirection::Down, MouseDirection::Left, MouseDirection::Right];
+
+/// the QMK basic keycodes for consumer and system usages
+const CONSUMER_KEYCODES: [(u16, ConsumerUsage); 17] = [
+    (0x00A8, ConsumerUsage::Mute),
+    (0x00A9, ConsumerUsage::VolumeIncrement),
+    (0x00AA, ConsumerUsage::VolumeDecrement),
+    (0x00AB, ConsumerUsage::NextTrack),
+    (0x00AC, ConsumerUsage::PrevTrack),
+    (0x00AD, ConsumerUsage::Stop),
+    (0x00AE, ConsumerUsage::PlayPause),
+    (0x00B0, ConsumerUsage::Eject),
+    (0x00B1, ConsumerUsage::LaunchMail),
+    (0x00B2, ConsumerUsage::LaunchCalculator),
+    (0x00B4, ConsumerUsage::Search),
+    (0x00B5, ConsumerUsage::BrowserHome),
+    (0x00B6, ConsumerUsage::BrowserBack),
+    (0x00B7, ConsumerUsage::BrowserForward),
+    (0x00B9, ConsumerUsage::BrowserRefresh),
+    (0x00BD, ConsumerUsage::BrightnessIncrement),
+    (0x00BE, ConsumerUsage::BrightnessDecrement),
+];
+const SYSTEM_KEYCODES: [(u16, SystemUsage); 3] = [
+    (0x00A5, SystemUsage::PowerDown),
+    (0x00A6, SystemUsage::Sleep),
+    (0x00A7, SystemUsage::WakeUp),
+];
+
+/// the layout options VIA picked from the keyboard definition - saved, but otherwise unused
+static LAYOUT_OPTIONS: AtomicU32 = AtomicU32::new(0);
+
+pub fn layout_options() -> u32 {
+    LAYOUT_OPTIONS.load(Ordering::Relaxed)
+}
+
+pub fn set_layout_options(options: u32) {
+    LAYOUT_OPTIONS.store(options, Ordering::Relaxed);
+}
+
+/// VIA protocol usages: page 0xFF60, usage 0x61, with 32-byte input and output reports
+pub const VIA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,  // Usage Page (Vendor 0xFF60)
+    0x09, 0x61,        // Usage (0x61)
+    0xA1, 0x01,        // Collection (Application)
+    0x09, 0x62,        //   Usage (0x62)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, VIA_REPORT_SIZE as u8, // Report Count
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0x09, 0x63,        //   Usage (0x63)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, VIA_REPORT_SIZE as u8, // Report Count
+    0x91, 0x02,        //   Output (Data, Variable, Absolute)
+    0xC0,              // End Collection
+];
+
+/// The QMK keycode for a keymap entry.
+pub fn keycode(action: Option<Action>, layer: Layer) -> u16 {
+    let Some(action) = action else {
+        return if layer == Layer::Default { KC_NO } else { KC_TRNS };
+    };
+    qmk_keycode(action).unwrap_or_else(|| {
+        defmt::warn!("no QMK keycode for action {}", action.encode());
+        KC_NO
+    })
+}
+
+/// The QMK keycode for an action, if it has one.
+fn qmk_keycode(action: Action) -> Option<u16> {
+    match action {
+        Action::Key(usage) => match usage as u8 {
+            usage @ (0x04..=0xA4 | 0xE0..=0xE7) => Some(usage as u16),
+            _ => None,
+        },
+        Action::Consumer(usage) => CONSUMER_KEYCODES.iter()
+            .find(|(_, u)| *u == usage)
+            .map(|(keycode, _)| *keycode),
+        Action::System(usage) => SYSTEM_KEYCODES.iter().find(|(_, u)| *u == usage).map(|(keycode, _)| *keycode),
+        Action::MouseMove(direction) => Some(KC_MS_UP + direction as u16),
+        Action::MouseWheel(direction) => Some(KC_MS_WH_UP + direction as u16),
+        Action::MouseButton(n) => (n < MOUSE_BUTTONS).then_some(KC_MS_BTN1 + n as u16),
+        Action::Macro(n) => (n < 0x80).then_some(QK_MACRO + n as u16),
+        Action::DynamicMacroRecord(n) => (n < 2).then_some(QK_DYNAMIC_MACRO_RECORD_START_1 + n as u16),
+        Action::DynamicMacroStop => Some(QK_DYNAMIC_MACRO_RECORD_STOP),
+        Action::DynamicMacroPlay(n) => (n < 2).then_some(QK_DYNAMIC_MACRO_PLAY_1 + n as u16),
+        Action::TapDance(n) => Some(QK_TAP_DANCE + n as u16),
+        Action::Leader => Some(QK_LEADER),
+        Action::LayerMomentary(layer) => Some(QK_MOMENTARY + layer as u16),
+        Action::LayerToggle(layer) => Some(QK_TOGGLE_LAYER + layer as u16),
+        Action::CapsWord => Some(QK_CAPS_WORD_TOGGLE),
+        Action::OneShot(usage) => match usage as u8 {
+            // QMK's five mod bits are ctrl, shift, alt, gui and "right hand"
+            modifier @ 0xE0..=0xE3 => Some(QK_ONE_SHOT_MOD | 1 << (modifier - 0xE0)),
+            modifier @ 0xE4..=0xE7 => Some(QK_ONE_SHOT_MOD | 0x10 | 1 << (modifier - 0xE4)),
+            _ => None,
+        },
+        Action::MidiNote(note) => note.checked_sub(QMK_MIDI_FIRST_NOTE)
+            .filter(|n| *n < QMK_MIDI_NOTES)
+            .map(|n| QK_MIDI_NOTE_C_0 + n as u16),
+    }
+}
+
+/// The keymap entry for a QMK keycode: Ok(None) unmaps the key, Err if there is no such action.
+pub fn action(keycode: u16) -> Result<Option<Action>, ()> {
+    let byte = keycode as u8;
+    let action = match keycode {
+        KC_NO | KC_TRNS => return Ok(None),
+        0x0004..=0x00A4 | 0x00E0..=0x00E7 => Action::Key(KeyboardUsage::from(byte)),
+        0x00A5..=0x00A7 => SYSTEM_KEYCODES.iter().find(|(k, _)| *k == keycode).map(|(_, u)| Action::System(*u)).ok_or(())?,
+        0x00A8..=0x00BE => CONSUMER_KEYCODES.iter()
+            .find(|(k, _)| *k == keycode)
+            .map(|(_, u)| Action::Consumer(*u))
+            .ok_or(())?,
+        0x00CD..=0x00D0 => Action::MouseMove(MOUSE_DIRECTIONS[(keycode - KC_MS_UP) as usize]),
+        0x00D1..=0x00D8 => Action::MouseButton(Some((keycode - KC_MS_BTN1) as u8).filter(|n| *n < MOUSE_BUTTONS).ok_or(())?),
+        0x00D9..=0x00DC => Action::MouseWheel(MOUSE_DIRECTIONS[(keycode - KC_MS_WH_UP) as usize]),
+        0x5220..=0x523F => Action::LayerMomentary(Layer::from_index(byte & 0x1F).ok_or(())?),
+        0x5260..=0x527F => Action::LayerToggle(Layer::from_index(byte & 0x1F).ok_or(())?),
+        0x52A0..=0x52BF => {
+            let mods = byte & 0x0F;
+            if mods.count_ones() != 1 {
+                return Err(()); // only single modifiers can be one-shot
+            }
+            let first = if byte & 0x10 != 0 { 0xE4 } else { 0xE0 };
+            Action::OneShot(KeyboardUsage::from(first + mods.trailing_zeros() as u8))
+        }
+        0x5700..=0x57FF => Action::TapDance(byte),
+        0x7100..=0x7147 => Action::MidiNote(QMK_MIDI_FIRST_NOTE + (keycode - QK_MIDI_NOTE_C_0) as u8),
+        0x7700..=0x777F => Action::Macro(byte),
+        0x7C53..=0x7C54 => Action::DynamicMacroRecord((keycode - QK_DYNAMIC_MACRO_RECORD_START_1) as u8),
+        QK_DYNAMIC_MACRO_RECORD_STOP => Action::DynamicMacroStop,
+        0x7C56..=0x7C57 => Action::DynamicMacroPlay((keycode - QK_DYNAMIC_MACRO_PLAY_1) as u8),
+        QK_LEADER => Action::Leader,
+        QK_CAPS_WORD_TOGGLE => Action::CapsWord,
+        _ => return Err(()),
+    };
+    Ok(Some(action))
+}
+
+/// the key number at a matrix position, if there is a key there
+fn matrix_key(row: u8, col: u8) -> Option<u8> {
+    let keynumber = row.checked_mul(10)?.checked_add(col)?;
+    (row < MATRIX_ROWS && col < MATRIX_COLS && ALL_KEY_NAMES.contains(&keynumber)).then_some(keynumber)
+}
+
+fn get_keycode(layer: u8, row: u8, col: u8) -> u16 {
+    match (Layer::from_index(layer), matrix_key(row, col)) {
+        (Some(layer), Some(keynumber)) => keycode(keymap_get(keynumber, layer), layer),
+        _ => KC_NO,
+    }
+}
+
+fn set_keycode(layer: u8, row: u8, col: u8, keycode: u16) {
+    let (Some(layer), Some(keynumber)) = (Layer::from_index(layer), matrix_key(row, col)) else {
+        return;
+    };
+    let Ok(action) = action(keycode) else {
+        defmt::warn!("unsupported QMK keycode {=u16:#x} for key {}, leaving it as it was", keycode, keynumber);
+        return;
+    };
+    // what an action with no QMK keycode read back as, written back
+    if keycode == KC_NO && keymap_get(keynumber, layer).is_some_and(|current| qmk_keycode(current).is_none()) {
+        return;
+    }
+    keymap_set(keynumber, layer, action);
+}
+
+/// the layer, row and column of keycode `index` in the dynamic keymap buffer
+fn buffer_position(index: usize) -> (u8, u8, u8) {
+    let per_layer = MATRIX_ROWS as usize * MATRIX_COLS as usize;
+    let position = index % per_layer;
+    ((index / per_layer) as u8, (position / MATRIX_COLS as usize) as u8, (position % MATRIX_COLS as usize) as u8)
+}
+
+/// the offset and size of a buffer chunk, if it fits in a report and within `buffer_size`
+fn chunk(data: &[u8; VIA_REPORT_SIZE], buffer_size: usize) -> Option<(usize, usize)> {
+    let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let size = data[3] as usize;
+    (size <= MAX_BUFFER_CHUNK && offset + size <= buffer_size).then_some((offset, size))
+}
+
+fn put_u32(data: &mut [u8], value: u32) {
+    data[..4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Carries out the command in `data` and overwrites it with the reply, as QMK does.  Returns whether
+/// anything that should be saved changed.
+pub async fn handle(data: &mut [u8; VIA_REPORT_SIZE]) -> bool {
+    let keymap_buffer_size = N_LAYERS * MATRIX_ROWS as usize * MATRIX_COLS as usize * 2;
+    match data[0] {
+        ID_GET_PROTOCOL_VERSION => data[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes()),
+        ID_GET_KEYBOARD_VALUE => match data[1] {
+            ID_UPTIME => put_u32(&mut data[2..], Instant::now().as_millis() as u32),
+            ID_LAYOUT_OPTIONS => put_u32(&mut data[2..], layout_options()),
+            ID_SWITCH_MATRIX_STATE => {
+                // one byte per row, starting at the requested row
+                let first_row = data[2];
+                data[3..].fill(0);
+                let keys = KEYS_MUTEX_LAZY.get().lock().await;
+                let key_index_map = KEY_INDEX_MAP.get();
+                for (row, byte) in (first_row..MATRIX_ROWS).zip(data[3..].iter_mut()) {
+                    for col in 0..MATRIX_COLS {
+                        let on = matrix_key(row, col).is_some_and(|k| match key_index_map.get(&k) {
+                            Some(index) => keys[*index].is_on() == Some(true),
+                            None => split::remote_key_is_down(k),
+                        });
+                        *byte |= (on as u8) << col;
+                    }
+                }
+            }
+            ID_FIRMWARE_VERSION => {
+                let version = |part: &str| part.parse::<u32>().unwrap_or(0);
+                let encoded = version(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+                    | version(env!("CARGO_PKG_VERSION_MINOR")) << 8
+                    | version(env!("CARGO_PKG_VERSION_PATCH"));
+                put_u32(&mut data[2..], encoded);
+            }
+            _ => data[0] = ID_UNHANDLED,
+        },
+        ID_SET_KEYBOARD_VALUE => match data[1] {
+            ID_LAYOUT_OPTIONS => {
+                set_layout_options(u32::from_be_bytes([data[2], data[3], data[4], data[5]]));
+                return true;
+            }
+            ID_DEVICE_INDICATION => defmt::info!("VIA is identifying this keyboard"),
+            _ => data[0] = ID_UNHANDLED,
+        },
+        ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+            let keycode = get_keycode(data[1], data[2], data[3]);
+            data[4..6].copy_from_slice(&keycode.to_be_bytes());
+        }
+        ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+            set_keycode(data[1], data[2], data[3], u16::from_be_bytes([data[4], data[5]]));
+            return true;
+        }
+        ID_DYNAMIC_KEYMAP_RESET => {
+            keymap_reset();
+            return true;
+        }
+        ID_EEPROM_RESET => {
+            keymap_reset();
+            MACRO_BUFFER.get().lock(|buffer| *buffer.borrow_mut() = default_macros());
+            set_layout_options(0);
+            return true;
+        }
+        ID_BOOTLOADER_JUMP => bootloader::request(),
+        ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[1] = MACRO_COUNT,
+        ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => data[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes()),
+        ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+            if let Some((offset, size)) = chunk(data, MACRO_BUFFER_SIZE) {
+                MACRO_BUFFER.get().lock(|buffer| {
+                    data[4..4 + size].copy_from_slice(&buffer.borrow()[offset..offset + size]);
+                });
+            }
+        }
+        ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+            if let Some((offset, size)) = chunk(data, MACRO_BUFFER_SIZE) {
+                MACRO_BUFFER.get().lock(|buffer| {
+                    buffer.borrow_mut()[offset..offset + size].copy_from_slice(&data[4..4 + size]);
+                });
+                return true;
+            }
+        }
+        ID_DYNAMIC_KEYMAP_MACRO_RESET => {
+            MACRO_BUFFER.get().lock(|buffer| *buffer.borrow_mut() = default_macros());
+            return true;
+        }
+        ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[1] = N_LAYERS as u8,
+        ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+            if let Some((offset, size)) = chunk(data, keymap_buffer_size) {
+                for i in offset..offset + size {
+                    let (layer, row, col) = buffer_position(i / 2);
+                    data[4 + i - offset] = get_keycode(layer, row, col).to_be_bytes()[i % 2];
+                }
+            }
+        }
+        ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+            if let Some((offset, size)) = chunk(data, keymap_buffer_size) {
+                // a chunk may start or end halfway through a keycode, so merge with what's there
+                let (first, last) = (offset / 2, (offset + size).div_ceil(2));
+                for index in first..last {
+                    let (layer, row, col) = buffer_position(index);
+                    let mut bytes = get_keycode(layer, row, col).to_be_bytes();
+                    for (half, byte) in bytes.iter_mut().enumerate() {
+                        if let Some(i) = (index * 2 + half).checked_sub(offset) && i < size {
+                            *byte = data[4 + i];
+                        }
+                    }
+                    set_keycode(layer, row, col, u16::from_be_bytes(bytes));
+                }
+                return true;
+            }
+        }
+        command => {
+            defmt::debug!("unhandled VIA command {=u8:#x}", command);
+            data[0] = ID_UNHANDLED;
+        }
+    }
+    false
+}
@@ -1,17 +1,30 @@
-# meant to be used with circuitpython_testing.py on the keyboard
+# meant to be used with circuitpython_testing.py on the keyboard, or with the rust firmware's
+# serial shell after running "stream volts" in it
 
 import serial
 from matplotlib import pyplot as plt
 import numpy as np
 
 
-voltages = np.zeros((100, 25))
+# 25 for circuitpython_testing.py, 23 (one per key) for the rust firmware, or None to take the
+# width from the first two good lines in a row of the same width (the very first line read is often
+# cut short)
+N_VALUES = None
+last_width = None
 
+voltages = None
 fig, ax = plt.subplots()
-lines = ax.plot(voltages[:, 0], voltages)
-print(len(lines))
-ax.set_xlim(0, 100)
-ax.set_ylim(0, 3.5)
+lines = []
+
+
+def start_plot(n_values):
+    global voltages, lines
+    voltages = np.zeros((100, n_values))
+    lines = ax.plot(voltages[:, 0], voltages)
+    print(len(lines), 'values per line')
+    ax.set_xlim(0, 100)
+    ax.set_ylim(0, 3.5)
+
 
 def update_plot(voltages, i):
     x = np.arange(voltages.shape[0]) + i
@@ -31,12 +44,18 @@ for line in s:
     elems = line.strip().split(b',')
     if b'BLE:' in line:
         continue
-    if len(elems) == 25:
+    if N_VALUES is None or len(elems) == N_VALUES:
         try:
             vs = [float(e) for e in elems]
         except ValueError:
             print('invalid line:', line)
             continue
+        if N_VALUES is None:
+            if len(vs) != last_width:
+                last_width = len(vs)
+                continue
+            N_VALUES = len(vs)
+            start_plot(N_VALUES)
         i += 1
     else:
         print('invalid line:', line)